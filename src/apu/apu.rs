@@ -0,0 +1,371 @@
+use crate::apu::channels::{Noise, Square, Wave};
+use crate::cartridge::clock::CYCLES_PER_SECOND;
+use crate::util::{StateError, StateReader, StateWriter};
+
+/// Host sample rate used unless the frontend asks for another
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+
+// Registers
+pub const NR10_ADDRESS: u16 = 0xFF10;
+pub const NR21_ADDRESS: u16 = 0xFF16;
+pub const NR30_ADDRESS: u16 = 0xFF1A;
+pub const NR41_ADDRESS: u16 = 0xFF20;
+pub const NR50_ADDRESS: u16 = 0xFF24;
+pub const NR51_ADDRESS: u16 = 0xFF25;
+pub const NR52_ADDRESS: u16 = 0xFF26;
+pub const WAVE_RAM_ADDRESS: u16 = 0xFF30;
+
+/// Bits that always read back as 1 in 0xFF10-0xFF2F, including write-only and unused ones
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // unused, NR21-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // unused, NR41-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+/// The frame sequencer runs at 512 Hz and clocks the length counters, sweep and envelopes
+const FRAME_SEQUENCER_PERIOD: u32 = 8192;
+
+/// Fixed-size ring buffer of stereo samples. When the consumer falls behind, the oldest samples
+/// are dropped so the audio never lags further behind than the buffer is long.
+pub struct SampleBuffer {
+    samples: Vec<(f32, f32)>,
+    start: usize,
+    len: usize,
+}
+
+impl SampleBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: vec![(0.0, 0.0); capacity.max(1)],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    // This and the other readers are for an audio backend, which the terminal frontend doesn't
+    // have yet
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push(&mut self, sample: (f32, f32)) {
+        let capacity = self.samples.len();
+        if self.len == capacity {
+            self.start = (self.start + 1) % capacity;
+            self.len -= 1;
+        }
+        self.samples[(self.start + self.len) % capacity] = sample;
+        self.len += 1;
+    }
+
+    #[allow(dead_code)]
+    pub fn pop(&mut self) -> Option<(f32, f32)> {
+        if self.len == 0 {
+            return None;
+        }
+        let sample = self.samples[self.start];
+        self.start = (self.start + 1) % self.samples.len();
+        self.len -= 1;
+        Some(sample)
+    }
+
+    /// Moves as many samples as fit into `out` as interleaved left/right pairs. Returns the
+    /// number of stereo samples written.
+    #[allow(dead_code)]
+    pub fn read_interleaved(&mut self, out: &mut [f32]) -> usize {
+        let mut written = 0;
+        for frame in out.chunks_exact_mut(2) {
+            let Some((left, right)) = self.pop() else { break };
+            frame[0] = left;
+            frame[1] = right;
+            written += 1;
+        }
+        written
+    }
+}
+
+/// Audio processing unit. Owns the sound registers at 0xFF10-0xFF3F and mixes the four
+/// channels into stereo samples at the host rate.
+#[allow(clippy::upper_case_acronyms)]
+pub struct APU {
+    pub square1: Square,
+    pub square2: Square,
+    pub wave: Wave,
+    pub noise: Noise,
+
+    // Last values written to 0xFF10-0xFF2F, for reading back
+    registers: [u8; 0x20],
+    pub powered: bool,
+    nr50: u8,
+    nr51: u8,
+
+    frame_sequencer_cycles: u32,
+    frame_sequencer_step: u8,
+
+    sample_rate: u32,
+    // Fractional progress towards the next sample, in units of 1 / CYCLES_PER_SECOND
+    sample_counter: u64,
+    // High-pass filter state, removing the DC offset the DACs add
+    capacitor: (f32, f32),
+    capacitor_charge: f32,
+    pub samples: SampleBuffer,
+}
+
+impl APU {
+    pub fn new(sample_rate: u32) -> Self {
+        let mut apu = Self {
+            square1: Square::new(true),
+            square2: Square::new(false),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            registers: [0; 0x20],
+            powered: true,
+            nr50: 0x77,
+            nr51: 0xF3,
+            frame_sequencer_cycles: 0,
+            frame_sequencer_step: 0,
+            sample_rate,
+            sample_counter: 0,
+            capacitor: (0.0, 0.0),
+            capacitor_charge: 1.0,
+            samples: SampleBuffer::new(1),
+        };
+        apu.registers[(NR50_ADDRESS - NR10_ADDRESS) as usize] = apu.nr50;
+        apu.registers[(NR51_ADDRESS - NR10_ADDRESS) as usize] = apu.nr51;
+        apu.set_sample_rate(sample_rate);
+        apu
+    }
+
+    /// Changes the host sample rate. The buffer holds half a second and starts out empty.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate.max(1);
+        self.sample_counter = 0;
+        self.capacitor_charge = 0.999958f32.powf(CYCLES_PER_SECOND as f32 / self.sample_rate as f32);
+        self.samples = SampleBuffer::new(self.sample_rate as usize / 2);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Saves the sound hardware. The sample rate and the samples not yet played belong to the
+    /// host, and stay as they are.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.square1.save_state(state);
+        self.square2.save_state(state);
+        self.wave.save_state(state);
+        self.noise.save_state(state);
+        state.write_bytes(&self.registers);
+        state.write_bool(self.powered);
+        state.write_u8(self.nr50);
+        state.write_u8(self.nr51);
+        state.write_u32(self.frame_sequencer_cycles);
+        state.write_u8(self.frame_sequencer_step);
+        state.write_f32(self.capacitor.0);
+        state.write_f32(self.capacitor.1);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader, state_version: u32) -> Result<(), StateError> {
+        self.square1.load_state(state, state_version)?;
+        self.square2.load_state(state, state_version)?;
+        self.wave.load_state(state, state_version)?;
+        self.noise.load_state(state, state_version)?;
+        state.read_into(&mut self.registers)?;
+        self.powered = state.read_bool()?;
+        self.nr50 = state.read_u8()?;
+        self.nr51 = state.read_u8()?;
+        self.frame_sequencer_cycles = state.read_u32()?;
+        self.frame_sequencer_step = state.read_u8()? & 0b111;
+        self.capacitor = (state.read_f32()?, state.read_f32()?);
+        Ok(())
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            NR52_ADDRESS => {
+                let mut value = READ_MASKS[(NR52_ADDRESS - NR10_ADDRESS) as usize];
+                if self.powered {
+                    value |= 0x80;
+                }
+                for (bit, enabled) in [self.square1.enabled, self.square2.enabled, self.wave.enabled, self.noise.enabled]
+                    .into_iter().enumerate() {
+                    if enabled {
+                        value |= 1 << bit;
+                    }
+                }
+                value
+            }
+            0xFF10..=0xFF2F => {
+                let index = (address - NR10_ADDRESS) as usize;
+                self.registers[index] | READ_MASKS[index]
+            }
+            0xFF30..=0xFF3F => self.wave.ram[(address - WAVE_RAM_ADDRESS) as usize],
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        if address >= WAVE_RAM_ADDRESS {
+            self.wave.ram[(address - WAVE_RAM_ADDRESS) as usize] = value;
+            return;
+        }
+        if address == NR52_ADDRESS {
+            self.set_power(value & 0x80 != 0);
+            return;
+        }
+        // Everything but NR52 and wave RAM is read-only while the APU is off
+        if !self.powered {
+            return;
+        }
+        self.registers[(address - NR10_ADDRESS) as usize] = value;
+        match address {
+            0xFF10..=0xFF14 => self.square1.write(address - NR10_ADDRESS, value),
+            0xFF16..=0xFF19 => self.square2.write(address - NR21_ADDRESS + 1, value),
+            0xFF1A..=0xFF1E => self.wave.write(address - NR30_ADDRESS, value),
+            0xFF20..=0xFF23 => self.noise.write(address - NR41_ADDRESS + 1, value),
+            NR50_ADDRESS => self.nr50 = value,
+            NR51_ADDRESS => self.nr51 = value,
+            _ => {}
+        }
+    }
+
+    /// Powering off clears every register and silences all channels
+    fn set_power(&mut self, powered: bool) {
+        if self.powered && !powered {
+            let wave_ram = self.wave.ram;
+            self.square1 = Square::new(true);
+            self.square2 = Square::new(false);
+            self.wave = Wave::new();
+            self.wave.ram = wave_ram;
+            self.noise = Noise::new();
+            self.registers = [0; 0x20];
+            self.nr50 = 0;
+            self.nr51 = 0;
+        } else if !self.powered && powered {
+            self.frame_sequencer_step = 0;
+        }
+        self.powered = powered;
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            if self.powered {
+                self.square1.tick();
+                self.square2.tick();
+                self.wave.tick();
+                self.noise.tick();
+
+                self.frame_sequencer_cycles += 1;
+                if self.frame_sequencer_cycles == FRAME_SEQUENCER_PERIOD {
+                    self.frame_sequencer_cycles = 0;
+                    self.clock_frame_sequencer();
+                }
+            }
+
+            self.sample_counter += self.sample_rate as u64;
+            if self.sample_counter >= CYCLES_PER_SECOND {
+                self.sample_counter -= CYCLES_PER_SECOND;
+                let sample = self.mix();
+                self.samples.push(sample);
+            }
+        }
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        let step = self.frame_sequencer_step;
+        if step.is_multiple_of(2) {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if step == 2 || step == 6 {
+            self.square1.clock_sweep();
+        }
+        if step == 7 {
+            self.square1.clock_envelope();
+            self.square2.clock_envelope();
+            self.noise.clock_envelope();
+        }
+        self.frame_sequencer_step = (step + 1) % 8;
+    }
+
+    /// Mixes the channels through their DACs, NR51 panning and NR50 master volume
+    fn mix(&mut self) -> (f32, f32) {
+        let dac = |enabled: bool, output: u8| if enabled { output as f32 / 7.5 - 1.0 } else { 0.0 };
+        let channels = [
+            dac(self.square1.dac_enabled(), self.square1.output()),
+            dac(self.square2.dac_enabled(), self.square2.output()),
+            dac(self.wave.dac_enabled, self.wave.output()),
+            dac(self.noise.dac_enabled(), self.noise.output()),
+        ];
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, sample) in channels.into_iter().enumerate() {
+            if self.nr51 & (0x10 << i) != 0 {
+                left += sample;
+            }
+            if self.nr51 & (0x01 << i) != 0 {
+                right += sample;
+            }
+        }
+        left *= ((self.nr50 >> 4) & 0x07) as f32 + 1.0;
+        right *= (self.nr50 & 0x07) as f32 + 1.0;
+        let left = self.high_pass(left / 32.0, true);
+        let right = self.high_pass(right / 32.0, false);
+        (left, right)
+    }
+
+    fn high_pass(&mut self, input: f32, left: bool) -> f32 {
+        let capacitor = if left { &mut self.capacitor.0 } else { &mut self.capacitor.1 };
+        let output = input - *capacitor;
+        *capacitor = input - output * self.capacitor_charge;
+        output
+    }
+}
+
+
+// Tests
+#[test]
+fn apu_channels_and_samples() {
+    let mut apu = APU::new(32_768);
+
+    // Square 2 at full volume with length 60, so it stops after 4 length clocks
+    apu.write_register(0xFF17, 0xF0);
+    apu.write_register(0xFF16, 0x80 | 60);
+    apu.write_register(0xFF18, 0x00);
+    apu.write_register(0xFF19, 0xC7);
+    assert_eq!(apu.read_register(NR52_ADDRESS), 0xF2);
+    assert_eq!(apu.read_register(0xFF16), 0xBF);
+
+    apu.tick(FRAME_SEQUENCER_PERIOD * 6);
+    assert_eq!(apu.read_register(NR52_ADDRESS), 0xF2);
+    apu.tick(FRAME_SEQUENCER_PERIOD);
+    assert_eq!(apu.read_register(NR52_ADDRESS), 0xF0);
+
+    // 7 frame sequencer periods at 32768 Hz, swinging between the two levels of a 512 Hz square
+    assert_eq!(apu.samples.len(), 7 * 64);
+    let mut out = vec![0.0; 7 * 64 * 2];
+    assert_eq!(apu.samples.read_interleaved(&mut out), 7 * 64);
+    assert!(out.iter().any(|sample| *sample > 0.1));
+    assert!(out.iter().any(|sample| *sample < -0.1));
+    assert!(apu.samples.is_empty());
+
+    // Turning the APU off clears registers, and they ignore writes until it is back on
+    apu.write_register(NR52_ADDRESS, 0x00);
+    apu.write_register(NR50_ADDRESS, 0x77);
+    assert_eq!(apu.read_register(NR50_ADDRESS), 0x00);
+    assert_eq!(apu.read_register(NR52_ADDRESS), 0x70);
+    apu.write_register(NR52_ADDRESS, 0x80);
+    apu.write_register(NR50_ADDRESS, 0x77);
+    assert_eq!(apu.read_register(NR50_ADDRESS), 0x77);
+}
@@ -0,0 +1,513 @@
+use crate::util::{StateError, StateReader, StateWriter};
+
+/// Square wave patterns for each NRx1 duty setting, one bit per step
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+/// Noise channel timer divisors for each NR43 divisor code
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// Counts down at 256 Hz when enabled, and stops the channel when it runs out
+pub struct LengthCounter {
+    pub enabled: bool,
+    pub counter: u16,
+    max: u16,
+}
+
+impl LengthCounter {
+    fn new(max: u16) -> Self {
+        Self { enabled: false, counter: 0, max }
+    }
+
+    fn load(&mut self, length: u16) {
+        self.counter = self.max - length;
+    }
+
+    fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    /// Returns true when the counter just expired
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_u16(self.counter);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.counter = state.read_u16()?.min(self.max);
+        Ok(())
+    }
+}
+
+/// Volume envelope of the square and noise channels, stepped at 64 Hz
+pub struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    timer: u8,
+    pub volume: u8,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Self { initial_volume: 0, increase: false, period: 0, timer: 0, volume: 0 }
+    }
+
+    fn write(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.increase = value & 0x08 != 0;
+        self.period = value & 0x07;
+    }
+
+    /// The DAC is off when NRx2 selects a decreasing envelope from volume 0
+    fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+    fn save_state(&self, state: &mut StateWriter) {
+        for value in [self.initial_volume, self.increase as u8, self.period, self.timer, self.volume] {
+            state.write_u8(value);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.initial_volume = state.read_u8()? & 0x0F;
+        self.increase = state.read_bool()?;
+        self.period = state.read_u8()? & 0x07;
+        self.timer = state.read_u8()?;
+        self.volume = state.read_u8()? & 0x0F;
+        Ok(())
+    }
+}
+
+/// Channels 1 and 2. Only channel 1 has the frequency sweep.
+pub struct Square {
+    pub enabled: bool,
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    duty: u8,
+    duty_step: u8,
+    pub frequency: u16,
+    timer: u32,
+
+    has_sweep: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_timer: u8,
+    sweep_enabled: bool,
+    shadow_frequency: u16,
+}
+
+impl Square {
+    pub fn new(has_sweep: bool) -> Self {
+        Self {
+            enabled: false,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            duty: 0,
+            duty_step: 0,
+            frequency: 0,
+            timer: 0,
+            has_sweep,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_timer: 0,
+            sweep_enabled: false,
+            shadow_frequency: 0,
+        }
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    /// Writes NRx0-NRx4, by their offset from NRx0
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.sweep_period = (value >> 4) & 0x07;
+                self.sweep_negate = value & 0x08 != 0;
+                self.sweep_shift = value & 0x07;
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.load((value & 0x3F) as u16);
+            }
+            2 => {
+                self.envelope.write(value);
+                if !self.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = (2048 - self.frequency as u32) * 4;
+
+        if self.has_sweep {
+            self.shadow_frequency = self.frequency;
+            self.sweep_timer = if self.sweep_period != 0 { self.sweep_period } else { 8 };
+            self.sweep_enabled = self.sweep_period != 0 || self.sweep_shift != 0;
+            if self.sweep_shift != 0 {
+                self.sweep_frequency();
+            }
+        }
+    }
+
+    /// Next frequency of the sweep. Overflowing past 2047 stops the channel.
+    fn sweep_frequency(&mut self) -> u16 {
+        let delta = self.shadow_frequency >> self.sweep_shift;
+        let frequency = if self.sweep_negate {
+            self.shadow_frequency.wrapping_sub(delta)
+        } else {
+            self.shadow_frequency + delta
+        };
+        if frequency > 2047 {
+            self.enabled = false;
+        }
+        frequency
+    }
+
+    pub fn tick(&mut self) {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = (2048 - self.frequency as u32) * 4;
+            self.duty_step = (self.duty_step + 1) & 0x07;
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_sweep(&mut self) {
+        if !self.has_sweep {
+            return;
+        }
+        self.sweep_timer = self.sweep_timer.saturating_sub(1);
+        if self.sweep_timer > 0 {
+            return;
+        }
+        self.sweep_timer = if self.sweep_period != 0 { self.sweep_period } else { 8 };
+        if self.sweep_enabled && self.sweep_period != 0 {
+            let frequency = self.sweep_frequency();
+            if frequency <= 2047 && self.sweep_shift != 0 {
+                self.frequency = frequency;
+                self.shadow_frequency = frequency;
+                // The new frequency is checked for overflow straight away
+                self.sweep_frequency();
+            }
+        }
+    }
+
+    /// Digital output, 0-15
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        ((DUTY_PATTERNS[self.duty as usize] >> self.duty_step) & 1) * self.envelope.volume
+    }
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        state.write_u8(self.duty);
+        state.write_u8(self.duty_step);
+        state.write_u16(self.frequency);
+        state.write_u32(self.timer);
+        state.write_u8(self.sweep_period);
+        state.write_bool(self.sweep_negate);
+        state.write_u8(self.sweep_shift);
+        state.write_u8(self.sweep_timer);
+        state.write_bool(self.sweep_enabled);
+        state.write_u16(self.shadow_frequency);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader, _state_version: u32) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.duty = state.read_u8()? & 0b11;
+        self.duty_step = state.read_u8()? & 0b111;
+        self.frequency = state.read_u16()? & 0x7FF;
+        self.timer = state.read_u32()?;
+        self.sweep_period = state.read_u8()? & 0b111;
+        self.sweep_negate = state.read_bool()?;
+        self.sweep_shift = state.read_u8()? & 0b111;
+        self.sweep_timer = state.read_u8()?;
+        self.sweep_enabled = state.read_bool()?;
+        self.shadow_frequency = state.read_u16()? & 0x7FF;
+        Ok(())
+    }
+}
+
+/// Channel 3, playing back 32 4-bit samples from wave RAM
+pub struct Wave {
+    pub enabled: bool,
+    pub dac_enabled: bool,
+    pub length: LengthCounter,
+    volume_code: u8,
+    pub frequency: u16,
+    timer: u32,
+    position: u8,
+    sample: u8,
+    pub ram: [u8; 16],
+}
+
+impl Wave {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            dac_enabled: false,
+            length: LengthCounter::new(256),
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            ram: [0; 16],
+        }
+    }
+
+    /// Writes NR30-NR34, by their offset from NR30
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.dac_enabled = value & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(value as u16),
+            2 => self.volume_code = (value >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.enabled = self.dac_enabled;
+                    self.length.trigger();
+                    self.timer = (2048 - self.frequency as u32) * 2;
+                    self.position = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn tick(&mut self) {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = (2048 - self.frequency as u32) * 2;
+            self.position = (self.position + 1) & 0x1F;
+            let byte = self.ram[self.position as usize / 2];
+            self.sample = if self.position.is_multiple_of(2) { byte >> 4 } else { byte & 0x0F };
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /// Digital output, 0-15
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        match self.volume_code {
+            0 => 0,
+            code => self.sample >> (code - 1),
+        }
+    }
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.dac_enabled);
+        self.length.save_state(state);
+        state.write_u8(self.volume_code);
+        state.write_u16(self.frequency);
+        state.write_u32(self.timer);
+        state.write_u8(self.position);
+        state.write_u8(self.sample);
+        state.write_bytes(&self.ram);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader, _state_version: u32) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.dac_enabled = state.read_bool()?;
+        self.length.load_state(state)?;
+        self.volume_code = state.read_u8()? & 0b11;
+        self.frequency = state.read_u16()? & 0x7FF;
+        self.timer = state.read_u32()?;
+        self.position = state.read_u8()? & 0x1F;
+        self.sample = state.read_u8()? & 0x0F;
+        state.read_into(&mut self.ram)?;
+        Ok(())
+    }
+}
+
+/// Channel 4, a linear-feedback shift register clocked at a programmable rate
+pub struct Noise {
+    pub enabled: bool,
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    clock_shift: u8,
+    width_mode: bool,
+    divisor_code: u8,
+    timer: u32,
+    lfsr: u16,
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            clock_shift: 0,
+            width_mode: false,
+            divisor_code: 0,
+            timer: 0,
+            lfsr: 0x7FFF,
+        }
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    /// Writes NR41-NR44, by their offset from NR40
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            1 => self.length.load((value & 0x3F) as u16),
+            2 => {
+                self.envelope.write(value);
+                if !self.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.clock_shift = value >> 4;
+                self.width_mode = value & 0x08 != 0;
+                self.divisor_code = value & 0x07;
+            }
+            4 => {
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.enabled = self.dac_enabled();
+                    self.length.trigger();
+                    self.envelope.trigger();
+                    self.timer = self.period();
+                    self.lfsr = 0x7FFF;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn tick(&mut self) {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period();
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            // 7-bit mode also feeds back into bit 6, for a shorter, more tonal sequence
+            if self.width_mode {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+            }
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    /// Digital output, 0-15
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        (!self.lfsr & 1) as u8 * self.envelope.volume
+    }
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        state.write_u8(self.clock_shift);
+        state.write_bool(self.width_mode);
+        state.write_u8(self.divisor_code);
+        state.write_u32(self.timer);
+        state.write_u16(self.lfsr);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader, _state_version: u32) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.clock_shift = state.read_u8()? & 0x0F;
+        self.width_mode = state.read_bool()?;
+        self.divisor_code = state.read_u8()? & 0b111;
+        self.timer = state.read_u32()?;
+        self.lfsr = state.read_u16()? & 0x7FFF;
+        Ok(())
+    }
+}
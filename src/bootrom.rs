@@ -1,9 +1,46 @@
-mod bootrom {
-    pub struct BootROM {
-        pub sp: u16,
-        pub pc: u16,
-        pub vram: [u8; 0x2000],
+use std::io;
+use crate::system::Model;
 
+/// Writing a non-zero value here unmaps the boot ROM until the next reset
+pub const BOOT_ROM_DISABLE_ADDRESS: u16 = 0xFF50;
+
+const DMG_BOOT_ROM_SIZE: usize = 0x100;
+const CGB_BOOT_ROM_SIZE: usize = 0x900;
+
+/// Boot ROM image, mapped over the start of the cartridge ROM at power on. The CGB one also
+/// covers 0x0200-0x08FF, leaving the cartridge header at 0x0100-0x01FF visible.
+pub struct BootROM {
+    pub data: Vec<u8>,
+    pub model: Model,
+    pub mapped: bool,
+}
+
+impl BootROM {
+    pub fn new(data: Vec<u8>, model: Model) -> Self {
+        Self { data, model, mapped: true }
     }
 
+    /// Loads a boot ROM image. Its size tells a CGB boot ROM from the DMG one, which is also
+    /// the one to use for MGB and SGB unless the caller knows better.
+    pub fn load(filename: &str) -> io::Result<BootROM> {
+        let data = std::fs::read(filename)?;
+        let model = match data.len() {
+            DMG_BOOT_ROM_SIZE => Model::DMG,
+            CGB_BOOT_ROM_SIZE => Model::CGB,
+            length => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("boot ROM is {} bytes, expected {} or {}", length, DMG_BOOT_ROM_SIZE, CGB_BOOT_ROM_SIZE))),
+        };
+        Ok(BootROM::new(data, model))
+    }
+
+    /// The byte at `address` if the boot ROM covers it
+    pub fn read(&self, address: u16) -> Option<u8> {
+        if !self.mapped {
+            return None;
+        }
+        match address {
+            0x0000..=0x00FF | 0x0200..=0x08FF => self.data.get(address as usize).copied(),
+            _ => None,
+        }
+    }
 }
@@ -1,30 +1,208 @@
+use crate::bootrom::{BootROM, BOOT_ROM_DISABLE_ADDRESS};
+use crate::cartridge::base_mbc::MemoryBankController;
+use crate::apu::apu::APU;
+use crate::dma::{DMA, DMA_ADDRESS, HDMA1_ADDRESS, HDMA5_ADDRESS};
+use crate::joypad::{Joypad, P1_ADDRESS};
+use crate::memory::{InternalRAM, SVBK_ADDRESS};
+use crate::ppu::ppu::{ColorMode, PPU, OPRI_ADDRESS, VBK_ADDRESS};
+use crate::serial::serial::{Serial, SB_ADDRESS, SC_ADDRESS};
+use crate::system::{SpeedSwitch, KEY0_ADDRESS, KEY0_DMG_COMPATIBILITY, KEY1_ADDRESS};
+use crate::timer::Timer;
+
 /// Trait that allows the motherboard to pass values between its components
 pub trait Bus {
     fn read8(&mut self, address: u16) -> u8;
     fn write8(&mut self, address: u16, value: u8);
 
+    /// Reads without side effects and without observers seeing it, for tracing
+    fn peek8(&mut self, address: u16) -> u8 {
+        self.read8(address)
+    }
+
+    #[cfg(test)]
     fn read16(&mut self, address: u16) -> u16 {
         let low = self.read8(address) as u16;
         let high = self.read8(address.wrapping_add(1)) as u16;
         (high << 8) | low
     }
+}
+
+/// One access the CPU made on the bus
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read { address: u16, value: u8 },
+    Write { address: u16, value: u8 },
+}
+
+impl Access {
+    pub fn address(&self) -> u16 {
+        match *self {
+            Access::Read { address, .. } | Access::Write { address, .. } => address,
+        }
+    }
+}
+
+/// Passes accesses on to another bus, telling `observer` about each one. The debugger watches
+/// memory through this.
+pub struct ObservedBus<B: Bus, F: FnMut(Access)> {
+    pub bus: B,
+    pub observer: F,
+}
 
-    fn write16(&mut self, address: u16, value: u16) {
-        self.write8(address, (value & 0x00FF) as u8);
-        self.write8(address.wrapping_add(1), (value >> 8) as u8);
+impl<B: Bus, F: FnMut(Access)> Bus for ObservedBus<B, F> {
+    fn read8(&mut self, address: u16) -> u8 {
+        let value = self.bus.read8(address);
+        (self.observer)(Access::Read { address, value });
+        value
+    }
+    fn write8(&mut self, address: u16, value: u8) {
+        (self.observer)(Access::Write { address, value });
+        self.bus.write8(address, value);
+    }
+    fn peek8(&mut self, address: u16) -> u8 {
+        self.bus.peek8(address)
     }
 }
 
 /// Mutable bus pointer that contains only necessary variables, avoids circular inheritance
+///
+/// Decodes the Game Boy memory map and dispatches every access to the component owning the
+/// region. IF (0xFF0F) and IE (0xFFFF) are owned by the CPU and never get this far.
 pub struct BusMut<'a> {
-    pub memory: &'a mut [u8],
+    pub cartridge: &'a mut Option<Box<dyn MemoryBankController>>,
+    pub boot_rom: &'a mut Option<BootROM>,
+    pub ram: &'a mut InternalRAM,
+    pub ppu: &'a mut PPU,
+    pub apu: &'a mut APU,
+    pub timer: &'a mut Timer,
+    pub joypad: &'a mut Joypad,
+    pub serial: &'a mut Serial,
+    pub dma: &'a mut DMA,
+    pub speed: &'a mut SpeedSwitch,
+    // Interrupts raised by register writes, for the motherboard to pass on to the CPU
+    pub interrupts: u8,
 }
 
 impl<'a> Bus for BusMut<'a> {
+    /// During OAM DMA the DMA unit has the external and video buses, and the CPU reads 0xFF
     fn read8(&mut self, addr: u16) -> u8 {
-        self.memory[addr as usize]
+        if self.dma.oam_active() && addr < 0xFF00 {
+            return 0xFF;
+        }
+        self.read_direct(addr)
     }
     fn write8(&mut self, addr: u16, val: u8) {
-        self.memory[addr as usize] = val;
+        if self.dma.oam_active() && addr < 0xFF00 {
+            return;
+        }
+        self.write_direct(addr, val);
+    }
+    fn peek8(&mut self, addr: u16) -> u8 {
+        self.read_direct(addr)
+    }
+}
+
+impl<'a> BusMut<'a> {
+    /// The CGB registers only answer in CGB mode
+    fn cgb(&self) -> bool {
+        self.ppu.color_mode == ColorMode::CGB
     }
-}
\ No newline at end of file
+
+    /// Reads without bus conflicts, as the DMA units see memory
+    pub fn read_direct(&mut self, addr: u16) -> u8 {
+        match addr {
+            // Cartridge ROM, with the boot ROM over it until it is unmapped
+            0x0000..=0x7FFF => {
+                if let Some(value) = self.boot_rom.as_ref().and_then(|boot_rom| boot_rom.read(addr)) {
+                    return value;
+                }
+                match self.cartridge {
+                    Some(cartridge) => cartridge.read_rom(addr),
+                    None => 0xFF,
+                }
+            }
+            // Video RAM
+            0x8000..=0x9FFF => self.ppu.vram[self.ppu.vram_address(addr)],
+            // Cartridge RAM
+            0xA000..=0xBFFF => match self.cartridge {
+                Some(cartridge) => cartridge.read_ram(addr),
+                None => 0xFF,
+            },
+            // Work RAM
+            0xC000..=0xDFFF => self.ram.wram[self.ram.wram_address(addr)],
+            // Echo of work RAM
+            0xE000..=0xFDFF => self.ram.wram[self.ram.wram_address(addr)],
+            // Object attribute memory
+            0xFE00..=0xFE9F => self.ppu.oam[(addr - 0xFE00) as usize],
+            // Unusable
+            0xFEA0..=0xFEFF => 0x00,
+            // Joypad
+            P1_ADDRESS => self.joypad.read_register(),
+            // Link port
+            SB_ADDRESS | SC_ADDRESS => self.serial.read_register(addr),
+            // Timer
+            0xFF04..=0xFF07 => self.timer.read_register(addr),
+            // Sound registers and wave RAM
+            0xFF10..=0xFF3F => self.apu.read_register(addr),
+            // DMA
+            DMA_ADDRESS | HDMA1_ADDRESS..=HDMA5_ADDRESS => self.dma.read_register(addr),
+            // LCD registers
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B => self.ppu.read_register(addr),
+            // CGB speed switch, VRAM bank, colour palettes and object priority
+            KEY1_ADDRESS => if self.cgb() { self.speed.read_register() } else { 0xFF },
+            VBK_ADDRESS | 0xFF68..=OPRI_ADDRESS => self.ppu.read_register(addr),
+            // CGB work RAM bank
+            SVBK_ADDRESS => if self.cgb() { self.ram.read_svbk() } else { 0xFF },
+            // IO registers
+            0xFF01..=0xFF7F => self.ram.io_ports[(addr - 0xFF00) as usize],
+            // High RAM
+            0xFF80..=0xFFFE => self.ram.hram[(addr - 0xFF80) as usize],
+            // Interrupt enable register, owned by the CPU
+            0xFFFF => 0xFF,
+        }
+    }
+
+    pub fn write_direct(&mut self, addr: u16, val: u8) {
+        match addr {
+            // Writes to ROM go to the MBC registers
+            0x0000..=0x7FFF => if let Some(cartridge) = self.cartridge {
+                cartridge.write_rom(addr, val);
+            },
+            0x8000..=0x9FFF => self.ppu.vram[self.ppu.vram_address(addr)] = val,
+            0xA000..=0xBFFF => if let Some(cartridge) = self.cartridge {
+                cartridge.write_ram(addr, val);
+            },
+            0xC000..=0xDFFF => self.ram.wram[self.ram.wram_address(addr)] = val,
+            0xE000..=0xFDFF => self.ram.wram[self.ram.wram_address(addr)] = val,
+            0xFE00..=0xFE9F => self.ppu.oam[(addr - 0xFE00) as usize] = val,
+            0xFEA0..=0xFEFF => {}
+            P1_ADDRESS => self.interrupts |= self.joypad.write_register(val),
+            SB_ADDRESS | SC_ADDRESS => self.serial.write_register(addr, val),
+            0xFF04..=0xFF07 => self.timer.write_register(addr, val),
+            0xFF10..=0xFF3F => self.apu.write_register(addr, val),
+            DMA_ADDRESS | HDMA1_ADDRESS..=HDMA5_ADDRESS => self.dma.write_register(addr, val),
+            BOOT_ROM_DISABLE_ADDRESS => if let Some(boot_rom) = self.boot_rom {
+                if val != 0 {
+                    boot_rom.mapped = false;
+                }
+            },
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B => self.interrupts |= self.ppu.write_register(addr, val),
+            // Only the boot ROM can leave CGB mode, by writing KEY0 before it unmaps itself
+            KEY0_ADDRESS => if self.cgb() && self.boot_rom.as_ref().is_some_and(|boot_rom| boot_rom.mapped)
+                && val & KEY0_DMG_COMPATIBILITY != 0 {
+                self.ppu.color_mode = ColorMode::Compatibility;
+                self.dma.cgb = false;
+            },
+            KEY1_ADDRESS => if self.cgb() {
+                self.speed.write_register(val);
+            },
+            VBK_ADDRESS | 0xFF68..=OPRI_ADDRESS => self.interrupts |= self.ppu.write_register(addr, val),
+            SVBK_ADDRESS => if self.cgb() {
+                self.ram.write_svbk(val);
+            },
+            0xFF01..=0xFF7F => self.ram.io_ports[(addr - 0xFF00) as usize] = val,
+            0xFF80..=0xFFFE => self.ram.hram[(addr - 0xFF80) as usize] = val,
+            0xFFFF => {}
+        }
+    }
+}
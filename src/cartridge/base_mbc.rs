@@ -1,12 +1,92 @@
 use std::ffi::c_int;
+use std::path::Path;
+use crate::cartridge::clock::{ClockSource, CYCLES_PER_SECOND};
+use crate::cartridge::header::{CGBFlag, CartridgeHeader};
 use crate::cartridge::rtc::RTC;
+use crate::util::{StateError, StateReader, StateWriter};
+
+/// Common interface for every cartridge memory bank controller. The bus routes the ROM window
+/// (0x0000-0x7FFF) and the external RAM window (0xA000-0xBFFF) through it.
+pub trait MemoryBankController {
+    fn base(&self) -> &BaseMBC;
+    fn base_mut(&mut self) -> &mut BaseMBC;
+
+    /// Reads from the ROM window, bank 0 at 0x0000-0x3FFF and the switchable bank above it
+    fn read_rom(&mut self, address: u16) -> u8 {
+        let mbc = self.base();
+        if address < 0x4000 {
+            mbc.read_rom_bank(mbc.rom_bank_selected_low, address)
+        } else {
+            mbc.read_rom_bank(mbc.rom_bank_selected, address - 0x4000)
+        }
+    }
+
+    /// Writes to the ROM window, which on a real cartridge land in the MBC control registers
+    fn write_rom(&mut self, address: u16, value: u8);
+
+    fn read_ram(&mut self, address: u16) -> u8 {
+        let mbc = self.base();
+        if !mbc.ram_bank_enabled {
+            return 0xFF;
+        }
+        mbc.read_ram_bank(mbc.ram_bank_selected, address - 0xA000)
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        let mbc = self.base_mut();
+        if mbc.ram_bank_enabled {
+            mbc.write_ram_bank(mbc.ram_bank_selected, address - 0xA000, value);
+        }
+    }
+
+    /// Advances cartridge hardware that keeps time, i.e. the MBC3 real-time clock, and writes
+    /// out pending autosaves
+    fn tick(&mut self, cycles: u32) {
+        let mbc = self.base_mut();
+        if mbc.rtc_enabled {
+            mbc.rtc.tick(cycles);
+        }
+        mbc.tick_autosave(cycles);
+    }
+
+    /// Replaces the time source of the real-time clock, if the cartridge has one
+    fn set_rtc_clock(&mut self, clock: Box<dyn ClockSource>) {
+        self.base_mut().rtc.set_clock(clock);
+    }
+
+    /// Saves the banking registers, cartridge RAM and clock. Controllers with registers of their
+    /// own save them after these.
+    fn save_state(&mut self, state: &mut StateWriter) {
+        self.base_mut().save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader, state_version: u32) -> Result<(), StateError> {
+        self.base_mut().load_state(state, state_version)
+    }
+
+    /// Persists what the cartridge battery keeps alive when the emulator shuts down
+    fn stop(&mut self) {
+        let mbc = self.base_mut();
+        if mbc.battery_enabled {
+            if let Err(error) = mbc.save_ram() {
+                eprintln!("Failed to write save file {}: {}", mbc.filename, error);
+            }
+        }
+    }
+}
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+/// Emulated time to wait after the game disables RAM before autosaving, so bursts of saves are
+/// written once
+const AUTOSAVE_DELAY: u64 = CYCLES_PER_SECOND;
 
 pub struct BaseMBC {
     pub filename: String,
-    pub game_title: String,
     pub rom_banks: Vec<u8>,
     pub ram_banks: Vec<u8>,
-    pub cart_type: u8,
+    pub sram: bool,
     pub battery_enabled: bool,
     pub rtc_enabled: bool,
     pub rtc: RTC,
@@ -14,37 +94,35 @@ pub struct BaseMBC {
     pub ram_bank_enabled: bool,
     pub external_ram_count: c_int,
     pub external_rom_count: c_int,
-    pub ram_bank_initialized: bool,
     pub ram_bank_selected: u16,
     pub rom_bank_selected: u16,
     pub rom_bank_selected_low: u16,
     pub cgb_mode: bool,
+    pub ram_dirty: bool,
+    pub autosave_pending: bool,
+    pub autosave_cycles: u64,
 }
 
 impl BaseMBC {
     pub fn new(filename: String, rom_banks: Vec<u8>,
-                external_ram_count: c_int, cart_type: u8, sram: u8,
+                external_ram_count: c_int, sram: bool,
                 battery_enabled: bool, rtc_enabled: bool) -> Self
     {
-            let new_filename = filename + ".ram";
+            let new_filename = Path::new(&filename).with_extension("sav").to_string_lossy().into_owned();
+            let rtc = RTC::new();
 
-            let mut rtc = RTC::new(String::from(""));
-            if rtc_enabled {
-                rtc = RTC::new(new_filename.clone());
-            }
-
-            let mut ram_bank_initialized = false;
-            let external_rom_count = rom_banks.iter().count() as c_int;
-            let ram_banks = BaseMBC::init_ram_banks(external_ram_count, &mut ram_bank_initialized);
+            let external_rom_count = (rom_banks.len() / ROM_BANK_SIZE) as c_int;
+            let ram_banks = vec![0; external_ram_count.max(0) as usize * RAM_BANK_SIZE];
 
-            let cgb_mode = rom_banks[0..0x0143].iter().count() > 7;
+            let header = CartridgeHeader::parse(&rom_banks).ok();
+            // Byte 0x0143 of the header says whether the game supports CGB mode
+            let cgb_mode = header.as_ref().is_some_and(|header| header.cgb_flag != CGBFlag::DMGOnly);
 
             Self {
                 filename: new_filename,
-                game_title: BaseMBC::get_game_title(rom_banks.clone(), cgb_mode),
                 rom_banks,
-                ram_banks: vec![],
-                cart_type,
+                ram_banks,
+                sram,
                 battery_enabled,
                 rtc_enabled,
                 rtc,
@@ -52,31 +130,119 @@ impl BaseMBC {
                 ram_bank_enabled: false,
                 external_ram_count,
                 external_rom_count,
-                ram_bank_initialized,
                 ram_bank_selected: 0,
                 rom_bank_selected: 1,
                 rom_bank_selected_low: 0,
                 cgb_mode,
+                ram_dirty: false,
+                autosave_pending: false,
+                autosave_cycles: 0,
             }
     }
 
-    fn init_ram_banks(n: c_int, ram_bank_initialized: &mut bool) -> Vec<u8> {
-        *ram_bank_initialized = true;
-        let ram_banks = vec![0; n as usize];
-        ram_banks
+    /// Wraps a ROM bank number around the banks actually present, as the cartridge leaves the
+    /// upper bank lines unconnected
+    pub fn mask_rom_bank(&self, bank: u16) -> u16 {
+        bank % self.external_rom_count.max(1) as u16
+    }
+
+    pub fn mask_ram_bank(&self, bank: u16) -> u16 {
+        bank % self.external_ram_count.max(1) as u16
+    }
+
+    /// Reads one byte at `offset` into the 16 KiB ROM bank `bank`. Open bus past the end of the ROM.
+    pub fn read_rom_bank(&self, bank: u16, offset: u16) -> u8 {
+        *self.rom_banks.get(bank as usize * ROM_BANK_SIZE + offset as usize).unwrap_or(&0xFF)
+    }
+
+    /// Reads one byte at `offset` into the 8 KiB RAM bank `bank`. Open bus past the end of the RAM.
+    pub fn read_ram_bank(&self, bank: u16, offset: u16) -> u8 {
+        *self.ram_banks.get(bank as usize * RAM_BANK_SIZE + offset as usize).unwrap_or(&0xFF)
     }
 
-    fn get_game_title(rom_banks: Vec<u8>, cgb_mode: bool) -> String {
-        let mut end = 0x0143;
-        if cgb_mode {
-            end = 0x0142;
+    pub fn write_ram_bank(&mut self, bank: u16, offset: u16, value: u8) {
+        if let Some(v) = self.ram_banks.get_mut(bank as usize * RAM_BANK_SIZE + offset as usize) {
+            *v = value;
+            self.ram_dirty = true;
         }
+    }
+
+    /// Games disable RAM once they're done saving, which is when the save file gets written
+    pub fn set_ram_enabled(&mut self, enabled: bool) {
+        if self.ram_bank_enabled && !enabled && self.ram_dirty && self.battery_enabled {
+            self.autosave_pending = true;
+            self.autosave_cycles = 0;
+        }
+        self.ram_bank_enabled = enabled;
+    }
+
+    fn tick_autosave(&mut self, cycles: u32) {
+        if !self.autosave_pending {
+            return;
+        }
+        self.autosave_cycles += cycles as u64;
+        if self.autosave_cycles >= AUTOSAVE_DELAY {
+            self.autosave_pending = false;
+            if let Err(error) = self.save_ram() {
+                eprintln!("Failed to autosave to {}: {}", self.filename, error);
+            }
+        }
+    }
+
+    /// Loads the battery-backed RAM, and the RTC footer of timer carts, from the .sav file. The
+    /// RAM is whatever the controller allocated, so MBC2 saves are its 512 built-in half-bytes.
+    pub fn load_ram(&mut self) -> std::io::Result<()> {
+        if !self.battery_enabled || !Path::new(&self.filename).exists() {
+            return Ok(());
+        }
+        let data = std::fs::read(&self.filename)?;
+        let ram_size = self.ram_banks.len().min(data.len());
+        self.ram_banks[..ram_size].copy_from_slice(&data[..ram_size]);
+        if self.rtc_enabled && !self.rtc.read_footer(&data[ram_size..]) {
+            eprintln!("No RTC data in save file {}", self.filename);
+        }
+        Ok(())
+    }
+
+    /// Writes the raw RAM, followed by the RTC footer for timer carts, to the .sav file. The file is
+    /// replaced in one go so a crash mid-write never leaves a truncated save.
+    pub fn save_ram(&mut self) -> std::io::Result<()> {
+        let mut data = self.ram_banks.clone();
+        if self.rtc_enabled {
+            self.rtc.write_footer(&mut data);
+        }
+        let temporary = self.filename.clone() + ".tmp";
+        std::fs::write(&temporary, data)?;
+        std::fs::rename(&temporary, &self.filename)?;
+        self.ram_dirty = false;
+        Ok(())
+    }
+
+    pub fn save_state(&mut self, state: &mut StateWriter) {
+        state.write_u8(self.memory_model);
+        state.write_bool(self.ram_bank_enabled);
+        state.write_u16(self.ram_bank_selected);
+        state.write_u16(self.rom_bank_selected);
+        state.write_u16(self.rom_bank_selected_low);
+        state.write_u32(self.ram_banks.len() as u32);
+        state.write_bytes(&self.ram_banks);
+        self.rtc.save_state(state);
+    }
 
-        let mut title = "".parse::<String>().unwrap();
-        for x in 0x0134..end {
-            title = title + &*(rom_banks[x].to_string());
+    /// RAM of a different size means the state is for another cartridge
+    pub fn load_state(&mut self, state: &mut StateReader, state_version: u32) -> Result<(), StateError> {
+        self.memory_model = state.read_u8()? & 1;
+        self.ram_bank_enabled = state.read_bool()?;
+        self.ram_bank_selected = state.read_u16()?;
+        self.rom_bank_selected = self.mask_rom_bank(state.read_u16()?);
+        self.rom_bank_selected_low = self.mask_rom_bank(state.read_u16()?);
+        if state.read_u32()? as usize != self.ram_banks.len() {
+            return Err(StateError::WrongCartridge);
         }
-        title
+        state.read_into(&mut self.ram_banks)?;
+        // What is in RAM now may not be what is in the save file
+        self.ram_dirty = true;
+        self.rtc.load_state(state, state_version)
     }
 }
 
@@ -85,22 +251,49 @@ pub struct ROMOnly {
     pub mbc: BaseMBC,
 }
 
-// impl ROMOnly {
-//     pub fn new() -> Self {
-//         Self {
-//         mbc: BaseMBC::new()
-//             }
-//     }
-//     fn set_item(&mut self, address: u16, mut value: u16) {
-//         if 0x2000 <= address && address < 0x4000 {
-//             if value == 0 {
-//                 value = 1;
-//             }
-//             self.mbc.rom_bank_selected = value & 0b1;
-//         }
-//         else if 0xA000 <= address && address < 0xC000 {
-//             self.mbc.ram_banks[self.mbc.ram_bank_selected..address-0xA000] = value;
-//         }
-//     }
-// }
+impl ROMOnly {
+    pub fn new(filename: String, rom_banks: Vec<u8>,
+               external_ram_count: c_int, sram: bool,
+               battery_enabled: bool, rtc_enabled: bool) -> Self {
+        Self {
+            mbc: BaseMBC::new(filename, rom_banks, external_ram_count,
+                              sram, battery_enabled, rtc_enabled),
+        }
+    }
+}
 
+impl MemoryBankController for ROMOnly {
+    fn base(&self) -> &BaseMBC { &self.mbc }
+    fn base_mut(&mut self) -> &mut BaseMBC { &mut self.mbc }
+
+    // There is no bank register, so writes to the ROM window do nothing
+    fn write_rom(&mut self, _address: u16, _value: u8) {}
+
+    // Without RAM nothing drives the bus, so reads float high and writes go nowhere
+    fn read_ram(&mut self, address: u16) -> u8 {
+        if !self.mbc.sram {
+            return 0xFF;
+        }
+        self.mbc.read_ram_bank(self.mbc.ram_bank_selected, address - 0xA000)
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if self.mbc.sram {
+            self.mbc.write_ram_bank(self.mbc.ram_bank_selected, address - 0xA000, value);
+        }
+    }
+}
+
+
+// Tests
+#[test]
+fn rom_only_ignores_writes() {
+    let mut rom = vec![0; 2 * ROM_BANK_SIZE];
+    rom[ROM_BANK_SIZE] = 0x01;
+    let mut cartridge = ROMOnly::new(String::from("test"), rom, 0, false, false, false);
+    for value in [0x00, 0x02, 0xFF] {
+        cartridge.write_rom(0x2000, value);
+        assert_eq!(cartridge.read_rom(0x4000), 0x01);
+    }
+    assert_eq!(cartridge.read_rom(0x0000), 0x00);
+}
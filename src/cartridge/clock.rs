@@ -0,0 +1,91 @@
+use std::cell::Cell;
+use std::os::raw::c_double;
+use std::rc::Rc;
+use std::time;
+
+/// CPU cycles in one second of emulated time
+pub const CYCLES_PER_SECOND: u64 = 4_194_304;
+
+/// Source of time for the cartridge real-time clock
+pub trait ClockSource {
+    /// Current time in seconds since the UNIX epoch, as seen by this clock
+    fn now(&self) -> c_double;
+
+    /// Called with the CPU cycles of every emulated step
+    fn tick(&mut self, _cycles: u32) {}
+}
+
+pub fn wall_time() -> c_double {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+/// Follows the host's real time, whether or not the emulator is running
+pub struct WallClock;
+
+impl ClockSource for WallClock {
+    fn now(&self) -> c_double {
+        wall_time()
+    }
+}
+
+/// Starts at the wall time it was created at, then only moves with emulated cycles. Pausing,
+/// fast-forwarding or slowing down the emulator does the same to the clock.
+pub struct EmulatedClock {
+    pub origin: c_double,
+    pub cycles: u64,
+}
+
+impl EmulatedClock {
+    pub fn new() -> Self {
+        Self {
+            origin: wall_time(),
+            cycles: 0,
+        }
+    }
+}
+
+impl ClockSource for EmulatedClock {
+    fn now(&self) -> c_double {
+        self.origin + self.cycles as c_double / CYCLES_PER_SECOND as c_double
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+    }
+}
+
+/// Stands still until moved by hand. Clones share the same time, so a test or movie player can
+/// keep one to freeze or fast-forward the clock it handed to the cartridge.
+#[derive(Clone)]
+pub struct ManualClock {
+    time: Rc<Cell<c_double>>,
+}
+
+impl ManualClock {
+    pub fn new(time: c_double) -> Self {
+        Self {
+            time: Rc::new(Cell::new(time)),
+        }
+    }
+
+    // Nothing in the frontend moves the clock by hand yet, only tests and embedders like a movie
+    // player
+    #[allow(dead_code)]
+    pub fn set(&self, time: c_double) {
+        self.time.set(time);
+    }
+
+    #[allow(dead_code)]
+    pub fn advance(&self, seconds: c_double) {
+        self.time.set(self.time.get() + seconds);
+    }
+}
+
+impl ClockSource for ManualClock {
+    fn now(&self) -> c_double {
+        self.time.get()
+    }
+}
@@ -0,0 +1,376 @@
+use std::fmt;
+use crate::cartridge::cartridge::MBCType;
+
+/// The Nintendo logo every cartridge carries at 0x0104, checked by the boot ROM
+pub const NINTENDO_LOGO: [u8; 0x30] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+/// The header ends with the global checksum at 0x014E-0x014F
+pub const HEADER_END: usize = 0x0150;
+
+/// Cartridge hardware, from the cart type byte at 0x0147
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CartridgeType {
+    ROMOnly,
+    MBC1,
+    MBC1RAM,
+    MBC1RAMBattery,
+    MBC2,
+    MBC2Battery,
+    ROMRAM,
+    ROMRAMBattery,
+    MMM01,
+    MMM01RAM,
+    MMM01RAMBattery,
+    MBC3TimerBattery,
+    MBC3TimerRAMBattery,
+    MBC3,
+    MBC3RAM,
+    MBC3RAMBattery,
+    MBC5,
+    MBC5RAM,
+    MBC5RAMBattery,
+    MBC5Rumble,
+    MBC5RumbleRAM,
+    MBC5RumbleRAMBattery,
+    MBC6,
+    MBC7SensorRumbleRAMBattery,
+    PocketCamera,
+    BandaiTAMA5,
+    HuC3,
+    HuC1RAMBattery,
+    Unknown(u8),
+}
+
+impl CartridgeType {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0x00 => CartridgeType::ROMOnly,
+            0x01 => CartridgeType::MBC1,
+            0x02 => CartridgeType::MBC1RAM,
+            0x03 => CartridgeType::MBC1RAMBattery,
+            0x05 => CartridgeType::MBC2,
+            0x06 => CartridgeType::MBC2Battery,
+            0x08 => CartridgeType::ROMRAM,
+            0x09 => CartridgeType::ROMRAMBattery,
+            0x0B => CartridgeType::MMM01,
+            0x0C => CartridgeType::MMM01RAM,
+            0x0D => CartridgeType::MMM01RAMBattery,
+            0x0F => CartridgeType::MBC3TimerBattery,
+            0x10 => CartridgeType::MBC3TimerRAMBattery,
+            0x11 => CartridgeType::MBC3,
+            0x12 => CartridgeType::MBC3RAM,
+            0x13 => CartridgeType::MBC3RAMBattery,
+            0x19 => CartridgeType::MBC5,
+            0x1A => CartridgeType::MBC5RAM,
+            0x1B => CartridgeType::MBC5RAMBattery,
+            0x1C => CartridgeType::MBC5Rumble,
+            0x1D => CartridgeType::MBC5RumbleRAM,
+            0x1E => CartridgeType::MBC5RumbleRAMBattery,
+            0x20 => CartridgeType::MBC6,
+            0x22 => CartridgeType::MBC7SensorRumbleRAMBattery,
+            0xFC => CartridgeType::PocketCamera,
+            0xFD => CartridgeType::BandaiTAMA5,
+            0xFE => CartridgeType::HuC3,
+            0xFF => CartridgeType::HuC1RAMBattery,
+            _ => CartridgeType::Unknown(code),
+        }
+    }
+
+    /// The emulated MBC and its (SRAM, Battery, RTC) features, or None if the hardware isn't supported
+    pub fn features(&self) -> Option<(MBCType, bool, bool, bool)> {
+        match self {
+            CartridgeType::ROMOnly => Some((MBCType::ROMOnly, false, false, false)),
+            CartridgeType::MBC1 => Some((MBCType::MBC1, false, false, false)),
+            CartridgeType::MBC1RAM => Some((MBCType::MBC1, true, false, false)),
+            CartridgeType::MBC1RAMBattery => Some((MBCType::MBC1, true, true, false)),
+            CartridgeType::MBC2 => Some((MBCType::MBC2, false, false, false)),
+            CartridgeType::MBC2Battery => Some((MBCType::MBC2, false, true, false)),
+            CartridgeType::ROMRAM => Some((MBCType::ROMOnly, true, false, false)),
+            CartridgeType::ROMRAMBattery => Some((MBCType::ROMOnly, true, true, false)),
+            CartridgeType::MBC3TimerBattery => Some((MBCType::MBC3, false, true, true)),
+            CartridgeType::MBC3TimerRAMBattery => Some((MBCType::MBC3, true, true, true)),
+            CartridgeType::MBC3 => Some((MBCType::MBC3, false, false, false)),
+            CartridgeType::MBC3RAM => Some((MBCType::MBC3, true, false, false)),
+            CartridgeType::MBC3RAMBattery => Some((MBCType::MBC3, true, true, false)),
+            CartridgeType::MBC5 => Some((MBCType::MBC5, false, false, false)),
+            CartridgeType::MBC5RAM => Some((MBCType::MBC5, true, false, false)),
+            CartridgeType::MBC5RAMBattery => Some((MBCType::MBC5, true, true, false)),
+            CartridgeType::MBC5Rumble => Some((MBCType::MBC5, false, false, false)),
+            CartridgeType::MBC5RumbleRAM => Some((MBCType::MBC5, true, false, false)),
+            CartridgeType::MBC5RumbleRAMBattery => Some((MBCType::MBC5, true, true, false)),
+            _ => None,
+        }
+    }
+}
+
+/// CGB support, from 0x0143
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CGBFlag {
+    DMGOnly,
+    CGBEnhanced,
+    CGBOnly,
+}
+
+/// Fatal problems that stop a header from being decoded at all
+#[derive(Debug, PartialEq)]
+pub enum HeaderError {
+    TooShort(usize),
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::TooShort(length) => write!(f, "ROM is {} bytes, too short to hold a header", length),
+        }
+    }
+}
+
+/// Problems found by `CartridgeHeader::validate`. Only some of them stop real hardware from booting.
+#[derive(Debug, PartialEq)]
+pub enum HeaderIssue {
+    TooShort(usize),
+    LogoMismatch,
+    HeaderChecksumMismatch { stored: u8, computed: u8 },
+    GlobalChecksumMismatch { stored: u16, computed: u16 },
+    UnknownCartridgeType(u8),
+    UnknownROMSize(u8),
+    UnknownRAMSize(u8),
+    ROMSizeMismatch { header: usize, file: usize },
+}
+
+impl fmt::Display for HeaderIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderIssue::TooShort(length) => write!(f, "ROM is {} bytes, too short to hold a header", length),
+            HeaderIssue::LogoMismatch => write!(f, "Nintendo logo does not match"),
+            HeaderIssue::HeaderChecksumMismatch { stored, computed } =>
+                write!(f, "header checksum is {:#04x}, expected {:#04x}", stored, computed),
+            HeaderIssue::GlobalChecksumMismatch { stored, computed } =>
+                write!(f, "global checksum is {:#06x}, expected {:#06x}", stored, computed),
+            HeaderIssue::UnknownCartridgeType(code) => write!(f, "unknown cartridge type {:#04x}", code),
+            HeaderIssue::UnknownROMSize(code) => write!(f, "unknown ROM size code {:#04x}", code),
+            HeaderIssue::UnknownRAMSize(code) => write!(f, "unknown RAM size code {:#04x}", code),
+            HeaderIssue::ROMSizeMismatch { header, file } =>
+                write!(f, "header declares {} bytes of ROM, file has {}", header, file),
+        }
+    }
+}
+
+/// Decoded cartridge header at 0x0100-0x014F
+#[derive(Clone, Debug, PartialEq)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub manufacturer_code: Option<String>,
+    pub cgb_flag: CGBFlag,
+    pub sgb_flag: bool,
+    pub new_licensee_code: Option<String>,
+    pub old_licensee_code: u8,
+    pub cart_type: CartridgeType,
+    pub rom_size_code: u8,
+    pub ram_size_code: u8,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+/// One field per line, as `--info` prints them
+impl fmt::Display for CartridgeHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let size = |size: Option<usize>, code: u8| match size {
+            Some(size) => format!("{} KiB", size / 1024),
+            None => format!("unknown ({:#04x})", code),
+        };
+        writeln!(f, "Title:           {}", self.title)?;
+        if let Some(code) = &self.manufacturer_code {
+            writeln!(f, "Manufacturer:    {}", code)?;
+        }
+        writeln!(f, "CGB:             {:?}", self.cgb_flag)?;
+        writeln!(f, "SGB:             {}", if self.sgb_flag { "yes" } else { "no" })?;
+        match &self.new_licensee_code {
+            Some(code) => writeln!(f, "Licensee:        {}", code)?,
+            None => writeln!(f, "Licensee:        {:#04x}", self.old_licensee_code)?,
+        }
+        writeln!(f, "Cartridge type:  {:?}", self.cart_type)?;
+        writeln!(f, "ROM size:        {}", size(self.rom_size(), self.rom_size_code))?;
+        writeln!(f, "RAM size:        {}", size(self.ram_size(), self.ram_size_code))?;
+        writeln!(f, "Version:         {}", self.version)?;
+        writeln!(f, "Header checksum: {:#04x}", self.header_checksum)?;
+        write!(f, "Global checksum: {:#06x}", self.global_checksum)
+    }
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Result<Self, HeaderError> {
+        if rom.len() < HEADER_END {
+            return Err(HeaderError::TooShort(rom.len()));
+        }
+
+        let cgb_flag = match rom[0x0143] {
+            0x80 => CGBFlag::CGBEnhanced,
+            0xC0 => CGBFlag::CGBOnly,
+            _ => CGBFlag::DMGOnly,
+        };
+
+        // Later carts shortened the title to make room for the CGB flag and a manufacturer code
+        let manufacturer = &rom[0x013F..0x0143];
+        let manufacturer_code = if cgb_flag != CGBFlag::DMGOnly
+            && manufacturer.iter().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()) {
+            Some(String::from_utf8_lossy(manufacturer).into_owned())
+        } else {
+            None
+        };
+        let title_end = match (&manufacturer_code, cgb_flag) {
+            (Some(_), _) => 0x013F,
+            (None, CGBFlag::DMGOnly) => 0x0144,
+            (None, _) => 0x0143,
+        };
+        let title = rom[0x0134..title_end]
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| if c.is_ascii_graphic() || *c == b' ' { *c as char } else { '?' })
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+
+        let old_licensee_code = rom[0x014B];
+        let new_licensee_code = if old_licensee_code == 0x33 {
+            Some(String::from_utf8_lossy(&rom[0x0144..0x0146]).into_owned())
+        } else {
+            None
+        };
+
+        Ok(Self {
+            title,
+            manufacturer_code,
+            cgb_flag,
+            sgb_flag: rom[0x0146] == 0x03,
+            new_licensee_code,
+            old_licensee_code,
+            cart_type: CartridgeType::from_code(rom[0x0147]),
+            rom_size_code: rom[0x0148],
+            ram_size_code: rom[0x0149],
+            version: rom[0x014C],
+            header_checksum: rom[0x014D],
+            global_checksum: u16::from_be_bytes([rom[0x014E], rom[0x014F]]),
+        })
+    }
+
+    /// ROM size in bytes, from 0x0148
+    pub fn rom_size(&self) -> Option<usize> {
+        match self.rom_size_code {
+            code @ 0x00..=0x08 => Some(0x8000 << code),
+            _ => None,
+        }
+    }
+
+    /// External RAM size in bytes, from 0x0149
+    pub fn ram_size(&self) -> Option<usize> {
+        match self.ram_size_code {
+            0x00 => Some(0),
+            0x01 => Some(0x800),
+            0x02 => Some(0x2000),
+            0x03 => Some(0x8000),
+            0x04 => Some(0x20000),
+            0x05 => Some(0x10000),
+            _ => None,
+        }
+    }
+
+    pub fn compute_header_checksum(rom: &[u8]) -> u8 {
+        let mut x: u8 = 0;
+        for byte in &rom[0x0134..0x014D] {
+            x = x.wrapping_sub(*byte).wrapping_sub(1);
+        }
+        x
+    }
+
+    pub fn compute_global_checksum(rom: &[u8]) -> u16 {
+        rom.iter()
+            .enumerate()
+            .filter(|(i, _)| *i != 0x014E && *i != 0x014F)
+            .fold(0u16, |sum, (_, byte)| sum.wrapping_add(*byte as u16))
+    }
+
+    /// Checks the header against the full ROM image it was parsed from
+    pub fn validate(&self, rom: &[u8]) -> Vec<HeaderIssue> {
+        if rom.len() < HEADER_END {
+            return vec![HeaderIssue::TooShort(rom.len())];
+        }
+        let mut issues = vec![];
+        if rom[0x0104..0x0134] != NINTENDO_LOGO {
+            issues.push(HeaderIssue::LogoMismatch);
+        }
+        let computed = CartridgeHeader::compute_header_checksum(rom);
+        if computed != self.header_checksum {
+            issues.push(HeaderIssue::HeaderChecksumMismatch { stored: self.header_checksum, computed });
+        }
+        let computed = CartridgeHeader::compute_global_checksum(rom);
+        if computed != self.global_checksum {
+            issues.push(HeaderIssue::GlobalChecksumMismatch { stored: self.global_checksum, computed });
+        }
+        if let CartridgeType::Unknown(code) = self.cart_type {
+            issues.push(HeaderIssue::UnknownCartridgeType(code));
+        }
+        match self.rom_size() {
+            Some(size) if size != rom.len() => issues.push(HeaderIssue::ROMSizeMismatch { header: size, file: rom.len() }),
+            None => issues.push(HeaderIssue::UnknownROMSize(self.rom_size_code)),
+            _ => {}
+        }
+        if self.ram_size().is_none() {
+            issues.push(HeaderIssue::UnknownRAMSize(self.ram_size_code));
+        }
+        issues
+    }
+}
+
+
+// Tests
+#[test]
+fn parse_header() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x0104..0x0134].copy_from_slice(&NINTENDO_LOGO);
+    rom[0x0134..0x013F].copy_from_slice(b"POKEMON_GLD");
+    rom[0x013F..0x0143].copy_from_slice(b"AAUE");
+    rom[0x0143] = 0x80;
+    rom[0x0144..0x0146].copy_from_slice(b"01");
+    rom[0x0146] = 0x03;
+    rom[0x0147] = 0x10;
+    rom[0x0149] = 0x03;
+    rom[0x014B] = 0x33;
+    rom[0x014C] = 0x01;
+    rom[0x014D] = CartridgeHeader::compute_header_checksum(&rom);
+    let global = CartridgeHeader::compute_global_checksum(&rom);
+    rom[0x014E..0x0150].copy_from_slice(&global.to_be_bytes());
+
+    let header = CartridgeHeader::parse(&rom).unwrap();
+    assert_eq!(header.title, "POKEMON_GLD");
+    assert_eq!(header.manufacturer_code.as_deref(), Some("AAUE"));
+    assert_eq!(header.cgb_flag, CGBFlag::CGBEnhanced);
+    assert!(header.sgb_flag);
+    assert_eq!(header.new_licensee_code.as_deref(), Some("01"));
+    assert_eq!(header.cart_type, CartridgeType::MBC3TimerRAMBattery);
+    assert_eq!(header.cart_type.features(), Some((MBCType::MBC3, true, true, true)));
+    assert_eq!(header.rom_size(), Some(0x8000));
+    assert_eq!(header.ram_size(), Some(0x8000));
+    assert_eq!(header.version, 1);
+    assert_eq!(header.validate(&rom), vec![]);
+    let info = header.to_string();
+    assert!(info.starts_with("Title:           POKEMON_GLD\nManufacturer:    AAUE\n"));
+    assert!(info.contains("Cartridge type:  MBC3TimerRAMBattery\nROM size:        32 KiB\nRAM size:        32 KiB\n"));
+
+    rom[0x0147] = 0x42;
+    rom[0x0148] = 0x01;
+    let header = CartridgeHeader::parse(&rom).unwrap();
+    let issues = header.validate(&rom);
+    assert!(issues.contains(&HeaderIssue::UnknownCartridgeType(0x42)));
+    assert!(issues.contains(&HeaderIssue::ROMSizeMismatch { header: 0x10000, file: 0x8000 }));
+    assert!(matches!(issues[0], HeaderIssue::HeaderChecksumMismatch { .. }));
+
+    assert_eq!(CartridgeHeader::parse(&rom[..0x100]), Err(HeaderError::TooShort(0x100)));
+    assert_eq!(header.validate(&rom[..0x100]), vec![HeaderIssue::TooShort(0x100)]);
+}
@@ -1,10 +1,14 @@
 use std::ffi::c_int;
-use crate::cartridge::base_mbc::BaseMBC;
+use crate::cartridge::base_mbc::{BaseMBC, MemoryBankController, ROM_BANK_SIZE};
+use crate::cartridge::header::NINTENDO_LOGO;
+use crate::util::{StateError, StateReader, StateWriter};
 
 pub struct MBC1 {
     pub base_mbc: BaseMBC,
     pub bank_select_register1: u8,
     pub bank_select_register2: u8,
+    // MBC1M multicarts wire the upper bank bits one line lower, making 4 games of 16 banks
+    pub multicart: bool,
 }
 
 pub struct MBC2 {
@@ -17,238 +21,362 @@ pub struct MBC3 {
 
 pub struct MBC5 {
     pub base_mbc: BaseMBC,
+    // Unlike the other MBCs bank 0 can be mapped, and the 9-bit register is kept unmasked
+    pub rom_bank_register: u16,
 }
 
 impl MBC1 {
-    pub fn new(&mut self, filename: String, rom_banks: Vec<u8>,
-               external_ram_count: c_int, cart_type: u8, sram: u8,
+    pub fn new(filename: String, rom_banks: Vec<u8>,
+               external_ram_count: c_int, sram: bool,
                battery_enabled: bool, rtc_enabled: bool) -> Self {
+        let multicart = MBC1::is_multicart(&rom_banks);
         Self {
-            base_mbc: BaseMBC::new(filename, rom_banks, external_ram_count, cart_type,
+            base_mbc: BaseMBC::new(filename, rom_banks, external_ram_count,
             sram, battery_enabled, rtc_enabled),
             bank_select_register1: 1,
-            bank_select_register2: 0
+            bank_select_register2: 0,
+            multicart,
         }
     }
 
-    pub fn set_item(&mut self, address: u16, mut value: u8) {
-        if (0x0000 <= address) && (address < 0x2000) {
-            self.base_mbc.ram_bank_enabled = (value & 0b00001111) == 0b1010
-        }
-        else if (0x2000 <= address) && (address < 0x4000) {
-            value &= 0b00011111;
-            if value == 0 { value = 1; }
-            self.bank_select_register1 = value
-        }
-        else if (0x4000 <= address) && (address < 0x6000) {
-            self.bank_select_register2 = value & 0b11;
-        }
-        else if (0x6000 <= address) && (address < 0x8000) {
-            self.base_mbc.memory_model = value & 0b1
-        }
-        else if (0xA000 <= address) && (address < 0xC000) {
-            if self.base_mbc.ram_bank_enabled {
-                self.base_mbc.ram_bank_selected =
-                    if self.base_mbc.memory_model == 1 { self.bank_select_register2 as u16 }
-                    else { 0 };
-                let range_start = (self.base_mbc.ram_bank_selected % self.base_mbc.external_ram_count as u16) as usize;
-                let range_end = (address - 0xA000) as usize;
-                for v in &mut self.base_mbc.ram_banks[range_start..range_end] {
-                    *v = value;
-                }
-            }
-        }
-        else {
-            panic!["Invalid writing address: {}", address];
-        }
+    /// MBC1M carts share the MBC1 cart type, but are 1 MiB and carry a second Nintendo logo in the
+    /// header of the game starting at bank 0x10
+    fn is_multicart(rom_banks: &[u8]) -> bool {
+        let second_game = 0x10 * ROM_BANK_SIZE;
+        rom_banks.len() == 0x40 * ROM_BANK_SIZE
+            && rom_banks[second_game + 0x0104..second_game + 0x0134] == NINTENDO_LOGO
+    }
+
+    /// Recomputes the banks mapped into 0x0000-0x3FFF, 0x4000-0x7FFF and 0xA000-0xBFFF
+    fn update_banks(&mut self) {
+        let (low_bits, high_shift) = if self.multicart { (0b00001111, 4) } else { (0b00011111, 5) };
+        let high = (self.bank_select_register2 as u16) << high_shift;
 
+        self.base_mbc.rom_bank_selected = self.base_mbc.mask_rom_bank(high | (self.bank_select_register1 & low_bits) as u16);
         if self.base_mbc.memory_model == 1 {
-            self.base_mbc.rom_bank_selected_low = ((self.bank_select_register2 << 5) & self.base_mbc.external_rom_count as u8) as u16;
+            // Mode 1 also applies the upper bits to the first ROM bank and selects the RAM bank
+            self.base_mbc.rom_bank_selected_low = self.base_mbc.mask_rom_bank(high);
+            self.base_mbc.ram_bank_selected = self.base_mbc.mask_ram_bank(self.bank_select_register2 as u16);
         }
         else {
             self.base_mbc.rom_bank_selected_low = 0;
+            self.base_mbc.ram_bank_selected = 0;
         }
+    }
+}
 
-        self.base_mbc.rom_bank_selected = (((self.bank_select_register2 << 5) | self.bank_select_register1) % self.base_mbc.external_rom_count as u8) as u16;
+impl MemoryBankController for MBC1 {
+    fn base(&self) -> &BaseMBC { &self.base_mbc }
+    fn base_mut(&mut self) -> &mut BaseMBC { &mut self.base_mbc }
+
+    fn save_state(&mut self, state: &mut StateWriter) {
+        self.base_mbc.save_state(state);
+        state.write_u8(self.bank_select_register1);
+        state.write_u8(self.bank_select_register2);
     }
 
-    pub fn get_item(&mut self, address: u16) -> u8 {
-        if (0xA000 <= address) && (address < 0xC000) {
-            if !self.base_mbc.ram_bank_initialized {
-                panic!["RAM banks not initialized: {}", address];
-            }
-            if !self.base_mbc.ram_bank_enabled {
-                return 0xFF
-            }
-            if self.base_mbc.memory_model == 1 {
-                self.base_mbc.ram_bank_selected = (self.bank_select_register2 % self.base_mbc.external_ram_count as u8) as u16;
-            }
-            else {
-                self.base_mbc.ram_bank_selected = 0;
-            }
-            self.base_mbc.ram_banks[self.base_mbc.ram_bank_selected as usize..(address-0xA000) as usize].iter().sum::<u8>()
+    fn load_state(&mut self, state: &mut StateReader, state_version: u32) -> Result<(), StateError> {
+        self.base_mbc.load_state(state, state_version)?;
+        self.bank_select_register1 = state.read_u8()? & 0b00011111;
+        self.bank_select_register2 = state.read_u8()? & 0b00000011;
+        self.update_banks();
+        Ok(())
+    }
+
+    fn write_rom(&mut self, address: u16, mut value: u8) {
+        if address < 0x2000 {
+            self.base_mbc.set_ram_enabled((value & 0b00001111) == 0b1010);
+        }
+        else if (0x2000..0x4000).contains(&address) {
+            value &= 0b00011111;
+            if value == 0 { value = 1; }
+            self.bank_select_register1 = value
+        }
+        else if (0x4000..0x6000).contains(&address) {
+            self.bank_select_register2 = value & 0b11;
         }
         else {
-            panic!["Invalid reading address: {}", address];
+            self.base_mbc.memory_model = value & 0b1
         }
+
+        self.update_banks();
     }
 }
 
 impl MBC2 {
-    pub fn new(&mut self, filename: String, rom_banks: Vec<u8>,
-               external_ram_count: c_int, cart_type: u8, sram: u8,
+    pub fn new(filename: String, rom_banks: Vec<u8>,
+               external_ram_count: c_int, sram: bool,
                battery_enabled: bool, rtc_enabled: bool) -> Self {
-        Self {
-            base_mbc: BaseMBC::new(filename, rom_banks, external_ram_count, cart_type,
+        let mut mbc = Self {
+            base_mbc: BaseMBC::new(filename, rom_banks, external_ram_count,
                                    sram, battery_enabled, rtc_enabled),
-        }
+        };
+        // MBC2 has its RAM built in, and its save files only hold those 512 half-bytes
+        mbc.base_mbc.ram_banks = vec![0; 512];
+        mbc
     }
+}
 
-    pub fn set_item(&mut self, address: u16, mut value: u8) {
-        if (0x0000 <= address) && (address < 0x4000) {
+impl MemoryBankController for MBC2 {
+    fn base(&self) -> &BaseMBC { &self.base_mbc }
+    fn base_mut(&mut self) -> &mut BaseMBC { &mut self.base_mbc }
+
+    fn write_rom(&mut self, address: u16, mut value: u8) {
+        if address < 0x4000 {
             value &= 0b00001111;
             if (address & 0x100) == 0 {
-                self.base_mbc.ram_bank_enabled = value == 0b00001010;
+                self.base_mbc.set_ram_enabled(value == 0b00001010);
             }
             else {
                 if value == 0 { value = 1; }
+                self.base_mbc.rom_bank_selected = self.base_mbc.mask_rom_bank(value as u16);
             }
         }
-        else if (0xA000 <= address) && (address < 0xC000) {
-            if self.base_mbc.ram_bank_enabled {
-                let range_start = 0;
-                let range_end = (address % 512) as usize;
-                for v in &mut self.base_mbc.ram_banks[range_start..range_end] {
-                    *v = value | 0b11110000;
-                }
-            }
-        }
-        else {
-            panic!["Invalid writing address: {}, value: {}", address, value];
-        }
     }
 
-    pub fn get_item(&mut self, address: u16) -> u8 {
-        if (0x0000 <= address) && (address < 0x4000) {
-            return self.base_mbc.ram_banks[0..address as usize].iter().sum::<u8>();
-        }
-        else if (0x4000 <= address) && (address < 0x8000) {
-            return self.base_mbc.ram_banks[self.base_mbc.ram_bank_selected as usize..(address - 0x4000) as usize].iter().sum::<u8>();
-        }
-        else if (0xA000 <= address) && (address < 0xC000) {
-            if !self.base_mbc.ram_bank_initialized {
-                panic!["RAM banks not initialized: {}", address];
-            }
-            if !self.base_mbc.ram_bank_enabled {
-                return 0xFF;
-            }
-            else {
-                return self.base_mbc.ram_banks[0..(address % 512) as usize].iter().sum::<u8>();
-            }
+    // MBC2 has 512 half-bytes of built-in RAM, mirrored across the whole window
+    fn read_ram(&mut self, address: u16) -> u8 {
+        if !self.base_mbc.ram_bank_enabled {
+            return 0xFF;
         }
-        else {
-            panic!["Invalid reading address: {}", address];
+        self.base_mbc.read_ram_bank(0, address % 512) | 0b11110000
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if self.base_mbc.ram_bank_enabled {
+            self.base_mbc.write_ram_bank(0, address % 512, value | 0b11110000);
         }
     }
 }
 
 impl MBC3 {
-    pub fn new(&mut self, filename: String, rom_banks: Vec<u8>,
-               external_ram_count: c_int, cart_type: u8, sram: u8,
+    pub fn new(filename: String, rom_banks: Vec<u8>,
+               external_ram_count: c_int, sram: bool,
                battery_enabled: bool, rtc_enabled: bool) -> Self {
         Self {
-            base_mbc: BaseMBC::new(filename, rom_banks, external_ram_count, cart_type,
+            base_mbc: BaseMBC::new(filename, rom_banks, external_ram_count,
                                    sram, battery_enabled, rtc_enabled),
         }
     }
-    pub fn set_item(&mut self, address: u16, mut value: u8) {
-        if (0x0000 <= address) && (address < 0x2000) {
-            if (value & 0b00001111) == 0b1010 {
-                self.base_mbc.ram_bank_enabled = true;
-            }
-            else if value == 0 {
-                self.base_mbc.ram_bank_enabled = false;
-            }
-            else {
-                self.base_mbc.ram_bank_enabled = false;
-                print!["Unexpected command for MBC3 at address: {}", address];
-            }
+}
+
+impl MemoryBankController for MBC3 {
+    fn base(&self) -> &BaseMBC { &self.base_mbc }
+    fn base_mut(&mut self) -> &mut BaseMBC { &mut self.base_mbc }
+
+    fn write_rom(&mut self, address: u16, mut value: u8) {
+        if address < 0x2000 {
+            // Any value but 0x0A in the low bits disables RAM and the clock registers
+            self.base_mbc.set_ram_enabled((value & 0b00001111) == 0b1010);
         }
-        else if (0x2000 <= address) && (address < 0x4000) {
+        else if (0x2000..0x4000).contains(&address) {
             value &= 0b01111111;
             if value == 0 {
                 value = 1;
             }
-            self.base_mbc.rom_bank_selected = (value % self.base_mbc.external_rom_count as u8) as u16;
+            self.base_mbc.rom_bank_selected = self.base_mbc.mask_rom_bank(value as u16);
         }
-        else if (0x4000 <= address) && (address < 0x6000) {
-            if (0x08 <= value) && (value < 0x0C) {
+        else if (0x4000..0x6000).contains(&address) {
+            if (0x08..=0x0C).contains(&value) {
                 self.base_mbc.ram_bank_selected = value as u16;
             } else {
-                self.base_mbc.ram_bank_selected = (value % self.base_mbc.external_ram_count as u8) as u16;
+                self.base_mbc.ram_bank_selected = self.base_mbc.mask_ram_bank((value & 0b11) as u16);
             }
         }
-        else if (0x6000 <= address) && (address < 0x8000) {
-            if self.base_mbc.rtc_enabled {
-                self.base_mbc.rtc.write_command(value);
-            }
+        else if self.base_mbc.rtc_enabled {
+            self.base_mbc.rtc.write_command(value);
+        }
+    }
+
+    fn read_ram(&mut self, address: u16) -> u8 {
+        if !self.base_mbc.ram_bank_enabled {
+            return 0xFF;
+        }
+        if self.base_mbc.ram_bank_selected <= 0x03 {
+            self.base_mbc.read_ram_bank(self.base_mbc.ram_bank_selected, address - 0xA000)
+        }
+        else if self.base_mbc.rtc_enabled && (0x08..=0x0C).contains(&self.base_mbc.ram_bank_selected) {
+            self.base_mbc.rtc.get_register(self.base_mbc.ram_bank_selected)
+        }
+        else {
+            0xFF
         }
-        else if (0xA000 <= address) && (address < 0xC000) {
-            if self.base_mbc.ram_bank_enabled {
-                if self.base_mbc.ram_bank_selected <= 0x03 {
-                    for v in &mut self.base_mbc.ram_banks[self.base_mbc.ram_bank_selected as usize..(address-0xA000) as usize] {
-                        *v = value;
-                    }
-                }
-                else if (0x08 <= self.base_mbc.ram_bank_selected) && (self.base_mbc.ram_bank_selected <= 0x0C) {
-                    self.base_mbc.rtc.set_register(self.base_mbc.ram_bank_selected, value);
-                }
-                else {
-                    panic!["Invalid RAM bank selected: {}", self.base_mbc.ram_bank_selected];
-                }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if self.base_mbc.ram_bank_enabled {
+            if self.base_mbc.ram_bank_selected <= 0x03 {
+                self.base_mbc.write_ram_bank(self.base_mbc.ram_bank_selected, address - 0xA000, value);
             }
-            else {
-                panic!["Invalid writing address: {}", address];
+            else if self.base_mbc.rtc_enabled && (0x08..=0x0C).contains(&self.base_mbc.ram_bank_selected) {
+                self.base_mbc.rtc.set_register(self.base_mbc.ram_bank_selected, value);
+                self.base_mbc.ram_dirty = true;
             }
         }
     }
 }
 
 impl MBC5 {
-    pub fn new(&mut self, filename: String, rom_banks: Vec<u8>,
-               external_ram_count: c_int, cart_type: u8, sram: u8,
+    pub fn new(filename: String, rom_banks: Vec<u8>,
+               external_ram_count: c_int, sram: bool,
                battery_enabled: bool, rtc_enabled: bool) -> Self {
         Self {
-            base_mbc: BaseMBC::new(filename, rom_banks, external_ram_count, cart_type,
+            base_mbc: BaseMBC::new(filename, rom_banks, external_ram_count,
                                    sram, battery_enabled, rtc_enabled),
+            rom_bank_register: 1,
         }
     }
+}
 
-    pub fn set_item(&mut self, address: u16, mut value: u8) {
-        if (0x0000 <= address) && (address < 0x2000) {
-            self.base_mbc.ram_bank_enabled = value == 0b00001010;
-        }
-        else if (0x2000 <= address) && (address < 0x3000) {
-            self.base_mbc.rom_bank_selected = ((self.base_mbc.rom_bank_selected & 0b100000000) |
-                value as u16) % self.base_mbc.external_rom_count as u16;
+impl MemoryBankController for MBC5 {
+    fn base(&self) -> &BaseMBC { &self.base_mbc }
+    fn base_mut(&mut self) -> &mut BaseMBC { &mut self.base_mbc }
+
+    fn save_state(&mut self, state: &mut StateWriter) {
+        self.base_mbc.save_state(state);
+        state.write_u16(self.rom_bank_register);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader, state_version: u32) -> Result<(), StateError> {
+        self.base_mbc.load_state(state, state_version)?;
+        self.rom_bank_register = state.read_u16()? & 0x1FF;
+        self.base_mbc.rom_bank_selected = self.base_mbc.mask_rom_bank(self.rom_bank_register);
+        Ok(())
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        if address < 0x2000 {
+            self.base_mbc.set_ram_enabled((value & 0b00001111) == 0b00001010);
         }
-        else if (0x3000 <= address) && (address < 0x4000) {
-            self.base_mbc.rom_bank_selected = ((((value & 0x1) << 8) |
-                (self.base_mbc.rom_bank_selected as u8 & 0xFF)) %
-                self.base_mbc.external_rom_count as u8) as u16
+        else if (0x2000..0x3000).contains(&address) {
+            self.rom_bank_register = (self.rom_bank_register & 0b100000000) | value as u16;
+            self.base_mbc.rom_bank_selected = self.base_mbc.mask_rom_bank(self.rom_bank_register);
         }
-        else if (0x4000 <= address) && (address < 0x6000) {
-            self.base_mbc.ram_bank_selected = ((value & 0xF) % self.base_mbc.external_ram_count as u8) as u16;
+        else if (0x3000..0x4000).contains(&address) {
+            self.rom_bank_register = ((value as u16 & 0x1) << 8) | (self.rom_bank_register & 0xFF);
+            self.base_mbc.rom_bank_selected = self.base_mbc.mask_rom_bank(self.rom_bank_register);
         }
-        else if (0xA000 <= address) && (address < 0xC000) {
-            if self.base_mbc.ram_bank_enabled {
-                for v in &mut self.base_mbc.ram_banks[self.base_mbc.ram_bank_selected as usize..(address-0xA000) as usize] {
-                    *v = value;
-                }
-            }
-        }
-        else {
-            print!["Unexpected write to {}, {}", address, value];
+        else if (0x4000..0x6000).contains(&address) {
+            self.base_mbc.ram_bank_selected = self.base_mbc.mask_ram_bank((value & 0xF) as u16);
         }
     }
 }
+
+
+// Tests
+#[cfg(test)]
+fn banked_rom(banks: usize) -> Vec<u8> {
+    // Every bank starts with its own number so the mapped bank can be read back
+    let mut rom = vec![0; banks * ROM_BANK_SIZE];
+    for bank in 0..banks {
+        rom[bank * ROM_BANK_SIZE] = bank as u8;
+    }
+    rom
+}
+
+#[test]
+fn mbc1_banking() {
+    let mut mbc = MBC1::new(String::from("test"), banked_rom(128), 4, true, true, false);
+    assert_eq!(mbc.read_rom(0x4000), 1);
+
+    // Bank 0 in the lower register reads as bank 1
+    mbc.write_rom(0x2000, 0x00);
+    assert_eq!(mbc.read_rom(0x4000), 1);
+    mbc.write_rom(0x2000, 0x05);
+    mbc.write_rom(0x4000, 0x02);
+    assert_eq!(mbc.read_rom(0x4000), 0x45);
+    assert_eq!(mbc.read_rom(0x0000), 0);
+
+    // Mode 1 maps the upper bits into the first bank and banks the RAM
+    mbc.write_rom(0x6000, 0x01);
+    assert_eq!(mbc.read_rom(0x0000), 0x40);
+    mbc.write_rom(0x0000, 0x0A);
+    mbc.write_ram(0xA123, 0x77);
+    mbc.write_rom(0x4000, 0x00);
+    assert_eq!(mbc.read_ram(0xA123), 0x00);
+    mbc.write_rom(0x4000, 0x02);
+    assert_eq!(mbc.read_ram(0xA123), 0x77);
+    assert_eq!(mbc.base_mbc.ram_banks[2 * 0x2000 + 0x123], 0x77);
+
+    // Bank numbers past the end of a smaller ROM wrap around
+    let mut mbc = MBC1::new(String::from("test"), banked_rom(8), 1, false, false, false);
+    mbc.write_rom(0x2000, 0x0B);
+    assert_eq!(mbc.read_rom(0x4000), 3);
+}
+
+#[test]
+fn mbc1_multicart() {
+    // Matching headers aren't enough, the second game needs the logo
+    let mut rom = banked_rom(64);
+    assert!(!MBC1::new(String::from("test"), rom.clone(), 1, false, false, false).multicart);
+    for game in [0x00, 0x10] {
+        rom[game * ROM_BANK_SIZE + 0x0104..game * ROM_BANK_SIZE + 0x0134].copy_from_slice(&NINTENDO_LOGO);
+    }
+    let mut mbc = MBC1::new(String::from("test"), rom, 1, false, false, false);
+    assert!(mbc.multicart);
+
+    mbc.write_rom(0x4000, 0x01);
+    mbc.write_rom(0x2000, 0x12);
+    assert_eq!(mbc.read_rom(0x4000), 0x12);
+    mbc.write_rom(0x6000, 0x01);
+    assert_eq!(mbc.read_rom(0x0000), 0x10);
+}
+
+#[test]
+fn mbc5_banking() {
+    let mut rom = banked_rom(512);
+    rom[0x100 * ROM_BANK_SIZE] = 0xAB;
+    let mut mbc = MBC5::new(String::from("test"), rom, 16, true, true, false);
+
+    mbc.write_rom(0x2000, 0x00);
+    assert_eq!(mbc.read_rom(0x4000), 0);
+    mbc.write_rom(0x3000, 0x01);
+    assert_eq!(mbc.read_rom(0x4000), 0xAB);
+
+    mbc.write_rom(0x0000, 0x0A);
+    mbc.write_rom(0x4000, 0x0F);
+    mbc.write_ram(0xBFFF, 0x12);
+    assert_eq!(mbc.base_mbc.ram_banks[16 * 0x2000 - 1], 0x12);
+}
+
+#[test]
+fn battery_save_round_trip() {
+    use crate::cartridge::clock::CYCLES_PER_SECOND;
+
+    let rom = std::env::temp_dir().join(format!("rustyboy_save_{}.gb", std::process::id()));
+    let filename = rom.to_string_lossy().into_owned();
+    let mut mbc = MBC3::new(filename.clone(), banked_rom(4), 1, true, true, true);
+    mbc.write_rom(0x0000, 0x0A);
+    mbc.write_ram(0xA000, 0x42);
+
+    // Disabling RAM schedules an autosave a second of emulated time later
+    mbc.write_rom(0x0000, 0x00);
+    mbc.tick(CYCLES_PER_SECOND as u32 - 1);
+    let save = rom.with_extension("sav");
+    assert!(!save.exists());
+    mbc.tick(1);
+    let data = std::fs::read(&save).unwrap();
+    assert_eq!(data.len(), 0x2000 + 48);
+    assert_eq!(data[0], 0x42);
+
+    let mut restored = MBC3::new(filename.clone(), banked_rom(4), 1, true, true, true);
+    restored.base_mbc.load_ram().unwrap();
+    restored.write_rom(0x0000, 0x0A);
+    assert_eq!(restored.read_ram(0xA000), 0x42);
+    std::fs::remove_file(&save).unwrap();
+
+    // MBC2 battery carts save their built-in RAM as the usual 512 byte file
+    let mut mbc = MBC2::new(filename.clone(), banked_rom(4), 0, false, true, false);
+    mbc.write_rom(0x0000, 0x0A);
+    mbc.write_ram(0xA1FF, 0x05);
+    mbc.stop();
+    let data = std::fs::read(&save).unwrap();
+    assert_eq!(data.len(), 512);
+    assert_eq!(data[0x1FF] & 0x0F, 0x05);
+
+    let mut restored = MBC2::new(filename, banked_rom(4), 0, false, true, false);
+    restored.base_mbc.load_ram().unwrap();
+    restored.write_rom(0x0000, 0x0A);
+    assert_eq!(restored.read_ram(0xA3FF), 0xF5);
+    std::fs::remove_file(save).unwrap();
+}
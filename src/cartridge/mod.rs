@@ -1,4 +1,7 @@
+#[allow(clippy::module_inception)]
 pub mod cartridge;
 pub mod base_mbc;
+pub mod header;
 mod rtc;
-mod mbc_extended;
\ No newline at end of file
+pub mod clock;
+mod mbc_extended;
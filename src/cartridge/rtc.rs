@@ -1,13 +1,25 @@
-use std::ffi::c_int;
-use std::fs::File;
 use std::os::raw::c_double;
-use std::time;
+use crate::cartridge::clock::{ClockSource, EmulatedClock};
+use crate::util::{StateError, StateReader, StateWriter};
 
+/// Size of the BGB/VBA-M compatible RTC block: 5 live and 5 latched registers as u32, then a u64 timestamp
+pub const RTC_FOOTER_SIZE: usize = 48;
+/// Older emulators store the timestamp as u32
+const RTC_FOOTER_SIZE_32: usize = 44;
+
+/// MBC3 real-time clock. The live counters follow a pluggable clock source, by default emulated
+/// time, and catch up with the time that passed between sessions when loaded from a save file.
+#[allow(clippy::upper_case_acronyms)]
 pub struct RTC {
-    pub filename: String,
+    pub clock: Box<dyn ClockSource>,
     pub latch_enabled: bool,
+    // Clock time the live counters were last synchronised at. Only whole seconds are ever taken
+    // off, so the remainder is the state of the sub-second divider.
     pub time_zero: c_double,
-    pub time_lock: bool,
+    pub seconds: u64,
+    pub minutes: u64,
+    pub hours: u64,
+    pub days: u64,
     pub sec_latch: u64,
     pub min_latch: u64,
     pub hour_latch: u64,
@@ -19,49 +31,314 @@ pub struct RTC {
 
 
 impl RTC {
-    pub fn new(filename: String) -> RTC {
-        if filename == "" {
-            return RTC {
-                filename,
-                latch_enabled: false,
-                time_zero: 0.0,
-                time_lock: false,
-                sec_latch: 0,
-                min_latch: 0,
-                hour_latch: 0,
-                day_latch_low: 0,
-                day_latch_high: 0,
-                day_carry: 0,
-                halt: 0,
-            }
+    pub fn new() -> RTC {
+        RTC::with_clock(Box::new(EmulatedClock::new()))
+    }
+
+    pub fn with_clock(clock: Box<dyn ClockSource>) -> RTC {
+        RTC {
+            time_zero: clock.now(),
+            clock,
+            latch_enabled: false,
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            sec_latch: 0,
+            min_latch: 0,
+            hour_latch: 0,
+            day_latch_low: 0,
+            day_latch_high: 0,
+            day_carry: 0,
+            halt: 0,
+        }
+    }
+
+    /// Swaps the time source, keeping the current counters
+    pub fn set_clock(&mut self, clock: Box<dyn ClockSource>) {
+        self.sync();
+        self.time_zero = clock.now();
+        self.clock = clock;
+    }
+
+    /// Passes emulated time on to the clock source. The counters catch up lazily in `sync`.
+    pub fn tick(&mut self, cycles: u32) {
+        self.clock.tick(cycles);
+    }
+
+    /// Brings the live counters up to the clock source's current time
+    fn sync(&mut self) {
+        let now = self.clock.now();
+        let elapsed = now - self.time_zero;
+        if self.halt != 0 || elapsed < 0.0 {
+            self.time_zero = now;
+            return;
+        }
+        let seconds = elapsed.floor();
+        self.advance(seconds as u64);
+        self.time_zero += seconds;
+    }
+
+    /// Ticks the counters forward one second. Like the hardware, a counter only carries into the
+    /// next one when it passes its normal limit; a value written out of range counts up to the
+    /// top of its bit width and silently wraps to 0.
+    fn increment_second(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
         }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days > 0x1FF {
+            self.days = 0;
+            self.day_carry = 1;
+        }
+    }
 
-        let new_filename = filename + ".rtc";
-        let mut time_zero = time::SystemTime::now();
-        let time_lock = false;
-        let day_carry = 0;
-        let halt = 0;
+    /// Advances the clock by whole seconds
+    fn advance(&mut self, mut seconds: u64) {
+        if self.halt != 0 {
+            return;
+        }
+        // Step out-of-range counters one by one until they have wrapped around
+        while seconds > 0 && (self.seconds >= 60 || self.minutes >= 60 || self.hours >= 24) {
+            self.increment_second();
+            seconds -= 1;
+        }
+        if seconds == 0 {
+            return;
+        }
+        let total = self.seconds + seconds;
+        self.seconds = total % 60;
+        let total = self.minutes + total / 60;
+        self.minutes = total % 60;
+        let total = self.hours + total / 60;
+        self.hours = total % 24;
+        let total = self.days + total / 24;
+        if total > 0x1FF {
+            self.day_carry = 1;
+        }
+        self.days = total & 0x1FF;
+    }
 
-        if !std::path::Path::new(&new_filename).exists() {
-            panic!("RTC file does not exist");
+    /// Writing 0x00 then 0x01 to 0x6000-0x7FFF copies the live counters into the readable registers
+    pub fn write_command(&mut self, command: u8) {
+        if command == 0x00 {
+            self.latch_enabled = true;
         }
         else {
-            let file = std::fs::File::open(new_filename).unwrap();
+            if command == 0x01 && self.latch_enabled {
+                self.sync();
+                self.latch_rtc();
+            }
+            self.latch_enabled = false;
+        }
+    }
+
+    fn latch_rtc(&mut self) {
+        self.sec_latch = self.seconds;
+        self.min_latch = self.minutes;
+        self.hour_latch = self.hours;
+        self.day_latch_low = self.days & 0xFF;
+        self.day_latch_high = self.days >> 8;
+    }
 
+    pub fn get_register(&self, register: u16) -> u8 {
+        match register {
+            0x08 => self.sec_latch as u8,
+            0x09 => self.min_latch as u8,
+            0x0A => self.hour_latch as u8,
+            0x0B => self.day_latch_low as u8,
+            0x0C => (self.day_latch_high as u8 & 0b1) | (self.halt as u8) << 6 | (self.day_carry as u8) << 7,
+            _ => 0xFF,
         }
     }
 
-    pub fn write_command(&self, command: u8) {
-        todo!()
+    /// Writes go to the live counters, and are mirrored in the latched registers so they read back
+    pub fn set_register(&mut self, register: u16, value: u8) {
+        self.sync();
+        let value = value as u64;
+        match register {
+            0x08 => {
+                self.seconds = value & 0x3F;
+                self.sec_latch = self.seconds;
+                // Writing the seconds resets the sub-second divider
+                self.time_zero = self.clock.now();
+            }
+            0x09 => {
+                self.minutes = value & 0x3F;
+                self.min_latch = self.minutes;
+            }
+            0x0A => {
+                self.hours = value & 0x1F;
+                self.hour_latch = self.hours;
+            }
+            0x0B => {
+                self.days = (self.days & 0x100) | value;
+                self.day_latch_low = value;
+            }
+            0x0C => {
+                self.days = (self.days & 0xFF) | ((value & 0b1) << 8);
+                self.day_latch_high = value & 0b1;
+                self.halt = (value >> 6) & 0b1;
+                self.day_carry = (value >> 7) & 0b1;
+                if self.halt == 0 {
+                    // Counting restarts from the moment the clock is resumed
+                    self.time_zero = self.clock.now();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Appends the clock in the layout used at the end of .sav files by BGB and VBA-M
+    pub fn write_footer(&mut self, out: &mut Vec<u8>) {
+        self.sync();
+        let day_high = (self.days >> 8) | self.halt << 6 | self.day_carry << 7;
+        let latched_day_high = self.day_latch_high | self.halt << 6 | self.day_carry << 7;
+        for register in [self.seconds, self.minutes, self.hours, self.days & 0xFF, day_high,
+                         self.sec_latch, self.min_latch, self.hour_latch, self.day_latch_low, latched_day_high] {
+            out.extend_from_slice(&(register as u32).to_le_bytes());
+        }
+        out.extend_from_slice(&(self.time_zero as u64).to_le_bytes());
     }
 
-    pub fn set_register(&self, p0: u16, p1: u8) {
-        todo!()
+    /// Restores the clock from a footer made by `write_footer`, then catches up with the time
+    /// passed on the clock source since it was written. Returns false if the data is too short.
+    pub fn read_footer(&mut self, data: &[u8]) -> bool {
+        if data.len() < RTC_FOOTER_SIZE_32 {
+            return false;
+        }
+        let register = |i: usize| u32::from_le_bytes(data[i * 4..i * 4 + 4].try_into().unwrap()) as u64;
+        self.seconds = register(0) & 0x3F;
+        self.minutes = register(1) & 0x3F;
+        self.hours = register(2) & 0x1F;
+        self.days = (register(3) & 0xFF) | ((register(4) & 0b1) << 8);
+        self.halt = (register(4) >> 6) & 0b1;
+        self.day_carry = (register(4) >> 7) & 0b1;
+        self.sec_latch = register(5) & 0x3F;
+        self.min_latch = register(6) & 0x3F;
+        self.hour_latch = register(7) & 0x1F;
+        self.day_latch_low = register(8) & 0xFF;
+        self.day_latch_high = register(9) & 0b1;
+
+        let saved_at = if data.len() >= RTC_FOOTER_SIZE {
+            u64::from_le_bytes(data[40..48].try_into().unwrap())
+        } else {
+            u32::from_le_bytes(data[40..44].try_into().unwrap()) as u64
+        };
+        self.time_zero = saved_at as c_double;
+        self.sync();
+        true
+    }
+
+    /// Saves the counters and how far into the current second the clock is. Unlike the .sav
+    /// footer this holds no timestamp, so a loaded state doesn't catch up with the time passed
+    /// since and replays the same.
+    pub fn save_state(&mut self, state: &mut StateWriter) {
+        self.sync();
+        for register in [self.seconds, self.minutes, self.hours, self.days, self.sec_latch, self.min_latch,
+                         self.hour_latch, self.day_latch_low, self.day_latch_high, self.day_carry, self.halt] {
+            state.write_u16(register as u16);
+        }
+        state.write_bool(self.latch_enabled);
+        state.write_f64(self.clock.now() - self.time_zero);
     }
 
-    fn load_state(&self, file: File, state_version: c_int, time_zero: &mut time::SystemTime) {
-        if state_version <= 12 {
-            self.time_zero = 0;
+    pub fn load_state(&mut self, state: &mut StateReader, _state_version: u32) -> Result<(), StateError> {
+        for register in [&mut self.seconds, &mut self.minutes, &mut self.hours, &mut self.days, &mut self.sec_latch,
+                         &mut self.min_latch, &mut self.hour_latch, &mut self.day_latch_low, &mut self.day_latch_high,
+                         &mut self.day_carry, &mut self.halt] {
+            *register = state.read_u16()? as u64;
         }
+        self.latch_enabled = state.read_bool()?;
+        self.time_zero = self.clock.now() - state.read_f64()?;
+        Ok(())
     }
-}
\ No newline at end of file
+}
+
+
+// Tests
+#[cfg(test)]
+use crate::cartridge::clock::{ManualClock, CYCLES_PER_SECOND};
+
+#[test]
+fn rtc_latch_and_carry() {
+    let mut rtc = RTC::new();
+    rtc.set_register(0x08, 59);
+    rtc.set_register(0x09, 59);
+    rtc.set_register(0x0A, 23);
+    rtc.set_register(0x0B, 0xFF);
+    rtc.set_register(0x0C, 0x01);
+    rtc.tick(CYCLES_PER_SECOND as u32);
+
+    // Nothing changes in the readable registers until latched
+    assert_eq!(rtc.get_register(0x08), 59);
+    rtc.write_command(0x00);
+    rtc.write_command(0x01);
+    assert_eq!(rtc.get_register(0x08), 0);
+    assert_eq!(rtc.get_register(0x09), 0);
+    assert_eq!(rtc.get_register(0x0A), 0);
+    assert_eq!(rtc.get_register(0x0B), 0);
+    assert_eq!(rtc.get_register(0x0C), 0x80);
+
+    // Halted clocks don't count
+    rtc.set_register(0x0C, 0x40);
+    rtc.tick(CYCLES_PER_SECOND as u32 * 5);
+    rtc.write_command(0x00);
+    rtc.write_command(0x01);
+    assert_eq!(rtc.get_register(0x08), 0);
+    assert_eq!(rtc.get_register(0x0C), 0x40);
+}
+
+#[test]
+fn rtc_out_of_range_wraps() {
+    let clock = ManualClock::new(0.0);
+    let mut rtc = RTC::with_clock(Box::new(clock.clone()));
+    rtc.set_register(0x08, 63);
+    rtc.set_register(0x0A, 25);
+    clock.advance(1.0);
+    rtc.write_command(0x00);
+    rtc.write_command(0x01);
+    assert_eq!(rtc.get_register(0x08), 0);
+    assert_eq!(rtc.get_register(0x09), 0);
+    assert_eq!(rtc.get_register(0x0A), 25);
+}
+
+#[test]
+fn rtc_manual_clock() {
+    let clock = ManualClock::new(1_000_000.0);
+    let mut rtc = RTC::with_clock(Box::new(clock.clone()));
+
+    // Fast-forward 3 days, 2 hours and a half second: the half second stays in the divider
+    clock.advance((3 * 86400 + 2 * 3600) as f64 + 0.5);
+    rtc.write_command(0x00);
+    rtc.write_command(0x01);
+    assert_eq!(rtc.get_register(0x0A), 2);
+    assert_eq!(rtc.get_register(0x0B), 3);
+    clock.advance(0.5);
+    rtc.write_command(0x00);
+    rtc.write_command(0x01);
+    assert_eq!(rtc.get_register(0x08), 1);
+
+    // Frozen time, and the save footer round trips
+    let mut footer = vec![];
+    rtc.write_footer(&mut footer);
+    let mut restored = RTC::with_clock(Box::new(clock.clone()));
+    clock.advance(600.0 * 86400.0);
+    assert!(restored.read_footer(&footer));
+    restored.write_command(0x00);
+    restored.write_command(0x01);
+    assert_eq!(restored.get_register(0x0B), (603 - 512) as u8);
+    assert_eq!(restored.get_register(0x0C), 0x80);
+}
@@ -1,6 +1,10 @@
-use crate::motherboard;
 use crate::motherboard::Bus;
+use crate::opcodes::{CB_OPCODES, OPCODES};
+use crate::system::Model;
+use crate::trace::Tracer;
+use crate::util::{StateError, StateReader, StateWriter};
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
 
     // Registers
@@ -21,24 +25,35 @@ pub struct CPU {
     pub interrupt_master_enable: bool,
     pub interrupt_queued: bool,
     pub halted: bool,
+    pub halt_bug: bool,
     pub stopped: bool,
-    pub bail: bool,
 
-    pub interrupts_flag: u8,
-    pub interrupts_enabled: u8,
     pub interrupts_flag_register: u8,
     pub interrupts_enabled_register: u8,
 
-    pub cycles: i64
+    pub cycles: i64,
+
+    /// Logs each instruction before it runs, when set
+    pub tracer: Option<Tracer>,
 }
 
 // Flag bits
-const FLAG_Z: u8 = 0b1000_000;
-const FLAG_N: u8 = 0b0100_000;
-const FLAG_H: u8 = 0b0010_000;
-const FLAG_C: u8 = 0b0001_000;
+const FLAG_Z: u8 = 0b1000_0000;
+const FLAG_N: u8 = 0b0100_0000;
+const FLAG_H: u8 = 0b0010_0000;
+const FLAG_C: u8 = 0b0001_0000;
 const FLAG_MASK: u8 = 0xF0;
 
+// Interrupt bits in IF/IE, in order of priority
+pub const INTR_VBLANK: u8 = 1 << 0;
+pub const INTR_LCDC: u8 = 1 << 1;
+pub const INTR_TIMER: u8 = 1 << 2;
+pub const INTR_SERIAL: u8 = 1 << 3;
+pub const INTR_HIGHTOLOW: u8 = 1 << 4;
+
+pub const IF_ADDRESS: u16 = 0xFF0F;
+pub const IE_ADDRESS: u16 = 0xFFFF;
+
 impl CPU {
     pub fn new() -> Self {
         Self {
@@ -57,13 +72,12 @@ impl CPU {
             interrupt_master_enable: false,
             interrupt_queued: false,
 
-            bail: false,
             halted: false,
+            halt_bug: false,
             stopped: false,
             is_stuck: false,
             cycles: 0,
-            interrupts_flag: 0,
-            interrupts_enabled: 0,
+            tracer: None,
         }
     }
 
@@ -80,13 +94,169 @@ impl CPU {
         self.pc = 0x0100;
     }
 
+    /// Registers as each model's boot ROM leaves them when it jumps to the cartridge. The DMG
+    /// and MGB ones only clear H and C when the header checksum is 0.
+    pub fn set_post_boot_state(&mut self, model: Model, header_checksum: u8) {
+        let flags = if header_checksum == 0 { 0x80 } else { 0xB0 };
+        let [a, f, b, c, d, e, h, l] = match model {
+            Model::DMG => [0x01, flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::MGB => [0xFF, flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::SGB => [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+            Model::CGB => [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+        };
+        self.a = a;
+        self.f = f;
+        self.b = b;
+        self.c = c;
+        self.d = d;
+        self.e = e;
+        self.h = h;
+        self.l = l;
+        self.sp = 0xFFFE;
+        self.pc = 0x0100;
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        for register in [self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l] {
+            state.write_u8(register);
+        }
+        state.write_u16(self.sp);
+        state.write_u16(self.pc);
+        state.write_bool(self.interrupt_master_enable);
+        state.write_bool(self.interrupt_queued);
+        state.write_bool(self.halted);
+        state.write_bool(self.halt_bug);
+        state.write_bool(self.stopped);
+        state.write_bool(self.is_stuck);
+        state.write_u8(self.interrupts_flag_register);
+        state.write_u8(self.interrupts_enabled_register);
+        state.write_u64(self.cycles as u64);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader, _state_version: u32) -> Result<(), StateError> {
+        for register in [&mut self.a, &mut self.f, &mut self.b, &mut self.c, &mut self.d, &mut self.e, &mut self.h, &mut self.l] {
+            *register = state.read_u8()?;
+        }
+        self.sp = state.read_u16()?;
+        self.pc = state.read_u16()?;
+        self.interrupt_master_enable = state.read_bool()?;
+        self.interrupt_queued = state.read_bool()?;
+        self.halted = state.read_bool()?;
+        self.halt_bug = state.read_bool()?;
+        self.stopped = state.read_bool()?;
+        self.is_stuck = state.read_bool()?;
+        self.interrupts_flag_register = state.read_u8()?;
+        self.interrupts_enabled_register = state.read_u8()?;
+        self.cycles = state.read_u64()? as i64;
+        Ok(())
+    }
+
     pub fn step<B: Bus>(&mut self, bus: &mut B) -> u32 {
-        let opcode = self.fetch8(bus);
-        self.execute_instruction(opcode, bus)
+        // An illegal opcode locks the CPU up until it is reset
+        if self.is_stuck {
+            return 4;
+        }
+
+        // HALT sleeps until any enabled interrupt is flagged, regardless of IME
+        if self.halted {
+            if self.pending_interrupts() == 0 {
+                self.cycles += 4;
+                return 4;
+            }
+            self.halted = false;
+        }
+
+        if self.interrupt_master_enable && self.pending_interrupts() != 0 {
+            let cycles = self.handle_interrupt(bus);
+            self.cycles += cycles as i64;
+            return cycles;
+        }
+
+        if self.tracer.is_some() {
+            self.trace(bus);
+        }
+
+        // EI only takes effect after the instruction following it
+        let enable_interrupts = self.interrupt_queued;
+
+        let opcode = if self.halt_bug {
+            // The HALT bug fails to increment PC, so the next byte is read twice
+            self.halt_bug = false;
+            self.read8(bus, self.pc)
+        } else {
+            self.fetch8(bus)
+        };
+        let cycles = self.execute_instruction(opcode, bus);
+
+        if enable_interrupts && self.interrupt_queued {
+            self.interrupt_queued = false;
+            self.interrupt_master_enable = true;
+        }
+
+        self.cycles += cycles as i64;
+        cycles
+    }
+
+    /// Hands the instruction about to run to the tracer, if it wants it
+    fn trace<B: Bus>(&mut self, bus: &mut B) {
+        let Some(mut tracer) = self.tracer.take() else {
+            return;
+        };
+        if tracer.wants(self.pc) {
+            let pcmem = [0, 1, 2, 3].map(|i| self.peek8(bus, self.pc.wrapping_add(i)));
+            tracer.trace(self, pcmem);
+        }
+        self.tracer = Some(tracer);
+    }
+
+    /// Requests an interrupt by setting its bit in IF
+    pub fn set_interrupt_flag(&mut self, flag: u8) {
+        self.interrupts_flag_register |= flag;
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.interrupts_flag_register & self.interrupts_enabled_register & 0x1F
+    }
+
+    /// Services the highest priority pending interrupt: pushes PC and jumps to its vector
+    fn handle_interrupt<B: Bus>(&mut self, bus: &mut B) -> u32 {
+        let pending = self.pending_interrupts();
+        let bit = pending.trailing_zeros() as u16;
+        self.interrupts_flag_register &= !(1 << bit);
+        self.interrupt_master_enable = false;
+        self.interrupt_queued = false;
+        self.push16(bus, self.pc);
+        self.pc = 0x0040 + bit * 8;
+        20
+    }
+
+    /// IF and IE live in the CPU, so accesses to them never reach the bus
+    fn read8<B: Bus>(&mut self, bus: &mut B, address: u16) -> u8 {
+        match address {
+            IF_ADDRESS => self.interrupts_flag_register | 0xE0,
+            IE_ADDRESS => self.interrupts_enabled_register,
+            _ => bus.read8(address),
+        }
+    }
+
+    fn peek8<B: Bus>(&self, bus: &mut B, address: u16) -> u8 {
+        match address {
+            IF_ADDRESS => self.interrupts_flag_register | 0xE0,
+            IE_ADDRESS => self.interrupts_enabled_register,
+            _ => bus.peek8(address),
+        }
+    }
+
+    fn write8<B: Bus>(&mut self, bus: &mut B, address: u16, value: u8) {
+        match address {
+            IF_ADDRESS => self.interrupts_flag_register = value & 0x1F,
+            IE_ADDRESS => self.interrupts_enabled_register = value,
+            _ => bus.write8(address, value),
+        }
     }
 
     fn fetch8<B: Bus>(&mut self, bus: &mut B) -> u8 {
-        let byte = bus.read8(self.pc);
+        let byte = self.read8(bus, self.pc);
         self.pc = self.pc.wrapping_add(1);
         byte
     }
@@ -98,85 +268,455 @@ impl CPU {
     }
 
 
+    /// Executes an instruction, taking its cycles from the opcode table
     fn execute_instruction<B: Bus>(&mut self, opcode: u8, bus: &mut B) -> u32 {
+        // Whether a conditional jump, call or return was taken, which makes it take longer
+        let mut taken = false;
         match opcode {
             // NOP
-            0x00 => {self.nop(); 4},
+            0x00 => self.nop(),
             // LD BC, d16
             0x01 => {
                 let d16 = self.fetch16(bus);
                 self.set_bc(d16);
-                12
             }
-            // LD BC, A
-            0x02 => {self.set_bc(self.a as u16); 8}
+            // LD (BC), A
+            0x02 => self.write8(bus, self.bc(), self.a),
             // INC BC
-            0x03 => {self.set_bc(self.bc().wrapping_add(1)); 8}
+            0x03 => self.set_bc(self.bc().wrapping_add(1)),
             // INC B
-            0x04 => {self.b = self.inc8(self.b); 4}
+            0x04 => self.b = self.inc8(self.b),
             // DEC B
-            0x05 => {self.b = self.dec8(self.b); 4}
+            0x05 => self.b = self.dec8(self.b),
             // LD B, d8
             0x06 => {
                 let d8 = self.fetch8(bus);
                 self.b = d8;
-                8
             }
             // RLCA
-            0x07 => {self.rlca(); 4}
-            // LD a16, SP
+            0x07 => self.rlca(),
+            // LD (a16), SP
             0x08 => {
                 let a16 = self.fetch16(bus);
-                bus.write16(a16, self.sp);
-                20
+                self.write8(bus, a16, self.sp as u8);
+                self.write8(bus, a16.wrapping_add(1), (self.sp >> 8) as u8);
             }
             // ADD HL, BC
             0x09 => {
                 let r = self.add16(self.hl(), self.bc());
                 self.set_hl(r);
-                20
             }
-            // LD A, BC
-            0x0A => {self.a = self.bc() as u8; 8}
+            // LD A, (BC)
+            0x0A => self.a = self.read8(bus, self.bc()),
             // DEC BC
-            0x0B => {self.set_bc(self.bc().wrapping_sub(1)); 8}
+            0x0B => self.set_bc(self.bc().wrapping_sub(1)),
             // INC C
-            0x0C => {self.c = self.inc8(self.c); 4}
+            0x0C => self.c = self.inc8(self.c),
             // DEC C
-            0x0D => {self.c = self.dec8(self.c); 4}
+            0x0D => self.c = self.dec8(self.c),
             // LD C, d8
             0x0E => {
                 let d8 = self.fetch8(bus);
                 self.c = d8;
-                8
             }
             // RRCA
-            0x0F => {self.rrca(); 4}
-            // TODO: STOP
-            0x10 => {4}
+            0x0F => self.rrca(),
+            // STOP, the second byte of the instruction is skipped
+            0x10 => {
+                self.fetch8(bus);
+                self.stopped = true;
+            }
             // LD DE, d16
             0x11 => {
                 let d16 = self.fetch16(bus);
                 self.set_de(d16);
-                12
             }
-            // LD DE, A
-            0x12 => {self.set_de(self.a as u16); 8}
+            // LD (DE), A
+            0x12 => self.write8(bus, self.de(), self.a),
             // INC DE
-            0x13 => {self.set_de(self.de().wrapping_sub(1)); 8}
+            0x13 => self.set_de(self.de().wrapping_add(1)),
             // INC D
-            0x14 => {self.d = self.inc8(self.d); 4}
+            0x14 => self.d = self.inc8(self.d),
             // DEC D
-            0x15 => {self.d = self.dec8(self.d); 4}
+            0x15 => self.d = self.dec8(self.d),
             // LD D, d8
             0x16 => {
                 let d8 = self.fetch8(bus);
                 self.d = d8;
-                8
             }
-            // 
-            _ => panic!("Unknown opcode: {:#04x}", opcode),
+            // RLA
+            0x17 => self.rla(),
+            // JR r8
+            0x18 => taken = self.jr(bus, true),
+            // ADD HL, DE
+            0x19 => {
+                let r = self.add16(self.hl(), self.de());
+                self.set_hl(r);
+            }
+            // LD A, (DE)
+            0x1A => self.a = self.read8(bus, self.de()),
+            // DEC DE
+            0x1B => self.set_de(self.de().wrapping_sub(1)),
+            // INC E
+            0x1C => self.e = self.inc8(self.e),
+            // DEC E
+            0x1D => self.e = self.dec8(self.e),
+            // LD E, d8
+            0x1E => {
+                let d8 = self.fetch8(bus);
+                self.e = d8;
+            }
+            // RRA
+            0x1F => self.rra(),
+            // JR NZ, r8
+            0x20 => taken = self.jr(bus, self.f & FLAG_Z == 0),
+            // LD HL, d16
+            0x21 => {
+                let d16 = self.fetch16(bus);
+                self.set_hl(d16);
+            }
+            // LD (HL+), A
+            0x22 => {
+                self.write8(bus, self.hl(), self.a);
+                self.set_hl(self.hl().wrapping_add(1));
+            }
+            // INC HL
+            0x23 => self.set_hl(self.hl().wrapping_add(1)),
+            // INC H
+            0x24 => self.h = self.inc8(self.h),
+            // DEC H
+            0x25 => self.h = self.dec8(self.h),
+            // LD H, d8
+            0x26 => {
+                let d8 = self.fetch8(bus);
+                self.h = d8;
+            }
+            // DAA
+            0x27 => self.daa(),
+            // JR Z, r8
+            0x28 => taken = self.jr(bus, self.f & FLAG_Z != 0),
+            // ADD HL, HL
+            0x29 => {
+                let r = self.add16(self.hl(), self.hl());
+                self.set_hl(r);
+            }
+            // LD A, (HL+)
+            0x2A => {
+                self.a = self.read8(bus, self.hl());
+                self.set_hl(self.hl().wrapping_add(1));
+            }
+            // DEC HL
+            0x2B => self.set_hl(self.hl().wrapping_sub(1)),
+            // INC L
+            0x2C => self.l = self.inc8(self.l),
+            // DEC L
+            0x2D => self.l = self.dec8(self.l),
+            // LD L, d8
+            0x2E => {
+                let d8 = self.fetch8(bus);
+                self.l = d8;
+            }
+            // CPL
+            0x2F => {
+                self.a = !self.a;
+                self.f |= FLAG_N | FLAG_H;
+            }
+            // JR NC, r8
+            0x30 => taken = self.jr(bus, self.f & FLAG_C == 0),
+            // LD SP, d16
+            0x31 => {
+                self.sp = self.fetch16(bus);
+            }
+            // LD (HL-), A
+            0x32 => {
+                self.write8(bus, self.hl(), self.a);
+                self.set_hl(self.hl().wrapping_sub(1));
+            }
+            // INC SP
+            0x33 => self.sp = self.sp.wrapping_add(1),
+            // INC (HL)
+            0x34 => {
+                let v = self.read8(bus, self.hl());
+                let r = self.inc8(v);
+                self.write8(bus, self.hl(), r);
+            }
+            // DEC (HL)
+            0x35 => {
+                let v = self.read8(bus, self.hl());
+                let r = self.dec8(v);
+                self.write8(bus, self.hl(), r);
+            }
+            // LD (HL), d8
+            0x36 => {
+                let d8 = self.fetch8(bus);
+                self.write8(bus, self.hl(), d8);
+            }
+            // SCF
+            0x37 => {
+                self.f = (self.f & FLAG_Z) | FLAG_C;
+            }
+            // JR C, r8
+            0x38 => taken = self.jr(bus, self.f & FLAG_C != 0),
+            // ADD HL, SP
+            0x39 => {
+                let r = self.add16(self.hl(), self.sp);
+                self.set_hl(r);
+            }
+            // LD A, (HL-)
+            0x3A => {
+                self.a = self.read8(bus, self.hl());
+                self.set_hl(self.hl().wrapping_sub(1));
+            }
+            // DEC SP
+            0x3B => self.sp = self.sp.wrapping_sub(1),
+            // INC A
+            0x3C => self.a = self.inc8(self.a),
+            // DEC A
+            0x3D => self.a = self.dec8(self.a),
+            // LD A, d8
+            0x3E => {
+                let d8 = self.fetch8(bus);
+                self.a = d8;
+            }
+            // CCF
+            0x3F => {
+                self.f = (self.f & (FLAG_Z | FLAG_C)) ^ FLAG_C;
+            }
+            // HALT
+            0x76 => {
+                if !self.interrupt_master_enable && self.pending_interrupts() != 0 {
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
+                }
+            }
+            // LD r, r' (including the (HL) forms)
+            0x40..=0x7F => {
+                let value = self.read_r8(opcode, bus);
+                self.write_r8(opcode >> 3, value, bus);
+            }
+            // ADD/ADC/SUB/SBC/AND/XOR/OR/CP A, r
+            0x80..=0xBF => {
+                let value = self.read_r8(opcode, bus);
+                self.alu(opcode >> 3, value);
+            }
+            // RET NZ
+            0xC0 => taken = self.ret_cc(bus, self.f & FLAG_Z == 0),
+            // POP BC
+            0xC1 => {
+                let v = self.pop16(bus);
+                self.set_bc(v);
+            }
+            // JP NZ, a16
+            0xC2 => taken = self.jp(bus, self.f & FLAG_Z == 0),
+            // JP a16
+            0xC3 => taken = self.jp(bus, true),
+            // CALL NZ, a16
+            0xC4 => taken = self.call(bus, self.f & FLAG_Z == 0),
+            // PUSH BC
+            0xC5 => self.push16(bus, self.bc()),
+            // ADD A, d8
+            0xC6 => {
+                let d8 = self.fetch8(bus);
+                self.add8(d8, false);
+            }
+            // RST 00H
+            0xC7 => self.rst(bus, 0x00),
+            // RET Z
+            0xC8 => taken = self.ret_cc(bus, self.f & FLAG_Z != 0),
+            // RET
+            0xC9 => {
+                self.pc = self.pop16(bus);
+            }
+            // JP Z, a16
+            0xCA => taken = self.jp(bus, self.f & FLAG_Z != 0),
+            // PREFIX CB
+            0xCB => {
+                let cb_opcode = self.fetch8(bus);
+                return self.execute_cb_instruction(cb_opcode, bus);
+            }
+            // CALL Z, a16
+            0xCC => taken = self.call(bus, self.f & FLAG_Z != 0),
+            // CALL a16
+            0xCD => taken = self.call(bus, true),
+            // ADC A, d8
+            0xCE => {
+                let d8 = self.fetch8(bus);
+                self.add8(d8, true);
+            }
+            // RST 08H
+            0xCF => self.rst(bus, 0x08),
+            // RET NC
+            0xD0 => taken = self.ret_cc(bus, self.f & FLAG_C == 0),
+            // POP DE
+            0xD1 => {
+                let v = self.pop16(bus);
+                self.set_de(v);
+            }
+            // JP NC, a16
+            0xD2 => taken = self.jp(bus, self.f & FLAG_C == 0),
+            // CALL NC, a16
+            0xD4 => taken = self.call(bus, self.f & FLAG_C == 0),
+            // PUSH DE
+            0xD5 => self.push16(bus, self.de()),
+            // SUB d8
+            0xD6 => {
+                let d8 = self.fetch8(bus);
+                self.sub8(d8, false);
+            }
+            // RST 10H
+            0xD7 => self.rst(bus, 0x10),
+            // RET C
+            0xD8 => taken = self.ret_cc(bus, self.f & FLAG_C != 0),
+            // RETI
+            0xD9 => {
+                self.pc = self.pop16(bus);
+                self.interrupt_master_enable = true;
+            }
+            // JP C, a16
+            0xDA => taken = self.jp(bus, self.f & FLAG_C != 0),
+            // CALL C, a16
+            0xDC => taken = self.call(bus, self.f & FLAG_C != 0),
+            // SBC A, d8
+            0xDE => {
+                let d8 = self.fetch8(bus);
+                self.sub8(d8, true);
+            }
+            // RST 18H
+            0xDF => self.rst(bus, 0x18),
+            // LDH (a8), A
+            0xE0 => {
+                let a8 = self.fetch8(bus);
+                self.write8(bus, 0xFF00 | a8 as u16, self.a);
+            }
+            // POP HL
+            0xE1 => {
+                let v = self.pop16(bus);
+                self.set_hl(v);
+            }
+            // LD (C), A
+            0xE2 => self.write8(bus, 0xFF00 | self.c as u16, self.a),
+            // PUSH HL
+            0xE5 => self.push16(bus, self.hl()),
+            // AND d8
+            0xE6 => {
+                let d8 = self.fetch8(bus);
+                self.and8(d8);
+            }
+            // RST 20H
+            0xE7 => self.rst(bus, 0x20),
+            // ADD SP, r8
+            0xE8 => {
+                let r8 = self.fetch8(bus);
+                self.sp = self.add_sp_r8(r8);
+            }
+            // JP HL
+            0xE9 => self.pc = self.hl(),
+            // LD (a16), A
+            0xEA => {
+                let a16 = self.fetch16(bus);
+                self.write8(bus, a16, self.a);
+            }
+            // XOR d8
+            0xEE => {
+                let d8 = self.fetch8(bus);
+                self.xor8(d8);
+            }
+            // RST 28H
+            0xEF => self.rst(bus, 0x28),
+            // LDH A, (a8)
+            0xF0 => {
+                let a8 = self.fetch8(bus);
+                self.a = self.read8(bus, 0xFF00 | a8 as u16);
+            }
+            // POP AF
+            0xF1 => {
+                let v = self.pop16(bus);
+                self.a = (v >> 8) as u8;
+                self.f = v as u8 & FLAG_MASK;
+            }
+            // LD A, (C)
+            0xF2 => self.a = self.read8(bus, 0xFF00 | self.c as u16),
+            // DI
+            0xF3 => {
+                self.interrupt_master_enable = false;
+                self.interrupt_queued = false;
+            }
+            // PUSH AF
+            0xF5 => {
+                let af = ((self.a as u16) << 8) | (self.f & FLAG_MASK) as u16;
+                self.push16(bus, af);
+            }
+            // OR d8
+            0xF6 => {
+                let d8 = self.fetch8(bus);
+                self.or8(d8);
+            }
+            // RST 30H
+            0xF7 => self.rst(bus, 0x30),
+            // LD HL, SP+r8
+            0xF8 => {
+                let r8 = self.fetch8(bus);
+                let r = self.add_sp_r8(r8);
+                self.set_hl(r);
+            }
+            // LD SP, HL
+            0xF9 => self.sp = self.hl(),
+            // LD A, (a16)
+            0xFA => {
+                let a16 = self.fetch16(bus);
+                self.a = self.read8(bus, a16);
+            }
+            // EI
+            0xFB => self.interrupt_queued = true,
+            // CP d8
+            0xFE => {
+                let d8 = self.fetch8(bus);
+                self.cp8(d8);
+            }
+            // RST 38H
+            0xFF => self.rst(bus, 0x38),
+            // Illegal opcodes hang the CPU
+            0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => {
+                self.is_stuck = true;
+            }
+        }
+        let info = &OPCODES[opcode as usize];
+        (if taken { info.cycles_taken } else { info.cycles }) as u32
+    }
+
+    /// Executes the second byte of a 0xCB-prefixed instruction. The returned cycles include the prefix.
+    fn execute_cb_instruction<B: Bus>(&mut self, opcode: u8, bus: &mut B) -> u32 {
+        let value = self.read_r8(opcode, bus);
+        let bit = (opcode >> 3) & 0x07;
+        let result = match opcode >> 6 {
+            // RLC/RRC/RL/RR/SLA/SRA/SWAP/SRL r
+            0 => match bit {
+                0 => Some(self.rlc(value)),
+                1 => Some(self.rrc(value)),
+                2 => Some(self.rl(value)),
+                3 => Some(self.rr(value)),
+                4 => Some(self.sla(value)),
+                5 => Some(self.sra(value)),
+                6 => Some(self.swap(value)),
+                _ => Some(self.srl(value)),
+            },
+            // BIT n, r only reads
+            1 => {
+                self.f = (self.f & FLAG_C)
+                    | FLAG_H
+                    | if value & (1 << bit) == 0 { FLAG_Z } else { 0 };
+                None
+            }
+            // RES n, r
+            2 => Some(value & !(1 << bit)),
+            // SET n, r
+            _ => Some(value | (1 << bit)),
+        };
+        if let Some(result) = result {
+            self.write_r8(opcode, result, bus);
         }
+        CB_OPCODES[opcode as usize].cycles as u32
     }
 
 
@@ -184,6 +724,146 @@ impl CPU {
         // Does nothing
     }
 
+    /// Reads one of B, C, D, E, H, L, (HL), A by the 3-bit index used in the opcode encoding
+    fn read_r8<B: Bus>(&mut self, index: u8, bus: &mut B) -> u8 {
+        match index & 0x07 {
+            0 => self.b,
+            1 => self.c,
+            2 => self.d,
+            3 => self.e,
+            4 => self.h,
+            5 => self.l,
+            6 => self.read8(bus, self.hl()),
+            _ => self.a,
+        }
+    }
+
+    fn write_r8<B: Bus>(&mut self, index: u8, value: u8, bus: &mut B) {
+        match index & 0x07 {
+            0 => self.b = value,
+            1 => self.c = value,
+            2 => self.d = value,
+            3 => self.e = value,
+            4 => self.h = value,
+            5 => self.l = value,
+            6 => self.write8(bus, self.hl(), value),
+            _ => self.a = value,
+        }
+    }
+
+    fn push16<B: Bus>(&mut self, bus: &mut B, value: u16) {
+        self.sp = self.sp.wrapping_sub(1);
+        self.write8(bus, self.sp, (value >> 8) as u8);
+        self.sp = self.sp.wrapping_sub(1);
+        self.write8(bus, self.sp, value as u8);
+    }
+
+    fn pop16<B: Bus>(&mut self, bus: &mut B) -> u16 {
+        let lo = self.read8(bus, self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
+        let hi = self.read8(bus, self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
+        (hi << 8) | lo
+    }
+
+    /// Jumps and calls return whether they were taken
+    fn jr<B: Bus>(&mut self, bus: &mut B, condition: bool) -> bool {
+        let r8 = self.fetch8(bus) as i8;
+        if condition {
+            self.pc = self.pc.wrapping_add(r8 as u16);
+        }
+        condition
+    }
+
+    fn jp<B: Bus>(&mut self, bus: &mut B, condition: bool) -> bool {
+        let a16 = self.fetch16(bus);
+        if condition {
+            self.pc = a16;
+        }
+        condition
+    }
+
+    fn call<B: Bus>(&mut self, bus: &mut B, condition: bool) -> bool {
+        let a16 = self.fetch16(bus);
+        if condition {
+            self.push16(bus, self.pc);
+            self.pc = a16;
+        }
+        condition
+    }
+
+    fn ret_cc<B: Bus>(&mut self, bus: &mut B, condition: bool) -> bool {
+        if condition {
+            self.pc = self.pop16(bus);
+        }
+        condition
+    }
+
+    fn rst<B: Bus>(&mut self, bus: &mut B, vector: u16) {
+        self.push16(bus, self.pc);
+        self.pc = vector;
+    }
+
+    /// Dispatches the ALU operation encoded in bits 3-5 of the opcode
+    fn alu(&mut self, op: u8, value: u8) {
+        match op & 0x07 {
+            0 => self.add8(value, false),
+            1 => self.add8(value, true),
+            2 => self.sub8(value, false),
+            3 => self.sub8(value, true),
+            4 => self.and8(value),
+            5 => self.xor8(value),
+            6 => self.or8(value),
+            _ => self.cp8(value),
+        }
+    }
+
+    fn add8(&mut self, value: u8, use_carry: bool) {
+        let carry = if use_carry && self.f & FLAG_C != 0 { 1 } else { 0 };
+        let result = self.a as u16 + value as u16 + carry as u16;
+        let h = (self.a & 0x0F) + (value & 0x0F) + carry > 0x0F;
+        self.a = result as u8;
+        self.f = if self.a == 0 { FLAG_Z } else { 0 }
+            | if h { FLAG_H } else { 0 }
+            | if result > 0xFF { FLAG_C } else { 0 };
+    }
+
+    fn sub8(&mut self, value: u8, use_carry: bool) {
+        self.a = self.compare8(value, use_carry);
+    }
+
+    fn cp8(&mut self, value: u8) {
+        self.compare8(value, false);
+    }
+
+    /// Computes A - value (- carry), setting the flags but leaving A untouched
+    fn compare8(&mut self, value: u8, use_carry: bool) -> u8 {
+        let carry = if use_carry && self.f & FLAG_C != 0 { 1 } else { 0 };
+        let result = self.a.wrapping_sub(value).wrapping_sub(carry);
+        let h = (self.a & 0x0F) < (value & 0x0F) + carry;
+        let c = (self.a as u16) < value as u16 + carry as u16;
+        self.f = FLAG_N
+            | if result == 0 { FLAG_Z } else { 0 }
+            | if h { FLAG_H } else { 0 }
+            | if c { FLAG_C } else { 0 };
+        result
+    }
+
+    fn and8(&mut self, value: u8) {
+        self.a &= value;
+        self.f = FLAG_H | if self.a == 0 { FLAG_Z } else { 0 };
+    }
+
+    fn xor8(&mut self, value: u8) {
+        self.a ^= value;
+        self.f = if self.a == 0 { FLAG_Z } else { 0 };
+    }
+
+    fn or8(&mut self, value: u8) {
+        self.a |= value;
+        self.f = if self.a == 0 { FLAG_Z } else { 0 };
+    }
+
     fn inc8(&mut self, r: u8) -> u8 {
         let old_val = r;
         let result = old_val.wrapping_add(1);
@@ -209,14 +889,38 @@ impl CPU {
     }
 
     fn add16(&mut self, x: u16, y: u16) -> u16 {
-        let mut result = x + y;
-        let mut flag = 0b0000_000;
-        flag += if ((self.hl() & 0xFFF) + (self.bc() & 0xFFF)) > 0xFFF { FLAG_H } else { 0 };
-        flag += if result > 0xFFFF { FLAG_C } else { 0 };
-        self.f &= 0b1000_000;
+        let result = x as u32 + y as u32;
+        let mut flag = 0;
+        flag |= if ((x & 0xFFF) + (y & 0xFFF)) > 0xFFF { FLAG_H } else { 0 };
+        flag |= if result > 0xFFFF { FLAG_C } else { 0 };
+        self.f &= FLAG_Z;
         self.f |= flag;
-        result |= 0xFFFF;
-        result
+        result as u16
+    }
+
+    /// SP + signed r8, as used by ADD SP, r8 and LD HL, SP+r8. Flags come from the low byte.
+    fn add_sp_r8(&mut self, r8: u8) -> u16 {
+        let h = (self.sp & 0x0F) + (r8 as u16 & 0x0F) > 0x0F;
+        let c = (self.sp & 0xFF) + r8 as u16 > 0xFF;
+        self.f = if h { FLAG_H } else { 0 } | if c { FLAG_C } else { 0 };
+        self.sp.wrapping_add(r8 as i8 as u16)
+    }
+
+    fn daa(&mut self) {
+        let mut adjust = 0;
+        let mut carry = self.f & FLAG_C != 0;
+        let subtract = self.f & FLAG_N != 0;
+        if self.f & FLAG_H != 0 || (!subtract && (self.a & 0x0F) > 0x09) {
+            adjust |= 0x06;
+        }
+        if carry || (!subtract && self.a > 0x99) {
+            adjust |= 0x60;
+            carry = true;
+        }
+        self.a = if subtract { self.a.wrapping_sub(adjust) } else { self.a.wrapping_add(adjust) };
+        self.f = (self.f & FLAG_N)
+            | if self.a == 0 { FLAG_Z } else { 0 }
+            | if carry { FLAG_C } else { 0 };
     }
 
     fn bc(&self) -> u16 {((self.b as u16) << 8) | self.c as u16}
@@ -236,27 +940,68 @@ impl CPU {
     }
 
     fn rlca(&mut self) {
-        let mut new_a = (self.a << 1) + (self.a >> 7);
-        let mut flag = 0b0000_000;
-        flag += if new_a > 0xFF { FLAG_C } else { 0 };
-        self.f &= 0b1000_000;
-        self.f |= flag;
-        new_a &= 0xFF;
-        self.a = new_a;
+        self.a = self.rlc(self.a);
+        self.f &= !FLAG_Z;
     }
     fn rrca(&mut self) {
-        let mut new_a = (self.a >> 1) + ((self.a & 1) << 7) + ((self.a & 1) << 8);
-        let mut flag = 0b0000_000;
-        flag += if new_a > 0xFF { FLAG_C } else { 0 };
-        self.f &= 0b1000_000;
-        self.f |= flag;
-        new_a &= 0xFF;
-        self.a = new_a;
+        self.a = self.rrc(self.a);
+        self.f &= !FLAG_Z;
+    }
+    fn rla(&mut self) {
+        self.a = self.rl(self.a);
+        self.f &= !FLAG_Z;
+    }
+    fn rra(&mut self) {
+        self.a = self.rr(self.a);
+        self.f &= !FLAG_Z;
+    }
+
+    /// Sets Z from the result and C from the bit shifted out, clearing N and H
+    fn shift_flags(&mut self, result: u8, carry: bool) -> u8 {
+        self.f = if result == 0 { FLAG_Z } else { 0 } | if carry { FLAG_C } else { 0 };
+        result
+    }
+    fn rlc(&mut self, v: u8) -> u8 {
+        self.shift_flags(v.rotate_left(1), v & 0x80 != 0)
+    }
+    fn rrc(&mut self, v: u8) -> u8 {
+        self.shift_flags(v.rotate_right(1), v & 0x01 != 0)
+    }
+    fn rl(&mut self, v: u8) -> u8 {
+        let carry_in = if self.f & FLAG_C != 0 { 0x01 } else { 0 };
+        self.shift_flags((v << 1) | carry_in, v & 0x80 != 0)
+    }
+    fn rr(&mut self, v: u8) -> u8 {
+        let carry_in = if self.f & FLAG_C != 0 { 0x80 } else { 0 };
+        self.shift_flags((v >> 1) | carry_in, v & 0x01 != 0)
+    }
+    fn sla(&mut self, v: u8) -> u8 {
+        self.shift_flags(v << 1, v & 0x80 != 0)
+    }
+    fn sra(&mut self, v: u8) -> u8 {
+        self.shift_flags((v >> 1) | (v & 0x80), v & 0x01 != 0)
+    }
+    fn swap(&mut self, v: u8) -> u8 {
+        self.shift_flags(v.rotate_left(4), false)
+    }
+    fn srl(&mut self, v: u8) -> u8 {
+        self.shift_flags(v >> 1, v & 0x01 != 0)
     }
 }
 
 
 // Tests
+/// Flat 64 KiB address space for exercising the CPU without a motherboard
+#[cfg(test)]
+impl Bus for Vec<u8> {
+    fn read8(&mut self, address: u16) -> u8 {
+        self[address as usize]
+    }
+    fn write8(&mut self, address: u16, value: u8) {
+        self[address as usize] = value;
+    }
+}
+
 #[test]
 fn inc_flags() {
     let mut cpu = CPU::new();
@@ -281,4 +1026,157 @@ fn dec_flags() {
     cpu.f = 0; cpu.b = 0x01; cpu.b = cpu.dec8(cpu.b);
     assert_eq!(cpu.b, 0x00);
     assert_eq!(cpu.f, FLAG_N | FLAG_Z); // H=0, C unchanged(0)
-}
\ No newline at end of file
+}
+
+#[test]
+fn add16_flags() {
+    let mut cpu = CPU::new();
+    cpu.f = FLAG_Z | FLAG_N;  // Z should be preserved, N cleared
+    assert_eq!(cpu.add16(0x0FFF, 0x0001), 0x1000);
+    assert_eq!(cpu.f, FLAG_Z | FLAG_H);
+
+    cpu.f = 0;
+    assert_eq!(cpu.add16(0xFFFF, 0x0002), 0x0001);
+    assert_eq!(cpu.f, FLAG_H | FLAG_C);
+}
+
+#[test]
+fn call_and_ret() {
+    let mut bus = vec![0; 0x10000];
+    // CALL 0x0200; LD A, 0x42 at 0x0200; RET
+    bus[0x0100..0x0103].copy_from_slice(&[0xCD, 0x00, 0x02]);
+    bus[0x0200..0x0203].copy_from_slice(&[0x3E, 0x42, 0xC9]);
+
+    let mut cpu = CPU::new();
+    assert_eq!(cpu.step(&mut bus), 24);
+    assert_eq!(cpu.pc, 0x0200);
+    assert_eq!(cpu.sp, 0xFFFC);
+    assert_eq!(cpu.step(&mut bus), 8);
+    assert_eq!(cpu.a, 0x42);
+    assert_eq!(cpu.step(&mut bus), 16);
+    assert_eq!(cpu.pc, 0x0103);
+    assert_eq!(cpu.sp, 0xFFFE);
+}
+
+#[test]
+fn cb_instructions() {
+    let mut bus = vec![0; 0x10000];
+    // SWAP A; BIT 7, (HL); SET 7, (HL); RL B
+    bus[0x0100..0x0108].copy_from_slice(&[0xCB, 0x37, 0xCB, 0x7E, 0xCB, 0xFE, 0xCB, 0x10]);
+
+    let mut cpu = CPU::new();
+    cpu.a = 0xF1;
+    cpu.set_hl(0xC000);
+    assert_eq!(cpu.step(&mut bus), 8);
+    assert_eq!(cpu.a, 0x1F);
+    assert_eq!(cpu.f, 0);
+
+    assert_eq!(cpu.step(&mut bus), 12);
+    assert_eq!(cpu.f, FLAG_Z | FLAG_H);
+    assert_eq!(cpu.step(&mut bus), 16);
+    assert_eq!(bus[0xC000], 0x80);
+
+    cpu.f = FLAG_C;
+    cpu.b = 0x80;
+    assert_eq!(cpu.step(&mut bus), 8);
+    assert_eq!(cpu.b, 0x01);
+    assert_eq!(cpu.f, FLAG_C);
+}
+
+#[test]
+fn interrupt_dispatch() {
+    let mut bus = vec![0; 0x10000];
+    // EI; NOP; NOP
+    bus[0x0100] = 0xFB;
+
+    let mut cpu = CPU::new();
+    cpu.interrupts_enabled_register = INTR_VBLANK | INTR_TIMER;
+    cpu.set_interrupt_flag(INTR_TIMER | INTR_VBLANK);
+
+    cpu.step(&mut bus);
+    assert!(!cpu.interrupt_master_enable);
+    // The instruction after EI still runs before the interrupt
+    cpu.step(&mut bus);
+    assert_eq!(cpu.pc, 0x0102);
+    assert!(cpu.interrupt_master_enable);
+
+    // VBlank wins over timer
+    assert_eq!(cpu.step(&mut bus), 20);
+    assert_eq!(cpu.pc, 0x0040);
+    assert_eq!(cpu.interrupts_flag_register, INTR_TIMER);
+    assert_eq!(bus.read16(cpu.sp), 0x0102);
+    assert!(!cpu.interrupt_master_enable);
+}
+
+#[test]
+fn halt_wakeup_and_bug() {
+    let mut bus = vec![0; 0x10000];
+    // HALT; INC A
+    bus[0x0100..0x0102].copy_from_slice(&[0x76, 0x3C]);
+
+    let mut cpu = CPU::new();
+    cpu.interrupts_enabled_register = INTR_TIMER;
+    cpu.step(&mut bus);
+    assert!(cpu.halted);
+    cpu.step(&mut bus);
+    assert_eq!(cpu.pc, 0x0101);
+
+    // Wakes up without servicing the interrupt as IME is off
+    cpu.set_interrupt_flag(INTR_TIMER);
+    cpu.step(&mut bus);
+    assert!(!cpu.halted);
+    assert_eq!(cpu.a, 1);
+
+    // HALT with IME off and an interrupt pending executes INC A twice
+    cpu.reset();
+    cpu.step(&mut bus);
+    assert!(!cpu.halted);
+    cpu.step(&mut bus);
+    cpu.step(&mut bus);
+    assert_eq!(cpu.a, 2);
+    assert_eq!(cpu.pc, 0x0102);
+}
+
+#[test]
+fn opcode_table_matches_cpu() {
+    use crate::opcodes::{Opcode, CB_OPCODES, OPCODES};
+
+    // Runs one instruction with the given flags, returning its cycles and where PC ends up
+    let run = |bytes: &[u8], flags: u8| {
+        let mut bus = vec![0; 0x10000];
+        bus[0x0100..0x0100 + bytes.len()].copy_from_slice(bytes);
+        let mut cpu = CPU::new();
+        cpu.f = flags;
+        cpu.sp = 0xD000;
+        (cpu.step(&mut bus), cpu.pc)
+    };
+    let check = |bytes: &[u8], info: &Opcode| {
+        let mut words = info.mnemonic.split([' ', ',']);
+        let operation = words.next().unwrap();
+        let jumps = matches!(operation, "JP" | "JR" | "CALL" | "RET" | "RETI" | "RST");
+        for flags in [0x00, FLAG_Z | FLAG_C] {
+            let taken = match words.clone().next() {
+                _ if !jumps => false,
+                Some("NZ") => flags & FLAG_Z == 0,
+                Some("Z") => flags & FLAG_Z != 0,
+                Some("NC") => flags & FLAG_C == 0,
+                Some("C") => flags & FLAG_C != 0,
+                _ => true,
+            };
+            let (cycles, pc) = run(bytes, flags);
+            let expected = if taken { info.cycles_taken } else { info.cycles };
+            assert_eq!(cycles, expected as u32, "cycles of {}", info.mnemonic);
+            if !taken {
+                assert_eq!(pc, 0x0100 + info.length as u16, "length of {}", info.mnemonic);
+            }
+        }
+    };
+    for (opcode, info) in OPCODES.iter().enumerate() {
+        if opcode != 0xCB {
+            check(&[opcode as u8], info);
+        }
+    }
+    for (opcode, info) in CB_OPCODES.iter().enumerate() {
+        check(&[0xCB, opcode as u8], info);
+    }
+}
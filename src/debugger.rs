@@ -0,0 +1,647 @@
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use crate::bus::Access;
+use crate::cpu::CPU;
+use crate::disassembler::{disassemble, Symbols};
+use crate::joypad::Button;
+use crate::motherboard::Motherboard;
+use crate::trace;
+
+const HELP: &str = "Addresses and values are hex, with or without a 0x or $ prefix. Counts are decimal.
+Addresses can also be labels from a symbol file.
+
+  b, break ADDR[-END] [if COND]           Stop before executing at ADDR, or anywhere in ADDR-END
+  w, watch ADDR[-END] [r|w|rw] [if COND]  Stop after the CPU reads and/or writes there (rw by default)
+  d, delete ID                            Remove a breakpoint or watchpoint
+  l, list                                 List breakpoints and watchpoints
+  s, step [N]                             Run N instructions (1 by default)
+  n, next                                 Run one instruction, running calls and RSTs to their return
+  finish                                  Run until the current function returns
+  c, continue [FRAMES]                    Run until a breakpoint, or for FRAMES frames
+  r, regs                                 Show the registers
+  x ADDR [LEN]                            Show LEN bytes of memory (16 by default)
+  dis [ADDR] [N]                          Disassemble N instructions (10 by default) from ADDR or PC
+  sym PATH                                Load labels from an RGBDS or no$gmb .sym file
+  set REG VALUE                           Change A, F, B, C, D, E, H, L, AF, BC, DE, HL, SP or PC
+  poke ADDR BYTE...                       Write bytes to memory, as the CPU would
+  press [BUTTON]                          Hold right, left, up, down, a, b, select or start, or
+                                          list the buttons held
+  release BUTTON                          Let go of a button
+  q, quit                                 Leave the debugger
+  h, help                                 Show this help
+
+COND compares a register to a value with ==, !=, <, <=, > or >=, like `a == 3f`. Join several
+with &&. An empty line repeats the last command.";
+
+/// Registers as the debugger names them, including the 16-bit pairs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Register {
+    A, F, B, C, D, E, H, L,
+    AF, BC, DE, HL, SP, PC,
+}
+
+impl Register {
+    fn parse(name: &str) -> Option<Register> {
+        Some(match name.to_lowercase().as_str() {
+            "a" => Register::A,
+            "f" => Register::F,
+            "b" => Register::B,
+            "c" => Register::C,
+            "d" => Register::D,
+            "e" => Register::E,
+            "h" => Register::H,
+            "l" => Register::L,
+            "af" => Register::AF,
+            "bc" => Register::BC,
+            "de" => Register::DE,
+            "hl" => Register::HL,
+            "sp" => Register::SP,
+            "pc" => Register::PC,
+            _ => return None,
+        })
+    }
+
+    pub fn read(self, cpu: &CPU) -> u16 {
+        let pair = |high: u8, low: u8| ((high as u16) << 8) | low as u16;
+        match self {
+            Register::A => cpu.a as u16,
+            Register::F => cpu.f as u16,
+            Register::B => cpu.b as u16,
+            Register::C => cpu.c as u16,
+            Register::D => cpu.d as u16,
+            Register::E => cpu.e as u16,
+            Register::H => cpu.h as u16,
+            Register::L => cpu.l as u16,
+            Register::AF => pair(cpu.a, cpu.f),
+            Register::BC => pair(cpu.b, cpu.c),
+            Register::DE => pair(cpu.d, cpu.e),
+            Register::HL => pair(cpu.h, cpu.l),
+            Register::SP => cpu.sp,
+            Register::PC => cpu.pc,
+        }
+    }
+
+    /// Sets the register, or the pair. The low bits of F don't exist and stay clear.
+    pub fn write(self, cpu: &mut CPU, value: u16) {
+        let (high, low) = ((value >> 8) as u8, value as u8);
+        match self {
+            Register::A => cpu.a = low,
+            Register::F => cpu.f = low & 0xF0,
+            Register::B => cpu.b = low,
+            Register::C => cpu.c = low,
+            Register::D => cpu.d = low,
+            Register::E => cpu.e = low,
+            Register::H => cpu.h = low,
+            Register::L => cpu.l = low,
+            Register::AF => (cpu.a, cpu.f) = (high, low & 0xF0),
+            Register::BC => (cpu.b, cpu.c) = (high, low),
+            Register::DE => (cpu.d, cpu.e) = (high, low),
+            Register::HL => (cpu.h, cpu.l) = (high, low),
+            Register::SP => cpu.sp = value,
+            Register::PC => cpu.pc = value,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    const OPERATORS: [(&'static str, Comparison); 6] = [
+        ("==", Comparison::Equal), ("!=", Comparison::NotEqual), ("<=", Comparison::LessOrEqual),
+        (">=", Comparison::GreaterOrEqual), ("<", Comparison::Less), (">", Comparison::Greater),
+    ];
+
+    fn operator(self) -> &'static str {
+        Comparison::OPERATORS.iter().find(|(_, comparison)| *comparison == self).unwrap().0
+    }
+}
+
+/// A register compared to a value, which must hold for a breakpoint to stop
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Condition {
+    pub register: Register,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+impl Condition {
+    /// Parses `register operator value`, with or without spaces
+    fn parse(text: &str) -> Result<Condition, String> {
+        let (operator, comparison) = Comparison::OPERATORS.iter()
+            .find(|(operator, _)| text.contains(operator))
+            .ok_or(format!("No comparison in condition {}", text.trim()))?;
+        let (register, value) = text.split_once(operator).unwrap();
+        Ok(Condition {
+            register: Register::parse(register.trim()).ok_or(format!("Unknown register {}", register.trim()))?,
+            comparison: *comparison,
+            value: parse_number(value.trim())?,
+        })
+    }
+
+    pub fn holds(&self, cpu: &CPU) -> bool {
+        let register = self.register.read(cpu);
+        match self.comparison {
+            Comparison::Equal => register == self.value,
+            Comparison::NotEqual => register != self.value,
+            Comparison::Less => register < self.value,
+            Comparison::LessOrEqual => register <= self.value,
+            Comparison::Greater => register > self.value,
+            Comparison::GreaterOrEqual => register >= self.value,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} {} {:X}", self.register, self.comparison.operator(), self.value)
+    }
+}
+
+/// What makes a breakpoint stop: executing from its addresses, or reading or writing them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BreakKind {
+    Execute,
+    Read,
+    Write,
+    ReadWrite,
+}
+
+pub struct Breakpoint {
+    pub id: u32,
+    pub kind: BreakKind,
+    // Inclusive
+    pub start: u16,
+    pub end: u16,
+    pub conditions: Vec<Condition>,
+}
+
+impl Breakpoint {
+    fn watches(&self, access: Access) -> bool {
+        let kind_matches = matches!((self.kind, access),
+            (BreakKind::Read | BreakKind::ReadWrite, Access::Read { .. })
+            | (BreakKind::Write | BreakKind::ReadWrite, Access::Write { .. }));
+        kind_matches && self.covers(access.address())
+    }
+
+    fn covers(&self, address: u16) -> bool {
+        self.start <= address && address <= self.end
+    }
+
+    fn conditions_hold(&self, cpu: &CPU) -> bool {
+        self.conditions.iter().all(|condition| condition.holds(cpu))
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            BreakKind::Execute => "break",
+            BreakKind::Read => "watch r",
+            BreakKind::Write => "watch w",
+            BreakKind::ReadWrite => "watch rw",
+        };
+        write!(f, "{}: {} {:04X}", self.id, kind, self.start)?;
+        if self.end != self.start {
+            write!(f, "-{:04X}", self.end)?;
+        }
+        for (i, condition) in self.conditions.iter().enumerate() {
+            write!(f, " {} {}", if i == 0 { "if" } else { "&&" }, condition)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why the debugger handed control back
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stop {
+    // The step, step over, step out or frames asked for are done
+    Done,
+    Breakpoint(u32),
+    Watchpoint(u32, Access),
+    // The CPU ran into an illegal opcode and won't go any further
+    Locked,
+}
+
+/// Runs the machine an instruction at a time, stopping at breakpoints and watchpoints
+pub struct Debugger {
+    pub breakpoints: Vec<Breakpoint>,
+    pub symbols: Symbols,
+    next_id: u32,
+    last_command: String,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: Vec::new(),
+            symbols: Symbols::new(),
+            next_id: 1,
+            last_command: String::new(),
+        }
+    }
+
+    /// Adds a breakpoint over `start..=end`, returning its id
+    pub fn add_breakpoint(&mut self, kind: BreakKind, start: u16, end: u16, conditions: Vec<Condition>) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint { id, kind, start, end, conditions });
+        id
+    }
+
+    pub fn remove_breakpoint(&mut self, id: u32) -> bool {
+        let count = self.breakpoints.len();
+        self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+        self.breakpoints.len() != count
+    }
+
+    /// Runs one instruction, or one cycle of HALT. Returns the watchpoint it hit, if any.
+    fn step_instruction(&self, motherboard: &mut Motherboard) -> Option<Stop> {
+        // VRAM DMA holding the CPU isn't an instruction, so it is run through
+        while motherboard.dma.stall_cycles > 0 {
+            motherboard.step();
+        }
+        let mut accesses = Vec::new();
+        motherboard.step_observed(|access| {
+            if self.breakpoints.iter().any(|breakpoint| breakpoint.watches(access)) {
+                accesses.push(access);
+            }
+        });
+        accesses.into_iter().find_map(|access| {
+            self.breakpoints.iter()
+                .find(|breakpoint| breakpoint.watches(access) && breakpoint.conditions_hold(&motherboard.cpu))
+                .map(|breakpoint| Stop::Watchpoint(breakpoint.id, access))
+        })
+    }
+
+    /// The breakpoint that stops execution at PC, if any
+    fn breakpoint_at(&self, motherboard: &Motherboard) -> Option<u32> {
+        // Waiting in HALT doesn't execute the instruction at PC
+        if motherboard.cpu.halted {
+            return None;
+        }
+        self.breakpoints.iter()
+            .find(|breakpoint| breakpoint.kind == BreakKind::Execute && breakpoint.covers(motherboard.cpu.pc)
+                && breakpoint.conditions_hold(&motherboard.cpu))
+            .map(|breakpoint| breakpoint.id)
+    }
+
+    /// Runs instructions until `finished`, given the opcode just run, says so or a breakpoint
+    /// stops it. The instruction at PC always runs, so running again leaves a breakpoint.
+    fn run(&self, motherboard: &mut Motherboard, mut finished: impl FnMut(&mut Motherboard, u8) -> bool) -> Stop {
+        loop {
+            if motherboard.cpu.is_stuck {
+                return Stop::Locked;
+            }
+            let opcode = motherboard.peek(motherboard.cpu.pc);
+            if let Some(stop) = self.step_instruction(motherboard) {
+                return stop;
+            }
+            if let Some(id) = self.breakpoint_at(motherboard) {
+                return Stop::Breakpoint(id);
+            }
+            if finished(motherboard, opcode) {
+                return Stop::Done;
+            }
+        }
+    }
+
+    /// Runs `count` instructions
+    pub fn step(&self, motherboard: &mut Motherboard, count: u64) -> Stop {
+        let mut remaining = count;
+        self.run(motherboard, |_, _| {
+            remaining -= 1;
+            remaining == 0
+        })
+    }
+
+    /// Runs one instruction, except that calls and RSTs run until they return
+    pub fn step_over(&self, motherboard: &mut Motherboard) -> Stop {
+        let pc = motherboard.cpu.pc;
+        let length = match motherboard.peek(pc) {
+            // CALL and CALL cc
+            0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC => 3,
+            // RST
+            opcode if opcode & 0xC7 == 0xC7 => 1,
+            _ => return self.step(motherboard, 1),
+        };
+        let (return_address, sp) = (pc.wrapping_add(length), motherboard.cpu.sp);
+        // Recursive calls come back to the same address deeper in the stack
+        self.run(motherboard, |motherboard, _| motherboard.cpu.pc == return_address && motherboard.cpu.sp >= sp)
+    }
+
+    /// Runs until a return leaves the current function
+    pub fn step_out(&self, motherboard: &mut Motherboard) -> Stop {
+        let sp = motherboard.cpu.sp;
+        self.run(motherboard, |motherboard, opcode| {
+            // RET cc, RET and RETI, which pop above where the stack started
+            matches!(opcode, 0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9) && motherboard.cpu.sp > sp
+        })
+    }
+
+    /// Runs until a breakpoint, or until the PPU completes `frames` frames
+    pub fn resume(&self, motherboard: &mut Motherboard, frames: Option<u64>) -> Stop {
+        let mut completed = 0;
+        motherboard.ppu.frame_ready = false;
+        self.run(motherboard, |motherboard, _| {
+            if motherboard.ppu.frame_ready {
+                motherboard.ppu.frame_ready = false;
+                completed += 1;
+            }
+            frames.is_some_and(|frames| completed >= frames)
+        })
+    }
+
+    /// Runs one command line, returning what to print. An empty line repeats the last command.
+    pub fn command(&mut self, motherboard: &mut Motherboard, line: &str) -> Result<String, String> {
+        let line = if line.trim().is_empty() { self.last_command.clone() } else { line.trim().to_string() };
+        self.last_command = line.clone();
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(String::new());
+        };
+        let arguments: Vec<&str> = words.collect();
+        let argument = |index: usize| arguments.get(index).copied().ok_or(format!("{} needs more arguments", command));
+
+        let stop = match command {
+            "b" | "break" => {
+                let (start, end) = self.parse_range(argument(0)?)?;
+                let conditions = parse_conditions(&arguments[1..])?;
+                let id = self.add_breakpoint(BreakKind::Execute, start, end, conditions);
+                return Ok(format!("{}", self.breakpoints.iter().find(|breakpoint| breakpoint.id == id).unwrap()));
+            }
+            "w" | "watch" => {
+                let (start, end) = self.parse_range(argument(0)?)?;
+                let (kind, rest) = match arguments.get(1).copied() {
+                    Some("r") => (BreakKind::Read, &arguments[2..]),
+                    Some("w") => (BreakKind::Write, &arguments[2..]),
+                    Some("rw") => (BreakKind::ReadWrite, &arguments[2..]),
+                    _ => (BreakKind::ReadWrite, &arguments[1..]),
+                };
+                let conditions = parse_conditions(rest)?;
+                let id = self.add_breakpoint(kind, start, end, conditions);
+                return Ok(format!("{}", self.breakpoints.iter().find(|breakpoint| breakpoint.id == id).unwrap()));
+            }
+            "d" | "delete" => {
+                let id = argument(0)?;
+                let id = id.parse().map_err(|_| format!("Invalid breakpoint {}", id))?;
+                return if self.remove_breakpoint(id) { Ok(String::new()) } else { Err(format!("No breakpoint {}", id)) };
+            }
+            "l" | "list" => {
+                if self.breakpoints.is_empty() {
+                    return Ok(String::from("No breakpoints"));
+                }
+                let lines: Vec<String> = self.breakpoints.iter().map(|breakpoint| breakpoint.to_string()).collect();
+                return Ok(lines.join("\n"));
+            }
+            "s" | "step" => {
+                let count = match arguments.first() {
+                    Some(count) => count.parse().ok().filter(|&count| count > 0).ok_or(format!("Invalid count {}", count))?,
+                    None => 1,
+                };
+                self.step(motherboard, count)
+            }
+            "n" | "next" => self.step_over(motherboard),
+            "finish" => self.step_out(motherboard),
+            "c" | "continue" => {
+                let frames = match arguments.first() {
+                    Some(frames) => Some(frames.parse().map_err(|_| format!("Invalid frame count {}", frames))?),
+                    None => None,
+                };
+                self.resume(motherboard, frames)
+            }
+            "r" | "regs" => return Ok(registers(motherboard)),
+            "x" => {
+                let address = self.parse_address(argument(0)?)?;
+                let length = match arguments.get(1) {
+                    Some(length) => length.parse().map_err(|_| format!("Invalid length {}", length))?,
+                    None => 16,
+                };
+                return Ok(hex_dump(motherboard, address, length));
+            }
+            "dis" => {
+                let address = match arguments.first() {
+                    Some(address) => self.parse_address(address)?,
+                    None => motherboard.cpu.pc,
+                };
+                let count = match arguments.get(1) {
+                    Some(count) => count.parse().map_err(|_| format!("Invalid count {}", count))?,
+                    None => 10,
+                };
+                return Ok(self.listing(motherboard, address, count));
+            }
+            "sym" => {
+                let path = argument(0)?;
+                self.symbols = Symbols::load(Path::new(path)).map_err(|error| format!("Failed to load {}: {}", path, error))?;
+                return Ok(String::new());
+            }
+            "set" => {
+                let register = Register::parse(argument(0)?).ok_or(format!("Unknown register {}", argument(0)?))?;
+                register.write(&mut motherboard.cpu, parse_number(argument(1)?)?);
+                return Ok(registers(motherboard));
+            }
+            "poke" => {
+                let address = self.parse_address(argument(0)?)?;
+                argument(1)?;
+                for (i, value) in arguments[1..].iter().enumerate() {
+                    let value = parse_number(value)?;
+                    let value = u8::try_from(value).map_err(|_| format!("{:X} doesn't fit in a byte", value))?;
+                    motherboard.poke(address.wrapping_add(i as u16), value);
+                }
+                return Ok(String::new());
+            }
+            "press" if arguments.is_empty() => {
+                let held: Vec<String> = Button::ALL.into_iter().filter(|&button| motherboard.joypad.is_pressed(button))
+                    .map(|button| format!("{:?}", button).to_lowercase()).collect();
+                return Ok(if held.is_empty() { "No buttons held".to_string() } else { held.join(" ") });
+            }
+            "press" | "release" => {
+                let button = Button::parse(argument(0)?).ok_or(format!("Unknown button {}", argument(0)?))?;
+                if command == "press" {
+                    motherboard.press(button);
+                } else {
+                    motherboard.release(button);
+                }
+                return Ok(String::new());
+            }
+            "h" | "help" => return Ok(HELP.to_string()),
+            _ => return Err(format!("Unknown command {}, try help", command)),
+        };
+        Ok(format!("{}\n{}\n{}", self.describe(motherboard, stop), registers(motherboard),
+            self.listing(motherboard, motherboard.cpu.pc, 1)))
+    }
+
+    /// Disassembles `count` instructions from `address`, with a line for each label
+    pub fn listing(&self, motherboard: &mut Motherboard, mut address: u16, count: usize) -> String {
+        let rom_bank = motherboard.rom_bank();
+        let mut lines = Vec::new();
+        for _ in 0..count {
+            let instruction = disassemble(|address| motherboard.peek(address), address, &self.symbols, rom_bank);
+            if let Some(label) = &instruction.label {
+                lines.push(format!("{}:", label));
+            }
+            lines.push(instruction.to_string());
+            address = address.wrapping_add(instruction.length as u16);
+        }
+        lines.join("\n")
+    }
+
+    /// Parses a label, or else a hex address
+    fn parse_address(&self, text: &str) -> Result<u16, String> {
+        match self.symbols.address(text) {
+            Some(address) => Ok(address),
+            None => parse_number(text),
+        }
+    }
+
+    /// Parses ADDR or ADDR-END
+    fn parse_range(&self, text: &str) -> Result<(u16, u16), String> {
+        let (start, end) = match text.split_once('-') {
+            Some((start, end)) => (self.parse_address(start)?, self.parse_address(end)?),
+            None => (self.parse_address(text)?, self.parse_address(text)?),
+        };
+        if end < start {
+            return Err(format!("Range {} ends before it starts", text));
+        }
+        Ok((start, end))
+    }
+
+    fn describe(&self, motherboard: &mut Motherboard, stop: Stop) -> String {
+        match stop {
+            Stop::Done => format!("Stopped at {:04X}", motherboard.cpu.pc),
+            Stop::Breakpoint(id) => format!("Breakpoint {} at {:04X}", id, motherboard.cpu.pc),
+            Stop::Watchpoint(id, Access::Read { address, value }) =>
+                format!("Watchpoint {}: read {:02X} from {:04X}, now at {:04X}", id, value, address, motherboard.cpu.pc),
+            Stop::Watchpoint(id, Access::Write { address, value }) =>
+                format!("Watchpoint {}: wrote {:02X} to {:04X}, now at {:04X}", id, value, address, motherboard.cpu.pc),
+            Stop::Locked => format!("The CPU is locked up by the illegal opcode before {:04X}", motherboard.cpu.pc),
+        }
+    }
+}
+
+/// Reads commands from `input` until quit or the end of input, printing to `output`
+pub fn repl(motherboard: &mut Motherboard, debugger: &mut Debugger, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+    writeln!(output, "{}\n{}", registers(motherboard), debugger.listing(motherboard, motherboard.cpu.pc, 1))?;
+    write!(output, "> ")?;
+    output.flush()?;
+    for line in input.lines() {
+        let line = line?;
+        if matches!(line.trim(), "q" | "quit") {
+            return Ok(());
+        }
+        match debugger.command(motherboard, &line) {
+            Ok(text) if text.is_empty() => {}
+            Ok(text) => writeln!(output, "{}", text)?,
+            Err(message) => writeln!(output, "{}", message)?,
+        }
+        write!(output, "> ")?;
+        output.flush()?;
+    }
+    writeln!(output)
+}
+
+/// Registers in the layout of trace logs, then the flags and what the CPU is doing
+pub fn registers(motherboard: &Motherboard) -> String {
+    let cpu = &motherboard.cpu;
+    let flags: String = ['Z', 'N', 'H', 'C'].iter().enumerate()
+        .map(|(i, &flag)| if cpu.f & (0x80 >> i) != 0 { flag } else { '-' })
+        .collect();
+    let mut text = format!("{} [{}] IME:{} IF:{:02X} IE:{:02X}", trace::registers(cpu), flags,
+        cpu.interrupt_master_enable as u8, cpu.interrupts_flag_register, cpu.interrupts_enabled_register);
+    if cpu.halted {
+        text += " HALTED";
+    }
+    if cpu.stopped {
+        text += " STOPPED";
+    }
+    text
+}
+
+fn hex_dump(motherboard: &mut Motherboard, address: u16, length: usize) -> String {
+    let bytes: Vec<u8> = (0..length).map(|i| motherboard.peek(address.wrapping_add(i as u16))).collect();
+    let lines: Vec<String> = bytes.chunks(16).enumerate().map(|(row, chunk)| {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+        format!("{:04X}: {}", address.wrapping_add(row as u16 * 16), hex.join(" "))
+    }).collect();
+    lines.join("\n")
+}
+
+/// Parses a hex number, which may start with 0x or $
+fn parse_number(text: &str) -> Result<u16, String> {
+    let digits = text.strip_prefix("0x").or(text.strip_prefix('$')).unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid number {}", text))
+}
+
+/// Parses what follows the address of a breakpoint: nothing, or `if` and conditions
+fn parse_conditions(words: &[&str]) -> Result<Vec<Condition>, String> {
+    match words.split_first() {
+        None => Ok(Vec::new()),
+        Some((&"if", rest)) if !rest.is_empty() => rest.join(" ").split("&&").map(Condition::parse).collect(),
+        Some(_) => Err(format!("Expected if and a condition, not {}", words.join(" "))),
+    }
+}
+
+
+// Tests
+#[cfg(test)]
+use crate::bus::Bus;
+
+#[test]
+fn breakpoints_and_stepping() {
+    let mut motherboard = Motherboard::new();
+    // CALL C010; INC A; JR C000, and at C010: LD (C100),A; RET
+    for (i, byte) in [0xCD, 0x10, 0xC0, 0x3C, 0x18, 0xFA].into_iter().enumerate() {
+        motherboard.write8(0xC000 + i as u16, byte);
+    }
+    for (i, byte) in [0xEA, 0x00, 0xC1, 0xC9].into_iter().enumerate() {
+        motherboard.write8(0xC010 + i as u16, byte);
+    }
+    motherboard.cpu.pc = 0xC000;
+    motherboard.cpu.a = 0;
+    let mut debugger = Debugger::new();
+
+    assert_eq!(debugger.command(&mut motherboard, "break c003 if a == 2").unwrap(), "1: break C003 if A == 2");
+    assert_eq!(debugger.resume(&mut motherboard, None), Stop::Breakpoint(1));
+    assert_eq!((motherboard.cpu.pc, motherboard.cpu.a), (0xC003, 2));
+
+    // The write in the call is watched, then the call is run out and stepped over
+    debugger.command(&mut motherboard, "watch $c100 w").unwrap();
+    let write = Access::Write { address: 0xC100, value: 3 };
+    assert_eq!(debugger.resume(&mut motherboard, None), Stop::Watchpoint(2, write));
+    assert_eq!(motherboard.cpu.pc, 0xC013);
+    assert_eq!(debugger.step_out(&mut motherboard), Stop::Done);
+    assert_eq!(motherboard.cpu.pc, 0xC003);
+    assert!(debugger.command(&mut motherboard, "finish").unwrap().starts_with("Watchpoint 2: wrote 04 to C100"));
+    assert!(debugger.command(&mut motherboard, "delete 2").is_ok());
+    debugger.command(&mut motherboard, "step 3").unwrap();
+    assert_eq!(motherboard.cpu.pc, 0xC000);
+    assert_eq!(debugger.step_over(&mut motherboard), Stop::Done);
+    assert_eq!(motherboard.cpu.pc, 0xC003);
+
+    // Registers and memory can be inspected and changed
+    let text = debugger.command(&mut motherboard, "set hl 0xc0de").unwrap();
+    assert!(text.starts_with("A:05 F:00 B:00 C:13 D:00 E:D8 H:C0 L:DE SP:FFFE PC:C003 [----]"));
+    debugger.command(&mut motherboard, "poke c100 12 34").unwrap();
+    assert_eq!(debugger.command(&mut motherboard, "x c0fe 4").unwrap(), "C0FE: 00 00 12 34");
+    debugger.symbols = Symbols::parse("00:c010 Store").unwrap();
+    assert_eq!(debugger.command(&mut motherboard, "dis c000 2").unwrap(), "C000  CD 10 C0  CALL Store\nC003  3C        INC A");
+    assert_eq!(debugger.command(&mut motherboard, "break Store").unwrap(), "3: break C010");
+    assert_eq!(debugger.command(&mut motherboard, "set q 1").unwrap_err(), "Unknown register q");
+    assert_eq!(debugger.command(&mut motherboard, "break c000 when a").unwrap_err(), "Expected if and a condition, not when a");
+
+    // Buttons stay held until released
+    debugger.command(&mut motherboard, "press start").unwrap();
+    debugger.command(&mut motherboard, "press A").unwrap();
+    assert_eq!(debugger.command(&mut motherboard, "press").unwrap(), "a start");
+    debugger.command(&mut motherboard, "release a").unwrap();
+    assert_eq!(debugger.command(&mut motherboard, "press").unwrap(), "start");
+    assert_eq!(debugger.command(&mut motherboard, "press turbo").unwrap_err(), "Unknown button turbo");
+}
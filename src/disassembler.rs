@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use crate::opcodes::{CB_OPCODES, OPCODES};
+
+/// One decoded instruction
+pub struct Instruction {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    /// Label of the instruction's own address, if the symbols have one
+    pub label: Option<String>,
+    /// Mnemonic with its operands filled in, like `LD A,$12` or `CALL Main`
+    pub text: String,
+    pub length: u8,
+    pub cycles: u8,
+    pub cycles_taken: u8,
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        write!(f, "{:04X}  {:<10}{}", self.address, bytes.join(" "), self.text)
+    }
+}
+
+/// Decodes the instruction at `address`, reading memory through `read`. Addresses in operands
+/// are shown as labels where `symbols` has one, with `rom_bank` the bank mapped at 0x4000-0x7FFF.
+pub fn disassemble(mut read: impl FnMut(u16) -> u8, address: u16, symbols: &Symbols, rom_bank: u16) -> Instruction {
+    let opcode = read(address);
+    let info = if opcode == 0xCB {
+        &CB_OPCODES[read(address.wrapping_add(1)) as usize]
+    } else {
+        &OPCODES[opcode as usize]
+    };
+    let bytes: Vec<u8> = (0..info.length as u16).map(|i| read(address.wrapping_add(i))).collect();
+    let next = address.wrapping_add(info.length as u16);
+    let address_text = |target: u16| match symbols.label(target, rom_bank) {
+        Some(label) => label.to_string(),
+        None => format!("${:04X}", target),
+    };
+
+    // Only one placeholder ever appears in a mnemonic, and CB instructions have none
+    let mnemonic = info.mnemonic;
+    let text = if mnemonic.contains("d16") {
+        mnemonic.replace("d16", &format!("${:04X}", u16::from_le_bytes([bytes[1], bytes[2]])))
+    } else if mnemonic.contains("a16") {
+        mnemonic.replace("a16", &address_text(u16::from_le_bytes([bytes[1], bytes[2]])))
+    } else if mnemonic.contains("d8") {
+        mnemonic.replace("d8", &format!("${:02X}", bytes[1]))
+    } else if mnemonic.contains("a8") {
+        mnemonic.replace("a8", &address_text(0xFF00 | bytes[1] as u16))
+    } else if mnemonic.starts_with("JR") {
+        mnemonic.replace("r8", &address_text(next.wrapping_add(bytes[1] as i8 as u16)))
+    } else if mnemonic.contains("SP+r8") {
+        mnemonic.replace("+r8", &format!("{:+}", bytes[1] as i8))
+    } else if mnemonic.contains("r8") {
+        mnemonic.replace("r8", &format!("{}", bytes[1] as i8))
+    } else {
+        mnemonic.to_string()
+    };
+
+    Instruction {
+        address,
+        label: symbols.label(address, rom_bank).map(String::from),
+        bytes,
+        text,
+        length: info.length,
+        cycles: info.cycles,
+        cycles_taken: info.cycles_taken,
+    }
+}
+
+/// Labels from an RGBDS or no$gmb symbol file. Each line there is `bank:address label`, both
+/// in hex, and comments start with a semicolon.
+pub struct Symbols {
+    // By address, then bank
+    labels: BTreeMap<(u16, u16), String>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self { labels: BTreeMap::new() }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Symbols::parse(&text).map_err(|message| io::Error::new(io::ErrorKind::InvalidData, message))
+    }
+
+    /// Parses the text of a symbol file. Where an address has several labels, the first is kept.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut symbols = Symbols::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap().trim();
+            // Skip blank lines, and section headers in the files of some other assemblers
+            if line.is_empty() || line.starts_with('[') {
+                continue;
+            }
+            let invalid = || format!("line {}: expected bank:address label, found {}", number + 1, line);
+            let (location, label) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let (bank, address) = location.split_once(':').ok_or_else(invalid)?;
+            let bank = u16::from_str_radix(bank, 16).map_err(|_| invalid())?;
+            let address = u16::from_str_radix(address, 16).map_err(|_| invalid())?;
+            symbols.labels.entry((address, bank)).or_insert_with(|| label.trim().to_string());
+        }
+        Ok(symbols)
+    }
+
+    /// The label at `address`, with `rom_bank` the bank mapped at 0x4000-0x7FFF. ROM labels
+    /// must be in the mapped bank; elsewhere, like banked RAM, the first bank with one wins.
+    pub fn label(&self, address: u16, rom_bank: u16) -> Option<&str> {
+        match address {
+            0x0000..=0x3FFF => self.labels.get(&(address, 0)),
+            0x4000..=0x7FFF => self.labels.get(&(address, rom_bank)),
+            _ => self.labels.range((address, 0)..=(address, u16::MAX)).next().map(|(_, label)| label),
+        }.map(String::as_str)
+    }
+
+    /// The address of a label, in whichever bank it is
+    pub fn address(&self, label: &str) -> Option<u16> {
+        self.labels.iter().find(|(_, name)| name.as_str() == label).map(|(&(address, _), _)| address)
+    }
+}
+
+
+// Tests
+#[test]
+fn disassembly() {
+    let symbols = Symbols::parse("; rgblink\n00:0150 Main\n00:0158 Main.loop\n01:4000 Banked\n00:c000 wBuffer ; first\n\n").unwrap();
+    let code = [
+        0x3E, 0x12, // LD A,$12
+        0x21, 0x00, 0xC0, // LD HL,$C000, as immediates aren't labelled
+        0xEA, 0x00, 0xC0, // LD (wBuffer),A
+        0xE0, 0x44, // LDH ($FF44),A
+        0xCB, 0x7E, // BIT 7,(HL)
+        0x20, 0xF2, // JR NZ,Main
+        0xF8, 0xFE, // LD HL,SP-2
+        0xCD, 0x00, 0x40, // CALL Banked
+        0xD3, // ILLEGAL
+    ];
+    let read = |address: u16| code.get(address.wrapping_sub(0x0150) as usize).copied().unwrap_or(0);
+
+    let mut address = 0x0150;
+    let mut lines = Vec::new();
+    for _ in 0..9 {
+        let instruction = disassemble(read, address, &symbols, 1);
+        lines.push(instruction.text);
+        address += instruction.length as u16;
+    }
+    assert_eq!(lines, ["LD A,$12", "LD HL,$C000", "LD (wBuffer),A", "LDH ($FF44),A", "BIT 7,(HL)", "JR NZ,Main",
+                       "LD HL,SP-2", "CALL Banked", "ILLEGAL"]);
+
+    let instruction = disassemble(read, 0x015C, &symbols, 1);
+    assert_eq!((instruction.length, instruction.cycles, instruction.cycles_taken), (2, 8, 12));
+    assert_eq!(instruction.to_string(), "015C  20 F2     JR NZ,Main");
+    assert_eq!(disassemble(read, 0x0150, &symbols, 1).label.as_deref(), Some("Main"));
+    // Another bank at 0x4000 has no label there
+    assert_eq!(disassemble(read, 0x0160, &symbols, 2).text, "CALL $4000");
+
+    assert_eq!(symbols.address("Main.loop"), Some(0x0158));
+    assert_eq!(Symbols::parse("00:0150").err().unwrap(), "line 1: expected bank:address label, found 00:0150");
+}
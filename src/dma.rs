@@ -0,0 +1,169 @@
+use crate::util::{StateError, StateReader, StateWriter};
+
+// Registers
+pub const DMA_ADDRESS: u16 = 0xFF46;
+pub const HDMA1_ADDRESS: u16 = 0xFF51;
+pub const HDMA2_ADDRESS: u16 = 0xFF52;
+pub const HDMA3_ADDRESS: u16 = 0xFF53;
+pub const HDMA4_ADDRESS: u16 = 0xFF54;
+pub const HDMA5_ADDRESS: u16 = 0xFF55;
+
+/// Bytes copied to OAM by one OAM DMA, one per M-cycle
+pub const OAM_DMA_LENGTH: u8 = 0xA0;
+/// VRAM DMA copies in blocks of 16 bytes, each stalling the CPU for 8 M-cycles
+pub const HDMA_BLOCK_SIZE: u16 = 0x10;
+pub const HDMA_BLOCK_CYCLES: u32 = 32;
+
+/// OAM DMA, and on CGB the VRAM DMA in its general-purpose and HBlank forms. This only keeps
+/// track of the transfers; the motherboard moves the bytes, since it owns both ends.
+#[allow(clippy::upper_case_acronyms)]
+pub struct DMA {
+    pub cgb: bool,
+
+    pub oam_register: u8,
+    oam_source: u16,
+    // Next byte of the running OAM DMA, None when idle
+    oam_index: Option<u8>,
+    // A new transfer takes one M-cycle to set up
+    oam_starting: bool,
+
+    hdma_source: u16,
+    hdma_destination: u16,
+    // Blocks of 16 bytes left to copy
+    hdma_remaining: u8,
+    pub hblank_active: bool,
+    general_blocks: u8,
+    /// T-cycles the CPU is held for while VRAM DMA copies
+    pub stall_cycles: u32,
+}
+
+impl DMA {
+    pub fn new() -> Self {
+        Self {
+            cgb: false,
+            oam_register: 0xFF,
+            oam_source: 0,
+            oam_index: None,
+            oam_starting: false,
+            hdma_source: 0,
+            hdma_destination: 0,
+            hdma_remaining: 0,
+            hblank_active: false,
+            general_blocks: 0,
+            stall_cycles: 0,
+        }
+    }
+
+    /// While OAM DMA runs the CPU can only reach HRAM and its own I/O registers
+    pub fn oam_active(&self) -> bool {
+        self.oam_index.is_some() && !self.oam_starting
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            DMA_ADDRESS => self.oam_register,
+            HDMA5_ADDRESS if self.cgb => {
+                // Bit 7 reads 0 while an HBlank transfer is running
+                let remaining = self.hdma_remaining.wrapping_sub(1) & 0x7F;
+                if self.hblank_active { remaining } else { 0x80 | remaining }
+            }
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            DMA_ADDRESS => {
+                self.oam_register = value;
+                // Sources past work RAM read its echo
+                let source = (value as u16) << 8;
+                self.oam_source = if source >= 0xE000 { source - 0x2000 } else { source };
+                self.oam_index = Some(0);
+                self.oam_starting = true;
+            }
+            _ if !self.cgb => {}
+            HDMA1_ADDRESS => self.hdma_source = (self.hdma_source & 0x00FF) | ((value as u16) << 8),
+            HDMA2_ADDRESS => self.hdma_source = (self.hdma_source & 0xFF00) | (value & 0xF0) as u16,
+            HDMA3_ADDRESS => self.hdma_destination = (self.hdma_destination & 0x00FF) | (((value & 0x1F) as u16) << 8),
+            HDMA4_ADDRESS => self.hdma_destination = (self.hdma_destination & 0xFF00) | (value & 0xF0) as u16,
+            HDMA5_ADDRESS => {
+                let blocks = (value & 0x7F) + 1;
+                if self.hblank_active && value & 0x80 == 0 {
+                    // Clearing bit 7 stops a running HBlank transfer
+                    self.hblank_active = false;
+                } else if value & 0x80 != 0 {
+                    self.hdma_remaining = blocks;
+                    self.hblank_active = true;
+                } else {
+                    self.hdma_remaining = blocks;
+                    self.general_blocks = blocks;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Advances OAM DMA by one M-cycle. Returns the source address and OAM offset of the byte
+    /// to copy in it.
+    pub fn oam_step(&mut self) -> Option<(u16, usize)> {
+        if self.oam_starting {
+            self.oam_starting = false;
+            return None;
+        }
+        let index = self.oam_index?;
+        self.oam_index = if index + 1 < OAM_DMA_LENGTH { Some(index + 1) } else { None };
+        Some((self.oam_source + index as u16, index as usize))
+    }
+
+    /// Number of blocks a general-purpose transfer just asked for, to copy all at once
+    pub fn take_general_blocks(&mut self) -> u8 {
+        std::mem::take(&mut self.general_blocks)
+    }
+
+    /// Source and VRAM offset of the next block to copy, moving the transfer on past it
+    pub fn next_block(&mut self) -> Option<(u16, u16)> {
+        if self.hdma_remaining == 0 {
+            self.hblank_active = false;
+            return None;
+        }
+        let block = (self.hdma_source, self.hdma_destination);
+        self.hdma_source = self.hdma_source.wrapping_add(HDMA_BLOCK_SIZE);
+        self.hdma_destination = (self.hdma_destination + HDMA_BLOCK_SIZE) & 0x1FF0;
+        self.hdma_remaining -= 1;
+        if self.hdma_remaining == 0 {
+            self.hblank_active = false;
+        }
+        Some(block)
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.cgb);
+        state.write_u8(self.oam_register);
+        state.write_u16(self.oam_source);
+        // 0xFF for no transfer, past the last index
+        state.write_u8(self.oam_index.unwrap_or(0xFF));
+        state.write_bool(self.oam_starting);
+        state.write_u16(self.hdma_source);
+        state.write_u16(self.hdma_destination);
+        state.write_u8(self.hdma_remaining);
+        state.write_bool(self.hblank_active);
+        state.write_u8(self.general_blocks);
+        state.write_u32(self.stall_cycles);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader, _state_version: u32) -> Result<(), StateError> {
+        self.cgb = state.read_bool()?;
+        self.oam_register = state.read_u8()?;
+        self.oam_source = state.read_u16()?;
+        let index = state.read_u8()?;
+        self.oam_index = if index < OAM_DMA_LENGTH { Some(index) } else { None };
+        self.oam_starting = state.read_bool()?;
+        self.hdma_source = state.read_u16()?;
+        self.hdma_destination = state.read_u16()?;
+        self.hdma_remaining = state.read_u8()?;
+        self.hblank_active = state.read_bool()?;
+        self.general_blocks = state.read_u8()?;
+        self.stall_cycles = state.read_u32()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+use crate::cpu::INTR_HIGHTOLOW;
+use crate::util::{StateError, StateReader, StateWriter};
+
+pub const P1_ADDRESS: u16 = 0xFF00;
+
+// P1 select lines, active low
+const SELECT_DIRECTIONS: u8 = 1 << 4;
+const SELECT_BUTTONS: u8 = 1 << 5;
+
+/// The eight Game Boy buttons. Each is wired to one of the four P1 input lines, in one of the
+/// two rows chosen by the select lines.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::Right, Button::Left, Button::Up, Button::Down, Button::A, Button::B, Button::Select, Button::Start,
+    ];
+
+    /// Parses a button name like `start` or `A`
+    pub fn parse(name: &str) -> Option<Button> {
+        Button::ALL.into_iter().find(|button| format!("{:?}", button).eq_ignore_ascii_case(name))
+    }
+
+    /// (direction row, input line bit)
+    fn line(&self) -> (bool, u8) {
+        match self {
+            Button::Right => (true, 1 << 0),
+            Button::Left => (true, 1 << 1),
+            Button::Up => (true, 1 << 2),
+            Button::Down => (true, 1 << 3),
+            Button::A => (false, 1 << 0),
+            Button::B => (false, 1 << 1),
+            Button::Select => (false, 1 << 2),
+            Button::Start => (false, 1 << 3),
+        }
+    }
+}
+
+/// P1/JOYP. The game pulls a select line low to read that row of buttons on the low nibble,
+/// where a pressed button also reads as 0.
+pub struct Joypad {
+    select: u8,
+    // Pressed buttons per row, 1 = pressed
+    directions: u8,
+    buttons: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Self {
+            select: SELECT_DIRECTIONS | SELECT_BUTTONS,
+            directions: 0,
+            buttons: 0,
+        }
+    }
+
+    /// Low nibble of P1, with the input lines pulled low by pressed buttons in selected rows
+    fn lines(&self) -> u8 {
+        let mut pressed = 0;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            pressed |= self.directions;
+        }
+        if self.select & SELECT_BUTTONS == 0 {
+            pressed |= self.buttons;
+        }
+        !pressed & 0x0F
+    }
+
+    /// Applies a change and returns the joypad interrupt if any input line fell from high to low
+    fn update(&mut self, change: impl FnOnce(&mut Self)) -> u8 {
+        let before = self.lines();
+        change(self);
+        if before & !self.lines() != 0 { INTR_HIGHTOLOW } else { 0 }
+    }
+
+    pub fn press(&mut self, button: Button) -> u8 {
+        self.update(|joypad| match button.line() {
+            (true, bit) => joypad.directions |= bit,
+            (false, bit) => joypad.buttons |= bit,
+        })
+    }
+
+    pub fn release(&mut self, button: Button) -> u8 {
+        self.update(|joypad| match button.line() {
+            (true, bit) => joypad.directions &= !bit,
+            (false, bit) => joypad.buttons &= !bit,
+        })
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        match button.line() {
+            (true, bit) => self.directions & bit != 0,
+            (false, bit) => self.buttons & bit != 0,
+        }
+    }
+
+    pub fn read_register(&self) -> u8 {
+        0xC0 | self.select | self.lines()
+    }
+
+    /// Only the select lines are writable. Selecting a row with a button held pulls its line low.
+    pub fn write_register(&mut self, value: u8) -> u8 {
+        self.update(|joypad| joypad.select = value & (SELECT_DIRECTIONS | SELECT_BUTTONS))
+    }
+
+    /// Buttons held are saved too, so a state taken mid-press replays the same
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.select);
+        state.write_u8(self.directions);
+        state.write_u8(self.buttons);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader, _state_version: u32) -> Result<(), StateError> {
+        self.select = state.read_u8()?;
+        self.directions = state.read_u8()?;
+        self.buttons = state.read_u8()?;
+        Ok(())
+    }
+}
+
+
+// Tests
+#[test]
+fn joypad_rows_and_interrupt() {
+    let mut joypad = Joypad::new();
+    assert_eq!(joypad.read_register(), 0xFF);
+
+    // Nothing selected, so no line falls
+    assert_eq!(joypad.press(Button::Start), 0);
+    assert_eq!(joypad.read_register(), 0xFF);
+
+    // Selecting the button row with Start held pulls line 3 low
+    assert_eq!(joypad.write_register(SELECT_DIRECTIONS), INTR_HIGHTOLOW);
+    assert_eq!(joypad.read_register(), 0xD7);
+    assert_eq!(joypad.press(Button::A), INTR_HIGHTOLOW);
+    assert_eq!(joypad.read_register(), 0xD6);
+    assert_eq!(joypad.press(Button::Right), 0);
+    assert_eq!(joypad.release(Button::A), 0);
+    assert_eq!(joypad.read_register(), 0xD7);
+
+    joypad.write_register(SELECT_BUTTONS);
+    assert_eq!(joypad.read_register(), 0xEE);
+    assert!(joypad.is_pressed(Button::Right));
+}
@@ -0,0 +1,293 @@
+use std::fmt;
+
+/// A parsed JSON value. Objects keep their keys in the order they were written.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// The value of `key`, if this is an object that has it
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    /// The number, if it is a whole one that fits
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Json::Number(number) if number >= 0.0 && number <= u64::MAX as f64 && number.fract() == 0.0 => Some(number as u64),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == Json::Null
+    }
+}
+
+/// Where and why parsing failed
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {} column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+/// Parses a whole JSON document
+pub fn parse(text: &str) -> Result<Json, ParseError> {
+    let mut parser = Parser { text: text.as_bytes(), position: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.position < text.len() {
+        return Err(parser.error("expected the end of the document"));
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> ParseError {
+        let before = &self.text[..self.position.min(self.text.len())];
+        let line_start = before.iter().rposition(|&byte| byte == b'\n').map_or(0, |i| i + 1);
+        ParseError {
+            line: before.iter().filter(|&&byte| byte == b'\n').count() + 1,
+            column: self.position - line_start + 1,
+            message: message.to_string(),
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.position).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.position += 1;
+        }
+    }
+
+    /// Consumes `literal` if the text continues with it
+    fn eat(&mut self, literal: &str) -> bool {
+        if self.text[self.position..].starts_with(literal.as_bytes()) {
+            self.position += literal.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, ParseError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ if self.eat("null") => Ok(Json::Null),
+            _ if self.eat("true") => Ok(Json::Bool(true)),
+            _ if self.eat("false") => Ok(Json::Bool(false)),
+            None => Err(self.error("expected a value, found the end of the document")),
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, ParseError> {
+        self.position += 1;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.eat("}") {
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected a key"));
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            if !self.eat(":") {
+                return Err(self.error("expected ':'"));
+            }
+            members.push((key, self.value()?));
+            self.skip_whitespace();
+            if self.eat("}") {
+                return Ok(Json::Object(members));
+            }
+            if !self.eat(",") {
+                return Err(self.error("expected ',' or '}'"));
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, ParseError> {
+        self.position += 1;
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.eat("]") {
+            return Ok(Json::Array(values));
+        }
+        loop {
+            values.push(self.value()?);
+            self.skip_whitespace();
+            if self.eat("]") {
+                return Ok(Json::Array(values));
+            }
+            if !self.eat(",") {
+                return Err(self.error("expected ',' or ']'"));
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.position += 1;
+        let mut text = String::new();
+        loop {
+            // Copy everything up to the next quote or escape in one go
+            let start = self.position;
+            while !matches!(self.peek(), None | Some(b'"' | b'\\' | 0x00..=0x1F)) {
+                self.position += 1;
+            }
+            // Only ever split at ASCII, so the bytes between are whole characters
+            text += std::str::from_utf8(&self.text[start..self.position]).unwrap();
+            match self.peek() {
+                Some(b'"') => {
+                    self.position += 1;
+                    return Ok(text);
+                }
+                Some(b'\\') => {
+                    self.position += 1;
+                    let escaped = match self.peek() {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{C}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            self.position += 1;
+                            text.push(self.unicode_escape()?);
+                            continue;
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    self.position += 1;
+                    text.push(escaped);
+                }
+                None => return Err(self.error("unterminated string")),
+                Some(_) => return Err(self.error("control character in string")),
+            }
+        }
+    }
+
+    /// Decodes the hex digits after `\u`, pairing up UTF-16 surrogates. Lone surrogates become
+    /// the replacement character.
+    fn unicode_escape(&mut self) -> Result<char, ParseError> {
+        let unit = self.hex4()?;
+        if (0xD800..0xDC00).contains(&unit) && self.text[self.position..].starts_with(b"\\u") {
+            let resume = self.position;
+            self.position += 2;
+            let low = self.hex4()?;
+            if (0xDC00..0xE000).contains(&low) {
+                let code = 0x10000 + ((unit - 0xD800) << 10) + (low - 0xDC00);
+                return Ok(char::from_u32(code).unwrap());
+            }
+            self.position = resume;
+        }
+        Ok(char::from_u32(unit).unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+
+    fn hex4(&mut self) -> Result<u32, ParseError> {
+        let digits = self.text.get(self.position..self.position + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .filter(|digits| digits.bytes().all(|byte| byte.is_ascii_hexdigit()))
+            .ok_or_else(|| self.error("expected four hex digits"))?;
+        self.position += 4;
+        Ok(u32::from_str_radix(digits, 16).unwrap())
+    }
+
+    fn number(&mut self) -> Result<Json, ParseError> {
+        let start = self.position;
+        let digits = |parser: &mut Self| {
+            let first = parser.position;
+            while matches!(parser.peek(), Some(b'0'..=b'9')) {
+                parser.position += 1;
+            }
+            parser.position > first
+        };
+        self.eat("-");
+        // No leading zeros
+        if !self.eat("0") && !digits(self) {
+            return Err(self.error("expected a digit"));
+        }
+        if self.eat(".") && !digits(self) {
+            return Err(self.error("expected a digit after '.'"));
+        }
+        if self.eat("e") || self.eat("E") {
+            let _ = self.eat("+") || self.eat("-");
+            if !digits(self) {
+                return Err(self.error("expected a digit in the exponent"));
+            }
+        }
+        let text = std::str::from_utf8(&self.text[start..self.position]).unwrap();
+        Ok(Json::Number(text.parse().unwrap()))
+    }
+}
+
+
+// Tests
+#[test]
+fn parsing() {
+    let value = parse(r#" {"name": "00 0000", "cycles": [[49152, 0, "r-m"], null], "ok": true,
+        "text": "tab\t\"quoted\" é😀", "numbers": [-1.5e2, 0, 65535]} "#).unwrap();
+    assert_eq!(value.get("name").and_then(Json::as_str), Some("00 0000"));
+    let cycles = value.get("cycles").and_then(Json::as_array).unwrap();
+    assert_eq!(cycles[0].as_array().unwrap()[0].as_u64(), Some(49152));
+    assert!(cycles[1].is_null());
+    assert_eq!(value.get("ok"), Some(&Json::Bool(true)));
+    assert_eq!(value.get("text").and_then(Json::as_str), Some("tab\t\"quoted\" \u{e9}\u{1F600}"));
+    let numbers = value.get("numbers").and_then(Json::as_array).unwrap();
+    assert_eq!(numbers[0], Json::Number(-150.0));
+    assert_eq!((numbers[0].as_u64(), numbers[2].as_u64()), (None, Some(65535)));
+    assert_eq!(parse("[]"), Ok(Json::Array(Vec::new())));
+
+    let error = |text: &str| parse(text).unwrap_err().to_string();
+    assert_eq!(error("{\"a\": [1, 2,]}"), "line 1 column 13: expected a value");
+    assert_eq!(error("[1,\n 2 3]"), "line 2 column 4: expected ',' or ']'");
+    assert_eq!(error("\"open"), "line 1 column 6: unterminated string");
+    assert_eq!(error("01"), "line 1 column 2: expected the end of the document");
+    assert_eq!(error(""), "line 1 column 1: expected a value, found the end of the document");
+}
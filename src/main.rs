@@ -1,17 +1,509 @@
 mod cartridge;
 mod util;
 mod cpu;
+mod opcodes;
 mod motherboard;
 mod system;
 mod bus;
-// use cartridge::cartridge::Cartridge;
+mod memory;
+mod ppu;
+mod apu;
+mod timer;
+mod joypad;
+mod dma;
+mod bootrom;
+mod serial;
+mod screenshot;
+mod debugger;
+mod disassembler;
+mod trace;
+mod json;
+mod single_step;
 
 extern crate std;
 
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use crate::bootrom::BootROM;
+use crate::cartridge::cartridge::load_cartridge;
+use crate::cartridge::clock::{wall_time, ManualClock, WallClock, CYCLES_PER_SECOND};
+use crate::cartridge::header::CartridgeHeader;
+use crate::debugger::Debugger;
+use crate::disassembler::{disassemble, Symbols};
 use crate::motherboard::Motherboard;
-use crate::cartridge::cartridge::Cartridge;
+use crate::ppu::ppu::{Renderer, CYCLES_PER_FRAME, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::serial::link::{SocketLink, StdoutLink};
+use crate::single_step::Report;
+use crate::system::Model;
+use crate::trace::Tracer;
+
+const USAGE: &str = "Usage: RustyBoy [OPTIONS] ROM
+       RustyBoy --cpu-tests DIR
+       RustyBoy --info ROM
+
+Options:
+  --boot-rom PATH     Run a DMG or CGB boot ROM before the cartridge
+  --model MODEL       dmg, mgb, sgb, cgb or auto (default), which follows the boot ROM
+                      or the cartridge header
+  --save-dir DIR      Keep battery saves in DIR instead of next to the ROM
+  --rtc CLOCK         What the cartridge clock follows: emulated (default) time, the host's
+                      wall clock, or nothing, staying frozen at the time it started
+  --frames N          Exit after N frames
+  --headless          Don't draw, and run as fast as possible
+  --screenshot PATH   Save the last frame as a PNG on exit
+  --load-state PATH   Start from a save state
+  --save-state PATH   Save the state of the machine on exit
+  --serial-stdout     Print bytes sent over the link port
+  --link-listen ADDR  Wait for another emulator to connect a link cable, on host:port or
+                      unix:PATH
+  --link-connect ADDR Connect a link cable to another emulator listening on ADDR
+  --renderer NAME     scanline (default) or fifo
+  --debug             Start in the debugger instead of running, type help there for commands
+  --symbols PATH      Label addresses from an RGBDS or no$gmb .sym file in disassembly
+  --disassemble RANGE Print the disassembly of START-END (hex) after reset, and exit
+  --trace PATH        Log each instruction run to PATH, or - for standard output, in
+                      Gameboy Doctor's format
+  --trace-pc RANGE    Only log instructions at START-END (hex)
+  --trace-frames RANGE
+                      Only log instructions in frames FIRST-LAST, counting from 0
+  --doctor            Make LY always read 0x90, as Gameboy Doctor's logs expect
+  --cpu-tests DIR     Run the SM83 single-step JSON tests in DIR, report each opcode and exit
+  --info              Print the decoded cartridge header and any problems with it, and exit
+  -h, --help          Print this help";
+
+/// What drives the cartridge's real-time clock
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RtcClock {
+    Emulated,
+    Wall,
+    Frozen,
+}
+
+/// Everything the command line can ask for
+#[derive(Debug, PartialEq)]
+struct Options {
+    rom: String,
+    boot_rom: Option<String>,
+    // None picks the model automatically
+    model: Option<Model>,
+    save_dir: Option<PathBuf>,
+    rtc: RtcClock,
+    frames: Option<u64>,
+    headless: bool,
+    screenshot: Option<PathBuf>,
+    load_state: Option<PathBuf>,
+    save_state: Option<PathBuf>,
+    serial_stdout: bool,
+    link_listen: Option<String>,
+    link_connect: Option<String>,
+    renderer: Renderer,
+    debug: bool,
+    symbols: Option<PathBuf>,
+    disassemble: Option<(u16, u16)>,
+    trace: Option<PathBuf>,
+    trace_pc: Option<(u16, u16)>,
+    trace_frames: Option<(u64, u64)>,
+    doctor: bool,
+    cpu_tests: Option<PathBuf>,
+    info: bool,
+}
+
+/// Parses the arguments after the program name. Err holds the message to print, which for
+/// --help is the usage itself.
+fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Options, String> {
+    let mut args = args.into_iter();
+    let mut rom = None;
+    let mut options = Options {
+        rom: String::new(),
+        boot_rom: None,
+        model: None,
+        save_dir: None,
+        rtc: RtcClock::Emulated,
+        frames: None,
+        headless: false,
+        screenshot: None,
+        load_state: None,
+        save_state: None,
+        serial_stdout: false,
+        link_listen: None,
+        link_connect: None,
+        renderer: Renderer::Scanline,
+        debug: false,
+        symbols: None,
+        disassemble: None,
+        trace: None,
+        trace_pc: None,
+        trace_frames: None,
+        doctor: false,
+        cpu_tests: None,
+        info: false,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            "--boot-rom" => options.boot_rom = Some(value(&arg)?),
+            "--model" => options.model = match value(&arg)?.to_lowercase().as_str() {
+                "auto" => None,
+                "dmg" => Some(Model::DMG),
+                "mgb" => Some(Model::MGB),
+                "sgb" => Some(Model::SGB),
+                "cgb" => Some(Model::CGB),
+                other => return Err(format!("Unknown model {}", other)),
+            },
+            "--save-dir" => options.save_dir = Some(PathBuf::from(value(&arg)?)),
+            "--rtc" => options.rtc = match value(&arg)?.to_lowercase().as_str() {
+                "emulated" => RtcClock::Emulated,
+                "wall" => RtcClock::Wall,
+                "frozen" => RtcClock::Frozen,
+                other => return Err(format!("Unknown clock {}", other)),
+            },
+            "--frames" => {
+                let frames = value(&arg)?;
+                options.frames = Some(frames.parse().map_err(|_| format!("Invalid frame count {}", frames))?);
+            }
+            "--headless" => options.headless = true,
+            "--screenshot" => options.screenshot = Some(PathBuf::from(value(&arg)?)),
+            "--load-state" => options.load_state = Some(PathBuf::from(value(&arg)?)),
+            "--save-state" => options.save_state = Some(PathBuf::from(value(&arg)?)),
+            "--serial-stdout" => options.serial_stdout = true,
+            "--link-listen" => options.link_listen = Some(value(&arg)?),
+            "--link-connect" => options.link_connect = Some(value(&arg)?),
+            "--renderer" => options.renderer = match value(&arg)?.to_lowercase().as_str() {
+                "scanline" => Renderer::Scanline,
+                "fifo" => Renderer::PixelFIFO,
+                other => return Err(format!("Unknown renderer {}", other)),
+            },
+            "--debug" => options.debug = true,
+            "--symbols" => options.symbols = Some(PathBuf::from(value(&arg)?)),
+            "--disassemble" => options.disassemble = Some(parse_range(&value(&arg)?, |n| u16::from_str_radix(n, 16).ok())?),
+            "--trace" => options.trace = Some(PathBuf::from(value(&arg)?)),
+            "--trace-pc" => options.trace_pc = Some(parse_range(&value(&arg)?, |n| u16::from_str_radix(n, 16).ok())?),
+            "--trace-frames" => options.trace_frames = Some(parse_range(&value(&arg)?, |n| n.parse().ok())?),
+            "--doctor" => options.doctor = true,
+            "--cpu-tests" => options.cpu_tests = Some(PathBuf::from(value(&arg)?)),
+            "--info" => options.info = true,
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+            _ if rom.is_none() => rom = Some(arg),
+            _ => return Err(format!("Unexpected argument {}", arg)),
+        }
+    }
+    let cables = options.serial_stdout as u8 + options.link_listen.is_some() as u8 + options.link_connect.is_some() as u8;
+    if cables > 1 {
+        return Err("Only one of --serial-stdout, --link-listen and --link-connect can be given".to_string());
+    }
+    // The CPU tests bring their own memory
+    options.rom = match rom {
+        Some(rom) => rom,
+        None if options.cpu_tests.is_some() => String::new(),
+        None => return Err("No ROM given".to_string()),
+    };
+    Ok(options)
+}
+
+/// Parses an inclusive range like `START-END`, where an end before the start means just the start
+fn parse_range<T: Ord + Copy>(range: &str, parse: impl Fn(&str) -> Option<T>) -> Result<(T, T), String> {
+    let invalid = || format!("Invalid range {}", range);
+    let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+    let start = parse(start).ok_or_else(invalid)?;
+    let end = parse(end).ok_or_else(invalid)?;
+    Ok((start, end.max(start)))
+}
+
+/// Draws a frame on the terminal, two pixels per character cell, with 24-bit colour
+fn draw_frame(out: &mut impl Write, framebuffer: &[u32]) -> std::io::Result<()> {
+    let mut text = String::from("\x1b[H");
+    for y in (0..SCREEN_HEIGHT).step_by(2) {
+        for x in 0..SCREEN_WIDTH {
+            let top = framebuffer[y * SCREEN_WIDTH + x];
+            let bottom = framebuffer[(y + 1) * SCREEN_WIDTH + x];
+            text += &format!("\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}",
+                top >> 16, (top >> 8) & 0xFF, top & 0xFF, bottom >> 16, (bottom >> 8) & 0xFF, bottom & 0xFF);
+        }
+        text += "\x1b[0m\n";
+    }
+    out.write_all(text.as_bytes())?;
+    out.flush()
+}
+
+/// Prints what the header of a ROM says and what is wrong with it. Returns the exit code, which
+/// is 1 if anything is.
+fn print_info(filename: &str) -> i32 {
+    let rom = match std::fs::read(filename) {
+        Ok(rom) => rom,
+        Err(error) => {
+            eprintln!("Failed to load {}: {}", filename, error);
+            return 1;
+        }
+    };
+    let header = match CartridgeHeader::parse(&rom) {
+        Ok(header) => header,
+        Err(error) => {
+            eprintln!("Failed to read the header of {}: {}", filename, error);
+            return 1;
+        }
+    };
+    println!("{}", header);
+    let issues = header.validate(&rom);
+    for issue in &issues {
+        println!("Problem:         {}", issue);
+    }
+    if issues.is_empty() { 0 } else { 1 }
+}
+
+/// Prints the instructions that start from `start` up to `end`, with a line for each label
+fn print_disassembly(motherboard: &mut Motherboard, symbols: &Symbols, start: u16, end: u16) {
+    let rom_bank = motherboard.rom_bank();
+    let mut address = start as u32;
+    while address <= end as u32 {
+        let instruction = disassemble(|address| motherboard.peek(address), address as u16, symbols, rom_bank);
+        if let Some(label) = &instruction.label {
+            println!("{}:", label);
+        }
+        // Cycles taken and not taken for branches, like 24/12
+        let cycles = match instruction.cycles_taken {
+            taken if taken != instruction.cycles => format!("{}/{}", taken, instruction.cycles),
+            _ => instruction.cycles.to_string(),
+        };
+        println!("{:<36}; {}", instruction.to_string(), cycles);
+        address += instruction.length as u32;
+    }
+}
+
+/// Set by Ctrl-C or SIGTERM, so the emulator still saves and flushes on the way out
+static QUIT: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+fn handle_quit_signals() {
+    use std::ffi::c_int;
+    const SIGINT: c_int = 2;
+    const SIGTERM: c_int = 15;
+    extern "C" fn request_quit(_signal: c_int) {
+        QUIT.store(true, Ordering::Relaxed);
+    }
+    extern "C" {
+        // Returns the previous handler, which is never needed back
+        fn signal(signal: c_int, handler: extern "C" fn(c_int)) -> usize;
+    }
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        signal(SIGINT, request_quit);
+        signal(SIGTERM, request_quit);
+    }
+}
+
+// Elsewhere Ctrl-C still ends the process at once, so runs that should save need --frames
+#[cfg(not(unix))]
+fn handle_quit_signals() {}
+
+/// Runs frames until the frame limit, if there is one, or until asked to quit
+fn run(motherboard: &mut Motherboard, options: &Options) {
+    // Frames are paced to the real Game Boy's 59.7 per second unless headless
+    let frame_time = Duration::from_secs_f64(CYCLES_PER_FRAME as f64 / CYCLES_PER_SECOND as f64);
+    let mut stdout = std::io::stdout();
+    if !options.headless {
+        let _ = write!(stdout, "\x1b[2J\x1b[?25l");
+    }
+    let mut next_frame = Instant::now();
+    let mut frames = 0;
+    while options.frames.is_none_or(|limit| frames < limit) && !QUIT.load(Ordering::Relaxed) {
+        motherboard.run_frame();
+        frames += 1;
+        if options.headless {
+            continue;
+        }
+        if draw_frame(&mut stdout, &motherboard.ppu.framebuffer).is_err() {
+            break;
+        }
+        next_frame += frame_time;
+        let now = Instant::now();
+        if next_frame > now {
+            std::thread::sleep(next_frame - now);
+        } else {
+            next_frame = now;
+        }
+    }
+    if !options.headless {
+        let _ = write!(stdout, "\x1b[?25h");
+    }
+}
 
 fn main() {
-    let motherboard = Motherboard::new();
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) if message == USAGE => {
+            println!("{}", message);
+            return;
+        }
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(2);
+        }
+    };
+
+    if let Some(directory) = &options.cpu_tests {
+        match Report::run_directory(directory) {
+            Ok(report) => {
+                println!("{}", report);
+                std::process::exit(if report.all_passed() { 0 } else { 1 });
+            }
+            Err(error) => {
+                eprintln!("Failed to run CPU tests {}: {}", directory.display(), error);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if options.info {
+        std::process::exit(print_info(&options.rom));
+    }
+
+    let mut motherboard = Motherboard::with_renderer(options.renderer);
+    if let Some(filename) = &options.boot_rom {
+        match BootROM::load(filename) {
+            Ok(boot_rom) => motherboard.insert_boot_rom(boot_rom),
+            Err(error) => {
+                eprintln!("Failed to load boot ROM {}: {}", filename, error);
+                std::process::exit(1);
+            }
+        }
+    }
+    match load_cartridge(&options.rom, options.save_dir.as_deref()) {
+        Ok(cartridge) => motherboard.insert_cartridge(cartridge),
+        Err(error) => {
+            eprintln!("Failed to load {}: {}", options.rom, error);
+            std::process::exit(1);
+        }
+    }
+    match options.rtc {
+        RtcClock::Emulated => {}
+        RtcClock::Wall => motherboard.set_rtc_clock(Box::new(WallClock)),
+        RtcClock::Frozen => motherboard.set_rtc_clock(Box::new(ManualClock::new(wall_time()))),
+    }
+    if let Some(model) = options.model {
+        motherboard.set_model(model);
+    }
+    if options.serial_stdout {
+        motherboard.set_link_cable(Box::new(StdoutLink));
+    }
+    if let Some(address) = &options.link_listen {
+        eprintln!("Waiting for the other emulator on {}", address);
+    }
+    let socket = match (&options.link_listen, &options.link_connect) {
+        (Some(address), _) => Some((address, SocketLink::listen(address))),
+        (_, Some(address)) => Some((address, SocketLink::connect(address))),
+        _ => None,
+    };
+    if let Some((address, socket)) = socket {
+        match socket {
+            Ok(socket) => motherboard.set_link_cable(Box::new(socket)),
+            Err(error) => {
+                eprintln!("Failed to connect link cable on {}: {}", address, error);
+                std::process::exit(1);
+            }
+        }
+    }
+    if options.doctor {
+        motherboard.ppu.fixed_ly = Some(0x90);
+    }
+    if let Some(path) = &options.trace {
+        let mut tracer = Tracer::create(path).unwrap_or_else(|error| {
+            eprintln!("Failed to create trace {}: {}", path.display(), error);
+            std::process::exit(1);
+        });
+        tracer.pc_range = options.trace_pc;
+        tracer.frames = options.trace_frames;
+        motherboard.cpu.tracer = Some(tracer);
+    }
+    if let Some(path) = &options.load_state {
+        let result = std::fs::read(path).map_err(|error| error.to_string())
+            .and_then(|data| motherboard.load_state(&data).map_err(|error| error.to_string()));
+        if let Err(error) = result {
+            eprintln!("Failed to load state {}: {}", path.display(), error);
+            std::process::exit(1);
+        }
+    }
+
+    let symbols = match &options.symbols {
+        Some(path) => Symbols::load(path).unwrap_or_else(|error| {
+            eprintln!("Failed to load symbols {}: {}", path.display(), error);
+            std::process::exit(1);
+        }),
+        None => Symbols::new(),
+    };
+    if let Some((start, end)) = options.disassemble {
+        print_disassembly(&mut motherboard, &symbols, start, end);
+        return;
+    }
+
+    if options.debug {
+        let mut debugger = Debugger::new();
+        debugger.symbols = symbols;
+        if let Err(error) = debugger::repl(&mut motherboard, &mut debugger, std::io::stdin().lock(), std::io::stdout()) {
+            eprintln!("Debugger failed: {}", error);
+        }
+    } else {
+        handle_quit_signals();
+        run(&mut motherboard, &options);
+    }
+
+    if let Some(path) = &options.screenshot {
+        if let Err(error) = screenshot::save_png(path, &motherboard.ppu.framebuffer, SCREEN_WIDTH, SCREEN_HEIGHT) {
+            eprintln!("Failed to save screenshot {}: {}", path.display(), error);
+        }
+    }
+    if let Some(path) = &options.save_state {
+        if let Err(error) = std::fs::write(path, motherboard.save_state()) {
+            eprintln!("Failed to save state {}: {}", path.display(), error);
+        }
+    }
+    motherboard.stop();
+}
+
+
+// Tests
+#[test]
+fn parse_options() {
+    let args = |line: &str| parse_args(line.split_whitespace().map(String::from));
+
+    let options = args("game.gb").unwrap();
+    assert_eq!((options.rom.as_str(), options.model, options.headless), ("game.gb", None, false));
+
+    let options = args("--headless --frames 600 --model CGB --renderer fifo --screenshot out.png game.gbc").unwrap();
+    assert_eq!(options.frames, Some(600));
+    assert_eq!(options.model, Some(Model::CGB));
+    assert_eq!(options.renderer, Renderer::PixelFIFO);
+    assert_eq!(options.screenshot, Some(PathBuf::from("out.png")));
+    assert!(options.headless);
+
+    let options = args("--load-state before.state --save-state after.state game.gb").unwrap();
+    assert_eq!(options.load_state, Some(PathBuf::from("before.state")));
+    assert_eq!(options.save_state, Some(PathBuf::from("after.state")));
+    assert!(args("--debug game.gb").unwrap().debug);
+    assert!(args("--info game.gb").unwrap().info);
+    assert_eq!(args("--rtc wall game.gb").unwrap().rtc, RtcClock::Wall);
+    assert_eq!(args("--rtc Frozen game.gb").unwrap().rtc, RtcClock::Frozen);
+    assert_eq!(args("--rtc host game.gb").unwrap_err(), "Unknown clock host");
+    assert_eq!(args("--disassemble 150-1ff game.gb").unwrap().disassemble, Some((0x0150, 0x01FF)));
+    assert_eq!(args("--disassemble 150 game.gb").unwrap_err(), "Invalid range 150");
+
+    let options = args("--trace trace.log --trace-pc 100-7fff --trace-frames 10-20 --doctor game.gb").unwrap();
+    assert_eq!(options.trace, Some(PathBuf::from("trace.log")));
+    assert_eq!(options.trace_pc, Some((0x0100, 0x7FFF)));
+    assert_eq!(options.trace_frames, Some((10, 20)));
+    assert!(options.doctor);
+    assert_eq!(args("--trace-frames a-b game.gb").unwrap_err(), "Invalid range a-b");
+    assert_eq!(args("--cpu-tests sm83/v1").unwrap().cpu_tests, Some(PathBuf::from("sm83/v1")));
+    assert_eq!(args("--link-listen 127.0.0.1:5000 game.gb").unwrap().link_listen.as_deref(), Some("127.0.0.1:5000"));
+    assert_eq!(args("--link-connect unix:/tmp/link game.gb").unwrap().link_connect.as_deref(), Some("unix:/tmp/link"));
+    assert_eq!(args("--serial-stdout --link-connect localhost:5000 game.gb").unwrap_err(),
+               "Only one of --serial-stdout, --link-listen and --link-connect can be given");
 
+    assert_eq!(args("--frames").unwrap_err(), "--frames needs a value");
+    assert_eq!(args("--frames ten game.gb").unwrap_err(), "Invalid frame count ten");
+    assert_eq!(args("--turbo game.gb").unwrap_err(), "Unknown option --turbo");
+    assert_eq!(args("--headless").unwrap_err(), "No ROM given");
 }
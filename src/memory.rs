@@ -0,0 +1,61 @@
+use crate::util::{StateError, StateReader, StateWriter};
+
+pub const SVBK_ADDRESS: u16 = 0xFF70;
+
+/// Work RAM comes in banks of 4 KiB. The first is always at 0xC000; 0xD000 shows bank 1 on
+/// DMG, and on CGB whichever of banks 1-7 SVBK selects.
+pub const WRAM_BANK_SIZE: usize = 0x1000;
+
+/// Memory internal to the Game Boy itself, as opposed to memory on the cartridge. Video RAM and
+/// OAM belong to the PPU.
+pub struct InternalRAM {
+    pub wram: [u8; 8 * WRAM_BANK_SIZE],
+    pub wram_bank: u8,
+    pub io_ports: [u8; 0x80],
+    pub hram: [u8; 0x7F],
+}
+
+impl InternalRAM {
+    pub fn new() -> Self {
+        Self {
+            wram: [0; 8 * WRAM_BANK_SIZE],
+            wram_bank: 1,
+            io_ports: [0; 0x80],
+            hram: [0; 0x7F],
+        }
+    }
+
+    /// Offset into `wram` of an access to work RAM at 0xC000-0xDFFF or its echo
+    pub fn wram_address(&self, address: u16) -> usize {
+        let offset = (address as usize - 0xC000) % (2 * WRAM_BANK_SIZE);
+        if offset < WRAM_BANK_SIZE {
+            offset
+        } else {
+            self.wram_bank as usize * WRAM_BANK_SIZE + offset - WRAM_BANK_SIZE
+        }
+    }
+
+    pub fn read_svbk(&self) -> u8 {
+        0xF8 | self.wram_bank
+    }
+
+    /// Selecting bank 0 selects bank 1
+    pub fn write_svbk(&mut self, value: u8) {
+        self.wram_bank = (value & 0b111).max(1);
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.wram);
+        state.write_u8(self.wram_bank);
+        state.write_bytes(&self.io_ports);
+        state.write_bytes(&self.hram);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader, _state_version: u32) -> Result<(), StateError> {
+        state.read_into(&mut self.wram)?;
+        self.write_svbk(state.read_u8()?);
+        state.read_into(&mut self.io_ports)?;
+        state.read_into(&mut self.hram)?;
+        Ok(())
+    }
+}
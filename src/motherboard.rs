@@ -1,38 +1,643 @@
 pub(crate) use crate::bus::Bus;
-use crate::bus::BusMut;
-use crate::cpu::CPU;
+use crate::apu::apu::{APU, DEFAULT_SAMPLE_RATE, NR52_ADDRESS};
+use crate::bootrom::BootROM;
+use crate::bus::{Access, BusMut, ObservedBus};
+use crate::cartridge::base_mbc::MemoryBankController;
+use crate::cartridge::clock::ClockSource;
+use crate::cpu::{CPU, IE_ADDRESS, IF_ADDRESS, INTR_VBLANK};
+use crate::joypad::{Button, Joypad};
+use crate::memory::InternalRAM;
+use crate::dma::{DMA, HDMA_BLOCK_CYCLES, HDMA_BLOCK_SIZE};
+use crate::ppu::palette::{COMPATIBILITY_BG, COMPATIBILITY_OBJ};
+use crate::ppu::ppu::{ColorMode, PPUMode, Renderer, PPU, BGP_ADDRESS, CYCLES_PER_FRAME, LCDC_ADDRESS};
+use crate::serial::link::LinkCable;
+use crate::serial::serial::Serial;
+use crate::system::{Model, SpeedSwitch};
+use crate::timer::{Timer, DIV_ADDRESS};
+use crate::util::{StateError, StateReader, StateWriter, STATE_MAGIC, STATE_VERSION};
 
 pub struct Motherboard {
+    pub model: Model,
     pub cpu: CPU,
-    memory: Vec<u8>,
+    cartridge: Option<Box<dyn MemoryBankController>>,
+    boot_rom: Option<BootROM>,
+    ram: InternalRAM,
+    pub ppu: PPU,
+    pub apu: APU,
+    pub timer: Timer,
+    pub joypad: Joypad,
+    pub serial: Serial,
+    pub dma: DMA,
+    pub speed: SpeedSwitch,
 }
 
 impl Motherboard {
+    #[cfg(test)]
     pub fn new() -> Self {
-        Self {
+        Motherboard::with_renderer(Renderer::Scanline)
+    }
+
+    /// Builds a motherboard whose PPU draws with the given renderer
+    pub fn with_renderer(renderer: Renderer) -> Self {
+        let mut motherboard = Self {
+            model: Model::DMG,
             cpu: CPU::new(),
-            memory: vec![0; 0x10000],
+            cartridge: None,
+            boot_rom: None,
+            ram: InternalRAM::new(),
+            ppu: PPU::with_renderer(renderer),
+            apu: APU::new(DEFAULT_SAMPLE_RATE),
+            timer: Timer::new(),
+            joypad: Joypad::new(),
+            serial: Serial::new(),
+            dma: DMA::new(),
+            speed: SpeedSwitch::new(),
+        };
+        motherboard.reset();
+        motherboard
+    }
+
+    /// Without a boot ROM to decide, a cartridge that supports CGB mode makes this a CGB
+    pub fn insert_cartridge(&mut self, cartridge: Box<dyn MemoryBankController>) {
+        if self.boot_rom.is_none() && cartridge.base().cgb_mode {
+            self.model = Model::CGB;
+        }
+        self.cartridge = Some(cartridge);
+        self.reset();
+    }
+
+    /// Makes this a different model from the one the boot ROM or cartridge picked, and resets
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+        self.reset();
+    }
+
+    /// Runs the given boot ROM at the next reset instead of skipping to the cartridge
+    pub fn insert_boot_rom(&mut self, boot_rom: BootROM) {
+        self.model = boot_rom.model;
+        self.boot_rom = Some(boot_rom);
+        self.reset();
+    }
+
+    /// Powers the machine off and on again. With a boot ROM everything starts out cleared and
+    /// the boot ROM runs from 0x0000. Without one, the machine starts at 0x0100 in the state the
+    /// model's boot ROM would have left it in.
+    ///
+    /// A CGB starts in CGB mode. Without a boot ROM to leave it for DMG games, they go straight
+    /// to compatibility mode with the default compatibility palettes.
+    pub fn reset(&mut self) {
+        // Debugging aids stay set up across a reset
+        let tracer = self.cpu.tracer.take();
+        self.cpu = CPU::new();
+        self.cpu.tracer = tracer;
+        self.ram = InternalRAM::new();
+        let fixed_ly = self.ppu.fixed_ly;
+        self.ppu = PPU::with_renderer(self.ppu.renderer);
+        self.ppu.fixed_ly = fixed_ly;
+        self.apu = APU::new(self.apu.sample_rate());
+        self.timer = Timer::new();
+        self.joypad = Joypad::new();
+        self.serial.reset();
+        self.dma = DMA::new();
+        self.speed = SpeedSwitch::new();
+
+        let cgb_cartridge = self.cartridge.as_ref().is_some_and(|cartridge| cartridge.base().cgb_mode);
+        self.ppu.color_mode = match self.model {
+            Model::CGB if self.boot_rom.is_some() || cgb_cartridge => ColorMode::CGB,
+            Model::CGB => ColorMode::Compatibility,
+            _ => ColorMode::DMG,
+        };
+        self.dma.cgb = self.ppu.color_mode == ColorMode::CGB;
+        self.serial.cgb = self.ppu.color_mode == ColorMode::CGB;
+        if self.ppu.color_mode == ColorMode::Compatibility {
+            self.ppu.bg_palettes.set_palette(0, COMPATIBILITY_BG);
+            self.ppu.obj_palettes.set_palette(0, COMPATIBILITY_OBJ);
+            self.ppu.obj_palettes.set_palette(1, COMPATIBILITY_OBJ);
+        }
+
+        if let Some(boot_rom) = &mut self.boot_rom {
+            boot_rom.mapped = true;
+            self.cpu.reset();
+            self.cpu.sp = 0x0000;
+            self.cpu.pc = 0x0000;
+            self.timer.counter = 0;
+            self.ppu.write_register(LCDC_ADDRESS, 0);
+            self.ppu.write_register(BGP_ADDRESS, 0);
+            self.apu.write_register(NR52_ADDRESS, 0);
+        } else {
+            let header_checksum = self.cartridge.as_ref()
+                .and_then(|cartridge| cartridge.base().rom_banks.get(0x014D).copied())
+                .unwrap_or(0);
+            self.cpu.set_post_boot_state(self.model, header_checksum);
+            self.cpu.set_interrupt_flag(INTR_VBLANK);
+            self.timer.set_post_boot_state(self.model);
+        }
+    }
+
+    /// Drives the cartridge real-time clock from the given time source instead of emulated time
+    pub fn set_rtc_clock(&mut self, clock: Box<dyn ClockSource>) {
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.set_rtc_clock(clock);
+        }
+    }
+
+    /// Plugs something into the link port, in place of whatever was there
+    pub fn set_link_cable(&mut self, link: Box<dyn LinkCable>) {
+        self.serial.link = link;
+    }
+
+    /// Holds a button down until it is released
+    pub fn press(&mut self, button: Button) {
+        let interrupts = self.joypad.press(button);
+        self.cpu.set_interrupt_flag(interrupts);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        let interrupts = self.joypad.release(button);
+        self.cpu.set_interrupt_flag(interrupts);
+    }
+
+    /// Shuts the machine down, letting the cartridge write out what its battery keeps and the
+    /// tracer what it has buffered
+    pub fn stop(&mut self) {
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.stop();
+        }
+        if let Some(tracer) = &mut self.cpu.tracer {
+            if let Err(error) = tracer.flush() {
+                eprintln!("Failed to write trace: {}", error);
+            }
+        }
+    }
+
+    /// Splits the motherboard into the CPU and a bus over everything else
+    fn split(&mut self) -> (&mut CPU, BusMut<'_>) {
+        let bus = BusMut {
+            cartridge: &mut self.cartridge,
+            boot_rom: &mut self.boot_rom,
+            ram: &mut self.ram,
+            ppu: &mut self.ppu,
+            apu: &mut self.apu,
+            timer: &mut self.timer,
+            joypad: &mut self.joypad,
+            serial: &mut self.serial,
+            dma: &mut self.dma,
+            speed: &mut self.speed,
+            interrupts: 0,
+        };
+        (&mut self.cpu, bus)
+    }
+
+    /// Runs the CPU for one instruction, and the rest of the machine for as long as it took.
+    /// While VRAM DMA holds the CPU, only the rest of the machine runs. Returns the CPU cycles
+    /// taken, which in double speed are half as long as the rest of the machine's.
+    pub fn step(&mut self) -> u32 {
+        self.step_observed(|_| {})
+    }
+
+    /// Like `step`, telling `observer` about every access the CPU makes on the bus. IF and IE
+    /// live in the CPU, so accesses to them aren't seen.
+    pub fn step_observed(&mut self, observer: impl FnMut(Access)) -> u32 {
+        let (cycles, interrupts) = if self.dma.stall_cycles > 0 {
+            (std::mem::take(&mut self.dma.stall_cycles), 0)
+        } else {
+            let (cpu, bus) = self.split();
+            let mut bus = ObservedBus { bus, observer };
+            let cycles = cpu.step(&mut bus);
+            (cycles, bus.bus.interrupts)
+        };
+        // STOP with a speed switch prepared switches speed and carries on, resetting DIV. Without
+        // one it carries on too, as the low-power stop mode isn't emulated.
+        if self.cpu.stopped {
+            self.cpu.stopped = false;
+            if self.speed.switch() {
+                self.timer.write_register(DIV_ADDRESS, 0);
+            }
+        }
+        let dots = self.dots(cycles);
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.tick(dots);
+        }
+        self.run_oam_dma(cycles);
+        self.apu.tick(dots);
+        let was_hblank = self.ppu.mode == PPUMode::HBlank;
+        let video_interrupts = self.ppu.tick(dots);
+        if let Some(tracer) = &mut self.cpu.tracer {
+            if video_interrupts & INTR_VBLANK != 0 {
+                tracer.frame += 1;
+            }
+        }
+        let interrupts = interrupts | self.timer.tick(cycles) | self.serial.tick(cycles) | video_interrupts;
+        self.cpu.set_interrupt_flag(interrupts);
+
+        // General-purpose DMA copies everything at once, HBlank DMA one block per HBlank
+        let blocks = self.dma.take_general_blocks();
+        for _ in 0..blocks {
+            self.copy_vram_dma_block();
+        }
+        if self.dma.hblank_active && !was_hblank && self.ppu.mode == PPUMode::HBlank && self.ppu.lcd_enabled() {
+            self.copy_vram_dma_block();
+        }
+        cycles
+    }
+
+    /// The ROM bank mapped at 0x4000-0x7FFF
+    pub fn rom_bank(&self) -> u16 {
+        self.cartridge.as_ref().map_or(1, |cartridge| cartridge.base().rom_bank_selected)
+    }
+
+    /// Reads memory as the CPU would, without the bus conflicts of DMA, for inspecting it
+    pub fn peek(&mut self, address: u16) -> u8 {
+        match address {
+            IF_ADDRESS => self.cpu.interrupts_flag_register | 0xE0,
+            IE_ADDRESS => self.cpu.interrupts_enabled_register,
+            _ => self.split().1.read_direct(address),
+        }
+    }
+
+    /// Writes memory as the CPU would, without the bus conflicts of DMA. Writes to ROM go to
+    /// the MBC registers, and writes to IO registers have their usual effects.
+    pub fn poke(&mut self, address: u16, value: u8) {
+        match address {
+            IF_ADDRESS => self.cpu.interrupts_flag_register = value & 0x1F,
+            IE_ADDRESS => self.cpu.interrupts_enabled_register = value,
+            _ => {
+                let mut bus = self.split().1;
+                bus.write_direct(address, value);
+                let interrupts = bus.interrupts;
+                self.cpu.set_interrupt_flag(interrupts);
+            }
+        }
+    }
+
+    /// Converts CPU cycles to cycles of the rest of the machine
+    fn dots(&self, cycles: u32) -> u32 {
+        if self.speed.double_speed { cycles / 2 } else { cycles }
+    }
+
+    /// Copies one OAM DMA byte per M-cycle
+    fn run_oam_dma(&mut self, cycles: u32) {
+        for _ in 0..cycles / 4 {
+            if let Some((source, index)) = self.dma.oam_step() {
+                let value = self.split().1.read_direct(source);
+                self.ppu.oam[index] = value;
+            }
+        }
+    }
+
+    fn copy_vram_dma_block(&mut self) {
+        if let Some((source, destination)) = self.dma.next_block() {
+            let mut bus = self.split().1;
+            for i in 0..HDMA_BLOCK_SIZE {
+                let value = bus.read_direct(source.wrapping_add(i));
+                bus.write_direct(0x8000 + destination + i, value);
+            }
+            // A block takes as long in either speed, which is twice the CPU cycles in double speed
+            self.dma.stall_cycles += if self.speed.double_speed { 2 * HDMA_BLOCK_CYCLES } else { HDMA_BLOCK_CYCLES };
+        }
+    }
+
+    /// Header and global checksums of the cartridge, which tell save states for it apart
+    fn cartridge_checksums(&self) -> [u8; 3] {
+        let mut checksums = [0; 3];
+        if let Some(rom) = self.cartridge.as_ref().and_then(|cartridge| cartridge.base().rom_banks.get(0x014D..0x0150)) {
+            checksums.copy_from_slice(rom);
+        }
+        checksums
+    }
+
+    /// Snapshots the whole machine. The boot ROM, the ROM itself, the link cable and the audio
+    /// output aren't part of it; they stay whatever is plugged in when the state is loaded.
+    pub fn save_state(&mut self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.write_bytes(&STATE_MAGIC);
+        state.write_u32(STATE_VERSION);
+        state.write_bytes(&self.cartridge_checksums());
+        state.write_u8(self.model as u8);
+        state.write_bool(self.boot_rom.as_ref().is_some_and(|boot_rom| boot_rom.mapped));
+        self.cpu.save_state(&mut state);
+        self.ram.save_state(&mut state);
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.save_state(&mut state);
         }
+        self.ppu.save_state(&mut state);
+        self.apu.save_state(&mut state);
+        self.timer.save_state(&mut state);
+        self.joypad.save_state(&mut state);
+        self.serial.save_state(&mut state);
+        self.dma.save_state(&mut state);
+        self.speed.save_state(&mut state);
+        state.data
     }
 
-    pub fn run_frame(&mut self) {
+    /// Restores a snapshot taken by `save_state`, with the same cartridge inserted. States from
+    /// older versions are read the way they were written, with anything they lack left as it
+    /// is after a reset. On error the machine is left as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data);
+        let mut magic = [0; 4];
+        state.read_into(&mut magic).map_err(|_| StateError::NotAState)?;
+        if magic != STATE_MAGIC {
+            return Err(StateError::NotAState);
+        }
+        let version = state.read_u32()?;
+        if version > STATE_VERSION {
+            return Err(StateError::NewerVersion(version));
+        }
+        let mut checksums = [0; 3];
+        state.read_into(&mut checksums)?;
+        if checksums != self.cartridge_checksums() {
+            return Err(StateError::WrongCartridge);
+        }
+
+        let backup = self.save_state();
+        let result = self.load_machine(&mut state, version);
+        if result.is_err() {
+            self.load_state(&backup).expect("a state just saved loads back");
+        }
+        result
+    }
+
+    /// Reads everything after the header of a save state
+    fn load_machine(&mut self, state: &mut StateReader, version: u32) -> Result<(), StateError> {
+        self.model = Model::from_u8(state.read_u8()?).ok_or(StateError::Corrupt)?;
+        let boot_rom_mapped = state.read_bool()?;
+        match &mut self.boot_rom {
+            Some(boot_rom) => boot_rom.mapped = boot_rom_mapped,
+            None if boot_rom_mapped => return Err(StateError::MissingBootROM),
+            None => {}
+        }
+        self.cpu.load_state(state, version)?;
+        self.ram.load_state(state, version)?;
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.load_state(state, version)?;
+        }
+        self.ppu.load_state(state, version)?;
+        self.apu.load_state(state, version)?;
+        self.timer.load_state(state, version)?;
+        self.joypad.load_state(state, version)?;
+        self.serial.load_state(state, version)?;
+        self.dma.load_state(state, version)?;
+        self.speed.load_state(state, version)?;
+        if !state.is_at_end() {
+            return Err(StateError::Corrupt);
+        }
+        Ok(())
+    }
+
+    /// Runs until the PPU completes a frame, and returns it. With the LCD off no frame is ever
+    /// completed, so a frame's worth of cycles is run instead.
+    pub fn run_frame(&mut self) -> &[u32] {
+        self.ppu.frame_ready = false;
         let mut cycles = 0;
-        while cycles < 70_224 {
-            let c = {
-                let (cpu, mem) = (&mut self.cpu, &mut self.memory);
-                let mut bus = BusMut { memory: mem };
-                cpu.step(&mut bus)
-            };
-            cycles += c as i64;
+        while !self.ppu.frame_ready && (self.ppu.lcd_enabled() || cycles < CYCLES_PER_FRAME) {
+            let cpu_cycles = self.step();
+            cycles += self.dots(cpu_cycles);
         }
+        &self.ppu.framebuffer
     }
 }
 
 impl Bus for Motherboard {
     fn read8(&mut self, address: u16) -> u8 {
-        self.memory[address as usize]
+        match address {
+            IF_ADDRESS => self.cpu.interrupts_flag_register | 0xE0,
+            IE_ADDRESS => self.cpu.interrupts_enabled_register,
+            _ => self.split().1.read8(address),
+        }
     }
     fn write8(&mut self, address: u16, value: u8) {
-        self.memory[address as usize] = value;
+        match address {
+            IF_ADDRESS => self.cpu.interrupts_flag_register = value & 0x1F,
+            IE_ADDRESS => self.cpu.interrupts_enabled_register = value,
+            _ => {
+                let mut bus = self.split().1;
+                bus.write8(address, value);
+                let interrupts = bus.interrupts;
+                self.cpu.set_interrupt_flag(interrupts);
+            }
+        }
+    }
+    fn peek8(&mut self, address: u16) -> u8 {
+        self.peek(address)
     }
 }
+
+// Tests
+#[test]
+fn memory_map() {
+    let mut motherboard = Motherboard::new();
+
+    // Nothing answers on the cartridge slot when it is empty
+    motherboard.write8(0x0100, 0x12);
+    assert_eq!(motherboard.read8(0x0100), 0xFF);
+
+    // Echo RAM mirrors work RAM both ways
+    motherboard.write8(0xC123, 0x34);
+    assert_eq!(motherboard.read8(0xE123), 0x34);
+    motherboard.write8(0xFDFF, 0x56);
+    assert_eq!(motherboard.read8(0xDDFF), 0x56);
+
+    motherboard.write8(0xFEA0, 0x78);
+    assert_eq!(motherboard.read8(0xFEA0), 0x00);
+
+    motherboard.write8(0xFF80, 0x9A);
+    assert_eq!(motherboard.read8(0xFF80), 0x9A);
+
+    motherboard.write8(IE_ADDRESS, 0x1F);
+    assert_eq!(motherboard.cpu.interrupts_enabled_register, 0x1F);
+}
+
+#[test]
+fn run_frame_until_vblank() {
+    let mut motherboard = Motherboard::new();
+    // JR -2 in work RAM
+    motherboard.write8(0xC000, 0x18);
+    motherboard.write8(0xC001, 0xFE);
+    motherboard.cpu.pc = 0xC000;
+    assert_eq!(motherboard.run_frame().len(), 160 * 144);
+    assert_eq!(motherboard.ppu.ly, 144);
+    assert_ne!(motherboard.cpu.interrupts_flag_register & crate::cpu::INTR_VBLANK, 0);
+
+    // A full frame later, the PPU is back at the start of VBlank
+    motherboard.cpu.interrupts_flag_register = 0;
+    motherboard.run_frame();
+    assert_eq!(motherboard.ppu.ly, 144);
+    assert_ne!(motherboard.cpu.interrupts_flag_register & crate::cpu::INTR_VBLANK, 0);
+}
+
+#[test]
+fn oam_and_vram_dma() {
+    let mut motherboard = Motherboard::new();
+    for i in 0..0xA0 {
+        motherboard.write8(0xC100 + i, i as u8);
+    }
+    // The CPU runs NOPs from HRAM, which stays reachable during OAM DMA
+    motherboard.cpu.pc = 0xFF80;
+    motherboard.write8(0xFF46, 0xC1);
+    motherboard.step();
+    assert_eq!(motherboard.read8(0xC100), 0xFF);
+    let mut cycles = 4;
+    while cycles < 4 + 160 * 4 {
+        cycles += motherboard.step();
+    }
+    assert_eq!(motherboard.read8(0xC100), 0x00);
+    assert_eq!(motherboard.ppu.oam[0x9F], 0x9F);
+
+    // A general-purpose transfer of two blocks copies at once, then holds the CPU
+    motherboard.dma.cgb = true;
+    motherboard.write8(0xFF51, 0xC1);
+    motherboard.write8(0xFF52, 0x00);
+    motherboard.write8(0xFF53, 0x00);
+    motherboard.write8(0xFF54, 0x40);
+    motherboard.write8(0xFF55, 0x01);
+    motherboard.step();
+    assert_eq!(motherboard.ppu.vram[0x40..0x60], (0..0x20).collect::<Vec<u8>>()[..]);
+    assert_eq!(motherboard.read8(0xFF55), 0xFF);
+    let pc = motherboard.cpu.pc;
+    assert_eq!(motherboard.step(), 2 * HDMA_BLOCK_CYCLES);
+    assert_eq!(motherboard.cpu.pc, pc);
+}
+
+#[test]
+fn boot_rom_and_post_boot_state() {
+    // Without a boot ROM, registers start as the DMG boot ROM leaves them
+    let mut motherboard = Motherboard::new();
+    assert_eq!((motherboard.cpu.a, motherboard.cpu.f, motherboard.cpu.pc), (0x01, 0x80, 0x0100));
+    assert_eq!(motherboard.read8(0xFF04), 0xAB);
+    assert_eq!(motherboard.read8(IF_ADDRESS), 0xE1);
+
+    // LD A,1; LDH (0x50),A unmaps the boot ROM
+    let mut boot_rom = vec![0; 0x100];
+    boot_rom[0..4].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+    motherboard.insert_boot_rom(BootROM::new(boot_rom, Model::DMG));
+    assert_eq!((motherboard.cpu.a, motherboard.cpu.pc), (0x00, 0x0000));
+    assert!(!motherboard.ppu.lcd_enabled());
+    assert_eq!(motherboard.read8(0x0000), 0x3E);
+    motherboard.step();
+    motherboard.step();
+    assert_eq!(motherboard.read8(0x0000), 0xFF);
+
+    // Resetting maps it again
+    motherboard.reset();
+    assert_eq!(motherboard.read8(0x0000), 0x3E);
+}
+
+#[test]
+fn cgb_mode() {
+    use crate::cartridge::cartridge::cartridge_from_rom;
+    use crate::cartridge::header::CartridgeHeader;
+    use crate::ppu::ppu::VRAM_BANK_SIZE;
+
+    // A cartridge flagged for CGB makes the machine a CGB
+    let mut rom = vec![0; 0x8000];
+    rom[0x0143] = 0x80;
+    rom[0x014D] = CartridgeHeader::compute_header_checksum(&rom);
+    let mut motherboard = Motherboard::new();
+    motherboard.insert_cartridge(cartridge_from_rom(String::from("test"), rom.clone()).unwrap());
+    assert_eq!((motherboard.model, motherboard.ppu.color_mode), (Model::CGB, ColorMode::CGB));
+    assert_eq!(motherboard.cpu.a, 0x11);
+
+    // Work RAM banks at 0xD000, where bank 0 selects bank 1
+    motherboard.write8(0xD000, 0x11);
+    motherboard.write8(0xFF70, 2);
+    assert_eq!(motherboard.read8(0xD000), 0x00);
+    motherboard.write8(0xD000, 0x22);
+    motherboard.write8(0xFF70, 0);
+    assert_eq!(motherboard.read8(0xFF70), 0xF9);
+    assert_eq!(motherboard.read8(0xD000), 0x11);
+
+    motherboard.write8(0xFF4F, 1);
+    motherboard.write8(0x8000, 0x33);
+    assert_eq!((motherboard.ppu.vram[0], motherboard.ppu.vram[VRAM_BANK_SIZE]), (0x00, 0x33));
+
+    // Colour 0 of BG palette 1 set to red, with the index stepping on after each write
+    motherboard.write8(0xFF68, 0x80 | 8);
+    motherboard.write8(0xFF69, 0x1F);
+    motherboard.write8(0xFF69, 0x00);
+    assert_eq!(motherboard.read8(0xFF68), 0xC0 | 10);
+    assert_eq!(motherboard.ppu.bg_palettes.color(1, 0), 0xFF0000);
+
+    // STOP alone keeps the speed, and a prepared switch waits for a STOP rather than the NOP after
+    motherboard.write8(0xC000, 0x10);
+    motherboard.write8(0xC002, 0x00);
+    motherboard.cpu.pc = 0xC000;
+    motherboard.step();
+    assert!(!motherboard.cpu.stopped);
+    motherboard.write8(0xFF4D, 1);
+    motherboard.step();
+    assert_eq!(motherboard.read8(0xFF4D), 0x7F);
+
+    // KEY1 then STOP switches to double speed, where a dot takes two CPU cycles
+    motherboard.cpu.pc = 0xC000;
+    let dot = motherboard.ppu.dot;
+    assert_eq!(motherboard.step(), 4);
+    assert_eq!(motherboard.read8(0xFF4D), 0xFE);
+    assert_eq!(motherboard.ppu.dot, dot + 2);
+
+    // A DMG cartridge in a CGB runs in compatibility mode, without the CGB registers
+    rom[0x0143] = 0x00;
+    rom[0x014D] = CartridgeHeader::compute_header_checksum(&rom);
+    motherboard.insert_cartridge(cartridge_from_rom(String::from("test"), rom).unwrap());
+    assert_eq!(motherboard.ppu.color_mode, ColorMode::Compatibility);
+    assert_eq!(motherboard.read8(0xFF70), 0xFF);
+    assert_eq!(motherboard.ppu.bg_palettes.color(0, 1), 0x7BFF31);
+}
+
+#[test]
+fn save_state_round_trip() {
+    use crate::cartridge::cartridge::cartridge_from_rom;
+    use crate::cartridge::header::CartridgeHeader;
+
+    // MBC1 with RAM, running a loop that counts in cartridge RAM and switches ROM banks and scroll
+    let mut rom = vec![0; 0x10000];
+    rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    rom[0x0147] = 0x02;
+    rom[0x0148] = 0x01;
+    rom[0x0149] = 0x02;
+    rom[0x014D] = CartridgeHeader::compute_header_checksum(&rom);
+    rom[0x0150..0x0161].copy_from_slice(&[0x3E, 0x0A, 0xEA, 0x00, 0x00, 0x21, 0x00, 0xA0, 0x34, 0x7E,
+                                         0xEA, 0x00, 0x20, 0xE0, 0x42, 0x18, 0xF7]);
+    let cartridge = || cartridge_from_rom(String::from("test"), rom.clone()).unwrap();
+
+    let mut motherboard = Motherboard::new();
+    motherboard.insert_cartridge(cartridge());
+    for _ in 0..3 {
+        motherboard.run_frame();
+    }
+    let state = motherboard.save_state();
+    for _ in 0..5 {
+        motherboard.run_frame();
+    }
+    let framebuffer = motherboard.ppu.framebuffer.clone();
+    let later = motherboard.save_state();
+    assert_ne!(state, later);
+
+    // Another machine with the same cartridge picks up from the state and ends up the same
+    let mut other = Motherboard::new();
+    other.insert_cartridge(cartridge());
+    other.load_state(&state).unwrap();
+    for _ in 0..5 {
+        other.run_frame();
+    }
+    assert_eq!(other.save_state(), later);
+    assert_eq!(other.ppu.framebuffer, framebuffer);
+
+    // Bad states are refused, leaving the machine as it was
+    let mut bad = later.clone();
+    bad[4..8].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+    assert_eq!(other.load_state(&bad), Err(StateError::NewerVersion(STATE_VERSION + 1)));
+    assert_eq!(other.load_state(b"RB"), Err(StateError::NotAState));
+    assert_eq!(other.load_state(&state[..state.len() - 1]), Err(StateError::Truncated));
+    bad = state.clone();
+    bad.push(0);
+    assert_eq!(other.load_state(&bad), Err(StateError::Corrupt));
+    assert_eq!(other.save_state(), later);
+    assert_eq!(Motherboard::new().load_state(&state), Err(StateError::WrongCartridge));
+
+    // A state taken while the boot ROM runs needs one to load
+    let mut booting = Motherboard::new();
+    booting.insert_boot_rom(BootROM::new(vec![0; 0x100], Model::DMG));
+    booting.insert_cartridge(cartridge());
+    assert_eq!(other.load_state(&booting.save_state()), Err(StateError::MissingBootROM));
+}
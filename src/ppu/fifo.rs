@@ -0,0 +1,359 @@
+use std::collections::VecDeque;
+use crate::ppu::ppu::*;
+use crate::util::{StateError, StateReader, StateWriter};
+
+/// Dots the background fetcher spends reading the tile number, then each byte of tile data
+const FETCH_STEP_DOTS: u8 = 2;
+/// Dots a sprite fetch holds up the pipeline for, once the background fetcher has filled the FIFO
+const SPRITE_FETCH_DOTS: u8 = 6;
+/// The first tile of each line is fetched twice, and the first copy thrown away
+const LINE_START_DOTS: u8 = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FetcherStep {
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+#[derive(Clone, Copy, Default)]
+struct SpritePixel {
+    color: u8,
+    flags: u8,
+    // Position in the OAM scan's priority order, lower wins
+    priority: usize,
+}
+
+/// State of mode 3 in the pixel-FIFO renderer
+pub struct PixelFIFO {
+    // Colour index and tile attributes
+    bg: VecDeque<(u8, u8)>,
+    obj: VecDeque<SpritePixel>,
+    step: FetcherStep,
+    step_dots: u8,
+    // Next tile column to fetch, counted from SCX or from the window's left edge
+    fetch_x: u8,
+    tile_address: usize,
+    attributes: u8,
+    low: u8,
+    high: u8,
+    // Pixels sent to the LCD on this line
+    lcd_x: u8,
+    // Pixels still to drop from the FIFO for fine scrolling
+    discard: u8,
+    // Dots left before the pipeline moves again
+    stall: u8,
+    in_window: bool,
+    // Sprites from the OAM scan not yet fetched, by X, with their priority
+    sprites: VecDeque<(usize, usize)>,
+    sprite_pending: Option<(usize, usize)>,
+}
+
+impl PixelFIFO {
+    pub fn new() -> Self {
+        Self {
+            bg: VecDeque::with_capacity(16),
+            obj: VecDeque::with_capacity(8),
+            step: FetcherStep::Tile,
+            step_dots: 0,
+            fetch_x: 0,
+            tile_address: 0,
+            attributes: 0,
+            low: 0,
+            high: 0,
+            lcd_x: 0,
+            discard: 0,
+            stall: 0,
+            in_window: false,
+            sprites: VecDeque::new(),
+            sprite_pending: None,
+        }
+    }
+
+    fn restart_fetcher(&mut self) {
+        self.bg.clear();
+        self.step = FetcherStep::Tile;
+        self.step_dots = 0;
+        self.fetch_x = 0;
+    }
+
+    /// Mode 3 can be interrupted at any dot, so the pipeline is saved as it stands
+    pub(super) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.bg.len() as u8);
+        for &(color, attributes) in &self.bg {
+            state.write_u8(color);
+            state.write_u8(attributes);
+        }
+        state.write_u8(self.obj.len() as u8);
+        for pixel in &self.obj {
+            state.write_u8(pixel.color);
+            state.write_u8(pixel.flags);
+            state.write_u8(pixel.priority as u8);
+        }
+        state.write_u8(self.step as u8);
+        state.write_u8(self.step_dots);
+        state.write_u8(self.fetch_x);
+        state.write_u16(self.tile_address as u16);
+        for value in [self.attributes, self.low, self.high, self.lcd_x, self.discard, self.stall] {
+            state.write_u8(value);
+        }
+        state.write_bool(self.in_window);
+        state.write_u8(self.sprites.len() as u8);
+        for &(priority, sprite) in &self.sprites {
+            state.write_u8(priority as u8);
+            state.write_u8(sprite as u8);
+        }
+        // 0xFF for none, past the last OAM offset
+        let (priority, sprite) = self.sprite_pending.unwrap_or((0, 0xFF));
+        state.write_u8(priority as u8);
+        state.write_u8(sprite as u8);
+    }
+
+    pub(super) fn load_state(&mut self, state: &mut StateReader, _state_version: u32) -> Result<(), StateError> {
+        let sprite_offset = |offset: u8| if offset < 0xA0 { Ok(offset as usize) } else { Err(StateError::Corrupt) };
+        self.bg.clear();
+        for _ in 0..state.read_u8()? {
+            self.bg.push_back((state.read_u8()? & 0b11, state.read_u8()?));
+        }
+        self.obj.clear();
+        for _ in 0..state.read_u8()? {
+            let (color, flags, priority) = (state.read_u8()? & 0b11, state.read_u8()?, state.read_u8()? as usize);
+            self.obj.push_back(SpritePixel { color, flags, priority });
+        }
+        self.step = match state.read_u8()? {
+            0 => FetcherStep::Tile,
+            1 => FetcherStep::DataLow,
+            2 => FetcherStep::DataHigh,
+            3 => FetcherStep::Push,
+            _ => return Err(StateError::Corrupt),
+        };
+        self.step_dots = state.read_u8()?;
+        self.fetch_x = state.read_u8()?;
+        self.tile_address = state.read_u16()? as usize;
+        if self.tile_address >= 2 * VRAM_BANK_SIZE - 1 {
+            return Err(StateError::Corrupt);
+        }
+        for value in [&mut self.attributes, &mut self.low, &mut self.high, &mut self.lcd_x, &mut self.discard, &mut self.stall] {
+            *value = state.read_u8()?;
+        }
+        if self.lcd_x >= SCREEN_WIDTH as u8 {
+            return Err(StateError::Corrupt);
+        }
+        self.in_window = state.read_bool()?;
+        self.sprites.clear();
+        for _ in 0..state.read_u8()? {
+            let priority = state.read_u8()? as usize;
+            self.sprites.push_back((priority, sprite_offset(state.read_u8()?)?));
+        }
+        let (priority, sprite) = (state.read_u8()? as usize, state.read_u8()?);
+        self.sprite_pending = if sprite == 0xFF { None } else { Some((priority, sprite_offset(sprite)?)) };
+        Ok(())
+    }
+}
+
+impl PPU {
+    /// Enters mode 3: takes the sprites found by the OAM scan and latches the fine scroll
+    pub(super) fn start_fifo_line(&mut self) {
+        // Sprites are fetched as the line reaches them, whatever order they take priority in
+        let mut sprites: Vec<(usize, usize)> = self.select_sprites().into_iter().enumerate().collect();
+        sprites.sort_by_key(|&(_, sprite)| self.oam[sprite + 1]);
+        let fifo = &mut self.fifo;
+        fifo.restart_fetcher();
+        fifo.obj.clear();
+        fifo.lcd_x = 0;
+        fifo.discard = self.scx % 8;
+        fifo.stall = LINE_START_DOTS;
+        fifo.in_window = false;
+        fifo.sprites = sprites.into();
+        fifo.sprite_pending = None;
+    }
+
+    /// Runs one dot of mode 3. Returns true once the 160th pixel has been sent to the LCD.
+    pub(super) fn fifo_dot(&mut self) -> bool {
+        if self.fifo.stall > 0 {
+            self.fifo.stall -= 1;
+            return false;
+        }
+
+        // A sprite starting at the next pixel stops the output until its row is fetched
+        if self.fifo.sprite_pending.is_none() && self.fifo.discard == 0 && self.lcdc & LCDC_OBJ_ENABLE != 0 {
+            if let Some(&(priority, sprite)) = self.fifo.sprites.front() {
+                if self.oam[sprite + 1] <= self.fifo.lcd_x + 8 {
+                    self.fifo.sprites.pop_front();
+                    self.fifo.sprite_pending = Some((priority, sprite));
+                }
+            }
+        }
+        if let Some((priority, sprite)) = self.fifo.sprite_pending {
+            if self.fifo.bg.is_empty() {
+                self.fetcher_dot();
+                return false;
+            }
+            self.fifo.sprite_pending = None;
+            self.load_sprite(sprite, priority);
+            self.fifo.stall = SPRITE_FETCH_DOTS - 1;
+            return false;
+        }
+
+        // Reaching WX throws away the background pixels and restarts the fetcher on the window
+        if !self.fifo.in_window && self.window_visible() && self.fifo.lcd_x + 7 >= self.wx {
+            self.fifo.in_window = true;
+            self.fifo.restart_fetcher();
+            self.fifo.discard = 7u8.saturating_sub(self.wx);
+        }
+
+        self.fetcher_dot();
+        let Some((color, attributes)) = self.fifo.bg.pop_front() else {
+            return false;
+        };
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return false;
+        }
+        let sprite = self.fifo.obj.pop_front().unwrap_or_default();
+        self.output_pixel(color, attributes, sprite);
+
+        self.fifo.lcd_x += 1;
+        if self.fifo.lcd_x < SCREEN_WIDTH as u8 {
+            return false;
+        }
+        if self.fifo.in_window {
+            self.window_line += 1;
+        }
+        true
+    }
+
+    /// Advances the background fetcher by one dot. Registers are read at the step that uses
+    /// them, so writes made while the line is drawn show up a few pixels later.
+    fn fetcher_dot(&mut self) {
+        if self.fifo.step == FetcherStep::Push {
+            // Pixels are only pushed into an empty FIFO
+            let fifo = &mut self.fifo;
+            if fifo.bg.is_empty() {
+                for x in 0..8 {
+                    let bit = if fifo.attributes & OBJ_X_FLIP != 0 { x } else { 7 - x };
+                    let color = (((fifo.high >> bit) & 1) << 1) | ((fifo.low >> bit) & 1);
+                    fifo.bg.push_back((color, fifo.attributes));
+                }
+                fifo.fetch_x = fifo.fetch_x.wrapping_add(1);
+                fifo.step = FetcherStep::Tile;
+            }
+            return;
+        }
+
+        self.fifo.step_dots += 1;
+        if self.fifo.step_dots < FETCH_STEP_DOTS {
+            return;
+        }
+        self.fifo.step_dots = 0;
+        match self.fifo.step {
+            FetcherStep::Tile => {
+                let (map, x, y) = if self.fifo.in_window {
+                    let map = if self.lcdc & LCDC_WINDOW_MAP != 0 { 0x1C00 } else { 0x1800 };
+                    (map, self.fifo.fetch_x, self.window_line)
+                } else {
+                    let map = if self.lcdc & LCDC_BG_MAP != 0 { 0x1C00 } else { 0x1800 };
+                    (map, (self.scx / 8).wrapping_add(self.fifo.fetch_x), self.ly.wrapping_add(self.scy))
+                };
+                let index = map + (y as usize / 8) * 32 + (x & 31) as usize;
+                let attributes = self.tile_attributes(index);
+                let tile_y = if attributes & OBJ_Y_FLIP != 0 { 7 - y % 8 } else { y % 8 };
+                self.fifo.tile_address = self.tile_data_address(self.vram[index], attributes) + tile_y as usize * 2;
+                self.fifo.attributes = attributes;
+                self.fifo.step = FetcherStep::DataLow;
+            }
+            FetcherStep::DataLow => {
+                self.fifo.low = self.vram[self.fifo.tile_address];
+                self.fifo.step = FetcherStep::DataHigh;
+            }
+            FetcherStep::DataHigh => {
+                self.fifo.high = self.vram[self.fifo.tile_address + 1];
+                self.fifo.step = FetcherStep::Push;
+            }
+            FetcherStep::Push => {}
+        }
+    }
+
+    /// Mixes a sprite's row into the sprite FIFO, over transparent pixels and pixels of sprites
+    /// with lower priority. On DMG the sprites already there always have priority.
+    fn load_sprite(&mut self, sprite: usize, priority: usize) {
+        let row = self.sprite_row(sprite);
+        let flags = self.sprite_flags(sprite);
+        // Sprites hanging off the left edge are cut
+        let skip = 8usize.saturating_sub(self.oam[sprite + 1] as usize);
+        let fifo = &mut self.fifo;
+        while fifo.obj.len() < 8 {
+            fifo.obj.push_back(SpritePixel::default());
+        }
+        for (i, &color) in row.iter().enumerate().skip(skip) {
+            let pixel = &mut fifo.obj[i - skip];
+            if color != 0 && (pixel.color == 0 || priority < pixel.priority) {
+                *pixel = SpritePixel { color, flags, priority };
+            }
+        }
+    }
+
+    /// Sends one pixel to the LCD, applying the palettes as they are right now
+    fn output_pixel(&mut self, color: u8, attributes: u8, sprite: SpritePixel) {
+        let color = if self.bg_enabled() { color } else { 0 };
+        let mut rgb = self.bg_color(color, attributes);
+        if sprite.color != 0 && self.lcdc & LCDC_OBJ_ENABLE != 0
+            && self.sprite_shows(color, attributes, sprite.flags) {
+            rgb = self.obj_color(sprite.color, sprite.flags);
+        }
+        self.framebuffer[self.ly as usize * SCREEN_WIDTH + self.fifo.lcd_x as usize] = rgb;
+    }
+}
+
+
+// Tests
+#[cfg(test)]
+use crate::ppu::scanline::{test_cgb_scene, test_scene};
+
+#[test]
+fn fifo_matches_scanline() {
+    for scene in [test_scene, test_cgb_scene] {
+        let mut scanline = scene(Renderer::Scanline);
+        let mut fifo = scene(Renderer::PixelFIFO);
+        scanline.tick(CYCLES_PER_FRAME);
+        fifo.tick(CYCLES_PER_FRAME);
+        assert!(scanline.framebuffer == fifo.framebuffer);
+    }
+}
+
+#[test]
+fn fifo_mode3_length() {
+    let mode3_length = |ppu: &mut PPU| {
+        ppu.tick(OAM_SCAN_DOTS);
+        let mut dots = 0;
+        while ppu.mode == PPUMode::PixelTransfer {
+            ppu.tick(1);
+            dots += 1;
+        }
+        ppu.tick(DOTS_PER_LINE - OAM_SCAN_DOTS - dots);
+        dots
+    };
+    let mut ppu = PPU::with_renderer(Renderer::PixelFIFO);
+    assert_eq!(mode3_length(&mut ppu), PIXEL_TRANSFER_DOTS);
+
+    // Fine scroll drops pixels at the start of the line
+    ppu.write_register(SCX_ADDRESS, 3);
+    assert_eq!(mode3_length(&mut ppu), PIXEL_TRANSFER_DOTS + 3);
+    ppu.write_register(SCX_ADDRESS, 0);
+
+    // A sprite costs 6 to 11 dots
+    ppu.oam[0..4].copy_from_slice(&[16, 20, 0, 0]);
+    ppu.write_register(LCDC_ADDRESS, ppu.lcdc | LCDC_OBJ_ENABLE);
+    let length = mode3_length(&mut ppu);
+    assert!((PIXEL_TRANSFER_DOTS + 6..=PIXEL_TRANSFER_DOTS + 11).contains(&length), "{}", length);
+
+    // Mid-line palette writes split the line
+    ppu.write_register(LCDC_ADDRESS, ppu.lcdc & !LCDC_OBJ_ENABLE);
+    ppu.tick(OAM_SCAN_DOTS + 6 + 80);
+    ppu.write_register(BGP_ADDRESS, 0xFF);
+    ppu.tick(DOTS_PER_LINE - OAM_SCAN_DOTS - 6 - 80);
+    let row = (ppu.ly as usize - 1) * SCREEN_WIDTH;
+    assert_eq!(ppu.framebuffer[row + 10], DMG_PALETTE[0]);
+    assert_eq!(ppu.framebuffer[row + 150], DMG_PALETTE[3]);
+}
@@ -0,0 +1,5 @@
+#[allow(clippy::module_inception)]
+pub mod ppu;
+pub mod palette;
+mod scanline;
+mod fifo;
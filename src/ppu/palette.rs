@@ -0,0 +1,73 @@
+use crate::util::{StateError, StateReader, StateWriter};
+
+/// Colours the CGB boot ROM gives DMG games it has no palette of its own for, as 0xRRGGBB
+pub const COMPATIBILITY_BG: [u32; 4] = [0xFFFFFF, 0x7BFF31, 0x0063C5, 0x000000];
+pub const COMPATIBILITY_OBJ: [u32; 4] = [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000];
+
+const AUTO_INCREMENT: u8 = 1 << 7;
+
+/// One of the two CGB palette memories: 8 palettes of 4 colours, each colour two bytes of
+/// little-endian RGB555. The CPU reaches it through an index register (BCPS/OCPS), which can
+/// step on after every write, and a data register (BCPD/OCPD).
+pub struct ColorPalettes {
+    pub data: [u8; 64],
+    index: u8,
+}
+
+impl ColorPalettes {
+    pub fn new() -> Self {
+        Self {
+            data: [0xFF; 64],
+            index: 0,
+        }
+    }
+
+    pub fn read_index(&self) -> u8 {
+        0x40 | self.index
+    }
+
+    pub fn write_index(&mut self, value: u8) {
+        self.index = value & (AUTO_INCREMENT | 0x3F);
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[(self.index & 0x3F) as usize]
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        self.data[(self.index & 0x3F) as usize] = value;
+        if self.index & AUTO_INCREMENT != 0 {
+            self.index = AUTO_INCREMENT | (self.index.wrapping_add(1) & 0x3F);
+        }
+    }
+
+    /// Colour `color` of palette `palette` as 0xRRGGBB, with each 5-bit channel scaled to 8 bits
+    pub fn color(&self, palette: u8, color: u8) -> u32 {
+        let offset = (palette as usize & 7) * 8 + color as usize * 2;
+        let rgb555 = u16::from_le_bytes([self.data[offset], self.data[offset + 1]]) as u32;
+        let scale = |channel: u32| (channel << 3) | (channel >> 2);
+        let (red, green, blue) = (rgb555 & 0x1F, (rgb555 >> 5) & 0x1F, (rgb555 >> 10) & 0x1F);
+        (scale(red) << 16) | (scale(green) << 8) | scale(blue)
+    }
+
+    /// Fills one palette from 0xRRGGBB colours, dropping the low bits of each channel
+    pub fn set_palette(&mut self, palette: u8, colors: [u32; 4]) {
+        for (i, rgb) in colors.into_iter().enumerate() {
+            let (red, green, blue) = ((rgb >> 19) & 0x1F, (rgb >> 11) & 0x1F, (rgb >> 3) & 0x1F);
+            let rgb555 = (red | (green << 5) | (blue << 10)) as u16;
+            let offset = (palette as usize & 7) * 8 + i * 2;
+            self.data[offset..offset + 2].copy_from_slice(&rgb555.to_le_bytes());
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.data);
+        state.write_u8(self.index);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader, _state_version: u32) -> Result<(), StateError> {
+        state.read_into(&mut self.data)?;
+        self.write_index(state.read_u8()?);
+        Ok(())
+    }
+}
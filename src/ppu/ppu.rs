@@ -0,0 +1,573 @@
+use crate::cpu::{INTR_LCDC, INTR_VBLANK};
+use crate::ppu::fifo::PixelFIFO;
+use crate::ppu::palette::ColorPalettes;
+use crate::util::{StateError, StateReader, StateWriter};
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+/// Dots (T-cycles) in one scanline, and scanlines in one frame including VBlank
+pub const DOTS_PER_LINE: u32 = 456;
+pub const LINES_PER_FRAME: u8 = 154;
+pub const CYCLES_PER_FRAME: u32 = DOTS_PER_LINE * LINES_PER_FRAME as u32;
+
+pub const OAM_SCAN_DOTS: u32 = 80;
+/// Length of mode 3 without fine scroll, window or sprites
+pub const PIXEL_TRANSFER_DOTS: u32 = 172;
+/// Sprites the OAM scan can select for one scanline
+const SPRITES_PER_LINE: usize = 10;
+/// CGB video RAM has a second bank, selected through VBK
+pub const VRAM_BANK_SIZE: usize = 0x2000;
+
+// Registers
+pub const LCDC_ADDRESS: u16 = 0xFF40;
+pub const STAT_ADDRESS: u16 = 0xFF41;
+pub const SCY_ADDRESS: u16 = 0xFF42;
+pub const SCX_ADDRESS: u16 = 0xFF43;
+pub const LY_ADDRESS: u16 = 0xFF44;
+pub const LYC_ADDRESS: u16 = 0xFF45;
+pub const BGP_ADDRESS: u16 = 0xFF47;
+pub const OBP0_ADDRESS: u16 = 0xFF48;
+pub const OBP1_ADDRESS: u16 = 0xFF49;
+pub const WY_ADDRESS: u16 = 0xFF4A;
+pub const WX_ADDRESS: u16 = 0xFF4B;
+pub const VBK_ADDRESS: u16 = 0xFF4F;
+pub const BCPS_ADDRESS: u16 = 0xFF68;
+pub const BCPD_ADDRESS: u16 = 0xFF69;
+pub const OCPS_ADDRESS: u16 = 0xFF6A;
+pub const OCPD_ADDRESS: u16 = 0xFF6B;
+pub const OPRI_ADDRESS: u16 = 0xFF6C;
+
+// LCDC bits
+pub const LCDC_ENABLE: u8 = 1 << 7;
+pub const LCDC_WINDOW_MAP: u8 = 1 << 6;
+pub const LCDC_WINDOW_ENABLE: u8 = 1 << 5;
+pub const LCDC_TILE_DATA: u8 = 1 << 4;
+pub const LCDC_BG_MAP: u8 = 1 << 3;
+pub const LCDC_OBJ_SIZE: u8 = 1 << 2;
+pub const LCDC_OBJ_ENABLE: u8 = 1 << 1;
+pub const LCDC_BG_ENABLE: u8 = 1 << 0;
+
+// STAT bits
+const STAT_LYC_INTERRUPT: u8 = 1 << 6;
+const STAT_OAM_INTERRUPT: u8 = 1 << 5;
+const STAT_VBLANK_INTERRUPT: u8 = 1 << 4;
+const STAT_HBLANK_INTERRUPT: u8 = 1 << 3;
+const STAT_LYC_EQUAL: u8 = 1 << 2;
+const STAT_WRITABLE: u8 = 0b0111_1000;
+
+// Object attribute flags
+pub const OBJ_BEHIND_BG: u8 = 1 << 7;
+pub const OBJ_Y_FLIP: u8 = 1 << 6;
+pub const OBJ_X_FLIP: u8 = 1 << 5;
+pub const OBJ_PALETTE: u8 = 1 << 4;
+
+// CGB background map attributes, in the same positions in object attributes where they apply
+pub const BG_PRIORITY: u8 = 1 << 7;
+pub const TILE_BANK: u8 = 1 << 3;
+pub const CGB_PALETTE: u8 = 0b111;
+
+/// Shades of grey for the four DMG colours, as 0xRRGGBB
+pub const DMG_PALETTE: [u32; 4] = [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000];
+
+/// How pixel transfer draws the screen. Scanline draws each line in one go at the end of mode 3,
+/// which is fast and right for nearly every game. PixelFIFO runs the background and sprite fetchers
+/// dot by dot, so mode 3 takes as long as it does on hardware and registers written mid-line
+/// take effect from the next pixel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Renderer {
+    Scanline,
+    PixelFIFO,
+}
+
+/// How colours are produced. A DMG draws four shades of grey. A CGB draws from its colour
+/// palettes, and runs DMG games in compatibility mode, where BGP, OBP0 and OBP1 pick colours out
+/// of the first background palette and the first two object palettes.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorMode {
+    DMG,
+    Compatibility,
+    CGB,
+}
+
+/// STAT mode bits, in the order a visible scanline goes through them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PPUMode {
+    HBlank = 0,
+    VBlank = 1,
+    OAMScan = 2,
+    PixelTransfer = 3,
+}
+
+/// Pixel processing unit. Owns video RAM, OAM, the LCD registers at 0xFF40-0xFF4B and the CGB
+/// video registers, and draws into the framebuffer with the chosen renderer.
+#[allow(clippy::upper_case_acronyms)]
+pub struct PPU {
+    pub renderer: Renderer,
+    /// LY reads this instead when set, like the 0x90 Gameboy Doctor's logs were made with
+    pub fixed_ly: Option<u8>,
+    pub color_mode: ColorMode,
+
+    /// Both VRAM banks; bank 1 holds CGB tile data and background map attributes
+    pub vram: [u8; 2 * VRAM_BANK_SIZE],
+    pub vram_bank: u8,
+    pub oam: [u8; 0xA0],
+    pub bg_palettes: ColorPalettes,
+    pub obj_palettes: ColorPalettes,
+    // Bit 0 set orders sprites by X as on DMG, clear by OAM position
+    pub opri: u8,
+
+    pub lcdc: u8,
+    pub stat: u8,
+    pub scy: u8,
+    pub scx: u8,
+    pub ly: u8,
+    pub lyc: u8,
+    pub bgp: u8,
+    pub obp0: u8,
+    pub obp1: u8,
+    pub wy: u8,
+    pub wx: u8,
+
+    pub mode: PPUMode,
+    // Dots into the current scanline
+    pub dot: u32,
+    // The STAT interrupt fires on the rising edge of all enabled sources ORed together
+    stat_line: bool,
+    // The window keeps its own line counter, which only moves on lines it is drawn on
+    pub window_line: u8,
+    window_triggered: bool,
+    pub(super) fifo: PixelFIFO,
+
+    /// 160x144 pixels as 0xRRGGBB, complete at the start of every VBlank
+    pub framebuffer: Vec<u32>,
+    pub frame_ready: bool,
+}
+
+impl PPU {
+    #[cfg(test)]
+    pub fn new() -> Self {
+        PPU::with_renderer(Renderer::Scanline)
+    }
+
+    pub fn with_renderer(renderer: Renderer) -> Self {
+        Self {
+            renderer,
+            fixed_ly: None,
+            color_mode: ColorMode::DMG,
+            vram: [0; 2 * VRAM_BANK_SIZE],
+            vram_bank: 0,
+            oam: [0; 0xA0],
+            bg_palettes: ColorPalettes::new(),
+            obj_palettes: ColorPalettes::new(),
+            opri: 0,
+            lcdc: 0x91,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0xFC,
+            obp0: 0xFF,
+            obp1: 0xFF,
+            wy: 0,
+            wx: 0,
+            mode: PPUMode::OAMScan,
+            dot: 0,
+            stat_line: false,
+            window_line: 0,
+            window_triggered: false,
+            fifo: PixelFIFO::new(),
+            framebuffer: vec![DMG_PALETTE[0]; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame_ready: false,
+        }
+    }
+
+    /// Saves everything but the renderer and fixed LY, which are the frontend's choice, and the
+    /// framebuffer, which is drawn again within a frame
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.color_mode as u8);
+        state.write_bytes(&self.vram);
+        state.write_u8(self.vram_bank);
+        state.write_bytes(&self.oam);
+        self.bg_palettes.save_state(state);
+        self.obj_palettes.save_state(state);
+        state.write_u8(self.opri);
+        for register in [self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0, self.obp1, self.wy, self.wx] {
+            state.write_u8(register);
+        }
+        state.write_u8(self.mode as u8);
+        state.write_u32(self.dot);
+        state.write_bool(self.stat_line);
+        state.write_u8(self.window_line);
+        state.write_bool(self.window_triggered);
+        self.fifo.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader, state_version: u32) -> Result<(), StateError> {
+        self.color_mode = match state.read_u8()? {
+            0 => ColorMode::DMG,
+            1 => ColorMode::Compatibility,
+            2 => ColorMode::CGB,
+            _ => return Err(StateError::Corrupt),
+        };
+        state.read_into(&mut self.vram)?;
+        self.vram_bank = state.read_u8()? & 1;
+        state.read_into(&mut self.oam)?;
+        self.bg_palettes.load_state(state, state_version)?;
+        self.obj_palettes.load_state(state, state_version)?;
+        self.opri = state.read_u8()?;
+        for register in [&mut self.lcdc, &mut self.stat, &mut self.scy, &mut self.scx, &mut self.ly, &mut self.lyc,
+                         &mut self.bgp, &mut self.obp0, &mut self.obp1, &mut self.wy, &mut self.wx] {
+            *register = state.read_u8()?;
+        }
+        self.mode = match state.read_u8()? {
+            0 => PPUMode::HBlank,
+            1 => PPUMode::VBlank,
+            2 => PPUMode::OAMScan,
+            3 => PPUMode::PixelTransfer,
+            _ => return Err(StateError::Corrupt),
+        };
+        self.dot = state.read_u32()?;
+        self.stat_line = state.read_bool()?;
+        self.window_line = state.read_u8()?;
+        self.window_triggered = state.read_bool()?;
+        self.fifo.load_state(state, state_version)?;
+        // Out of range, these would draw outside the framebuffer
+        if self.ly >= LINES_PER_FRAME || self.dot >= DOTS_PER_LINE
+            || (self.mode == PPUMode::PixelTransfer && self.ly >= SCREEN_HEIGHT as u8) {
+            return Err(StateError::Corrupt);
+        }
+        Ok(())
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcdc & LCDC_ENABLE != 0
+    }
+
+    /// Offset into `vram` of a CPU access to 0x8000-0x9FFF, through the bank VBK selects
+    pub fn vram_address(&self, address: u16) -> usize {
+        self.vram_bank as usize * VRAM_BANK_SIZE + (address - 0x8000) as usize
+    }
+
+    /// Palette memory is out of reach of the CPU while the PPU draws
+    fn palettes_accessible(&self) -> bool {
+        !(self.lcd_enabled() && self.mode == PPUMode::PixelTransfer)
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        let cgb = self.color_mode == ColorMode::CGB;
+        match address {
+            LCDC_ADDRESS => self.lcdc,
+            STAT_ADDRESS => {
+                let lyc_equal = if self.ly == self.lyc { STAT_LYC_EQUAL } else { 0 };
+                0x80 | self.stat | lyc_equal | self.mode as u8
+            }
+            SCY_ADDRESS => self.scy,
+            SCX_ADDRESS => self.scx,
+            LY_ADDRESS => self.fixed_ly.unwrap_or(self.ly),
+            LYC_ADDRESS => self.lyc,
+            BGP_ADDRESS => self.bgp,
+            OBP0_ADDRESS => self.obp0,
+            OBP1_ADDRESS => self.obp1,
+            WY_ADDRESS => self.wy,
+            WX_ADDRESS => self.wx,
+            VBK_ADDRESS if cgb => 0xFE | self.vram_bank,
+            BCPS_ADDRESS if cgb => self.bg_palettes.read_index(),
+            BCPD_ADDRESS if cgb && self.palettes_accessible() => self.bg_palettes.read_data(),
+            OCPS_ADDRESS if cgb => self.obj_palettes.read_index(),
+            OCPD_ADDRESS if cgb && self.palettes_accessible() => self.obj_palettes.read_data(),
+            OPRI_ADDRESS if cgb => 0xFE | self.opri,
+            _ => 0xFF,
+        }
+    }
+
+    /// Writes an LCD register. Returns the interrupts the write raises.
+    pub fn write_register(&mut self, address: u16, value: u8) -> u8 {
+        let cgb = self.color_mode == ColorMode::CGB;
+        match address {
+            LCDC_ADDRESS => self.set_lcdc(value),
+            STAT_ADDRESS => self.stat = value & STAT_WRITABLE,
+            SCY_ADDRESS => self.scy = value,
+            SCX_ADDRESS => self.scx = value,
+            // LY is read-only
+            LY_ADDRESS => {}
+            LYC_ADDRESS => self.lyc = value,
+            BGP_ADDRESS => self.bgp = value,
+            OBP0_ADDRESS => self.obp0 = value,
+            OBP1_ADDRESS => self.obp1 = value,
+            WY_ADDRESS => self.wy = value,
+            WX_ADDRESS => self.wx = value,
+            VBK_ADDRESS if cgb => self.vram_bank = value & 1,
+            BCPS_ADDRESS if cgb => self.bg_palettes.write_index(value),
+            BCPD_ADDRESS if cgb && self.palettes_accessible() => self.bg_palettes.write_data(value),
+            OCPS_ADDRESS if cgb => self.obj_palettes.write_index(value),
+            OCPD_ADDRESS if cgb && self.palettes_accessible() => self.obj_palettes.write_data(value),
+            OPRI_ADDRESS if cgb => self.opri = value & 1,
+            _ => {}
+        }
+        self.update_stat_line()
+    }
+
+    fn set_lcdc(&mut self, value: u8) {
+        let was_enabled = self.lcd_enabled();
+        self.lcdc = value;
+        if was_enabled && !self.lcd_enabled() {
+            // Turning the LCD off resets the scanline and blanks the screen
+            self.ly = 0;
+            self.dot = 0;
+            self.mode = PPUMode::HBlank;
+            self.framebuffer.fill(DMG_PALETTE[0]);
+        } else if !was_enabled && self.lcd_enabled() {
+            self.ly = 0;
+            self.dot = 0;
+            self.window_line = 0;
+            self.window_triggered = false;
+            self.mode = PPUMode::OAMScan;
+        }
+    }
+
+    /// Runs the PPU for the given number of dots. Returns the interrupts raised meanwhile.
+    pub fn tick(&mut self, cycles: u32) -> u8 {
+        if !self.lcd_enabled() {
+            return 0;
+        }
+        let mut interrupts = 0;
+        for _ in 0..cycles {
+            interrupts |= self.tick_dot();
+        }
+        interrupts
+    }
+
+    fn tick_dot(&mut self) -> u8 {
+        let mut interrupts = 0;
+        self.dot += 1;
+
+        if self.mode == PPUMode::OAMScan && self.dot == OAM_SCAN_DOTS {
+            self.mode = PPUMode::PixelTransfer;
+            if self.renderer == Renderer::PixelFIFO {
+                self.start_fifo_line();
+            }
+        } else if self.mode == PPUMode::PixelTransfer && self.pixel_transfer_dot() {
+            self.mode = PPUMode::HBlank;
+        }
+
+        if self.dot == DOTS_PER_LINE {
+            self.dot = 0;
+            self.ly = (self.ly + 1) % LINES_PER_FRAME;
+            if self.ly == SCREEN_HEIGHT as u8 {
+                self.mode = PPUMode::VBlank;
+                self.frame_ready = true;
+                interrupts |= INTR_VBLANK;
+            } else if self.ly < SCREEN_HEIGHT as u8 {
+                if self.ly == 0 {
+                    self.window_line = 0;
+                    self.window_triggered = false;
+                }
+                self.mode = PPUMode::OAMScan;
+            }
+        }
+
+        // The window starts on the first line where LY matches WY, and stays on for the frame
+        if self.mode == PPUMode::OAMScan && self.dot == 0 && self.ly == self.wy {
+            self.window_triggered = true;
+        }
+
+        interrupts | self.update_stat_line()
+    }
+
+    /// Runs one dot of mode 3. Returns true when the line is finished.
+    fn pixel_transfer_dot(&mut self) -> bool {
+        match self.renderer {
+            Renderer::Scanline => {
+                if self.dot < OAM_SCAN_DOTS + PIXEL_TRANSFER_DOTS {
+                    return false;
+                }
+                self.render_scanline();
+                true
+            }
+            Renderer::PixelFIFO => self.fifo_dot(),
+        }
+    }
+
+    fn update_stat_line(&mut self) -> u8 {
+        if !self.lcd_enabled() {
+            self.stat_line = false;
+            return 0;
+        }
+        let line = (self.stat & STAT_LYC_INTERRUPT != 0 && self.ly == self.lyc)
+            || (self.stat & STAT_OAM_INTERRUPT != 0 && self.mode == PPUMode::OAMScan)
+            || (self.stat & STAT_VBLANK_INTERRUPT != 0 && self.mode == PPUMode::VBlank)
+            || (self.stat & STAT_HBLANK_INTERRUPT != 0 && self.mode == PPUMode::HBlank);
+        let rising = line && !self.stat_line;
+        self.stat_line = line;
+        if rising { INTR_LCDC } else { 0 }
+    }
+
+    /// Whether the window covers part of the current scanline
+    pub(super) fn window_visible(&self) -> bool {
+        self.lcdc & LCDC_WINDOW_ENABLE != 0 && self.window_triggered && self.wx <= 166
+    }
+
+    /// Whether the background and window are drawn. On CGB, LCDC bit 0 instead takes priority
+    /// away from them, and they are always drawn.
+    pub(super) fn bg_enabled(&self) -> bool {
+        self.lcdc & LCDC_BG_ENABLE != 0 || self.color_mode == ColorMode::CGB
+    }
+
+    /// Attributes of the tile at `index` into a tile map, kept in VRAM bank 1. Always 0 outside CGB mode.
+    pub(super) fn tile_attributes(&self, index: usize) -> u8 {
+        if self.color_mode == ColorMode::CGB { self.vram[VRAM_BANK_SIZE + index] } else { 0 }
+    }
+
+    /// Colour index 0-3 and attributes of pixel (x, y) in the 256x256 tile map at `map` (0x1800
+    /// or 0x1C00 into VRAM)
+    pub(super) fn tile_map_pixel(&self, map: usize, x: u8, y: u8) -> (u8, u8) {
+        let index = map + (y as usize / 8) * 32 + x as usize / 8;
+        let attributes = self.tile_attributes(index);
+        let tile_x = if attributes & OBJ_X_FLIP != 0 { 7 - x % 8 } else { x % 8 };
+        let tile_y = if attributes & OBJ_Y_FLIP != 0 { 7 - y % 8 } else { y % 8 };
+        let color = self.tile_pixel(self.tile_data_address(self.vram[index], attributes), tile_x, tile_y);
+        (color, attributes)
+    }
+
+    /// Address into VRAM of a background or window tile, following the LCDC addressing mode and
+    /// the bank in its attributes
+    pub(super) fn tile_data_address(&self, tile: u8, attributes: u8) -> usize {
+        let bank = if attributes & TILE_BANK != 0 { VRAM_BANK_SIZE } else { 0 };
+        if self.lcdc & LCDC_TILE_DATA != 0 {
+            bank + tile as usize * 16
+        } else {
+            bank + (0x1000 + tile as i8 as isize * 16) as usize
+        }
+    }
+
+    /// Colour index 0-3 of pixel (x, y) in the 8x8 tile at `address` into VRAM
+    pub(super) fn tile_pixel(&self, address: usize, x: u8, y: u8) -> u8 {
+        let low = self.vram[address + y as usize * 2];
+        let high = self.vram[address + y as usize * 2 + 1];
+        let bit = 7 - x;
+        (((high >> bit) & 1) << 1) | ((low >> bit) & 1)
+    }
+
+    /// OAM scan: the first ten sprites in OAM that overlap line LY, as offsets into OAM, in the
+    /// order they take priority in. That is by X, then by OAM position on DMG, and by OAM
+    /// position alone on CGB unless OPRI asks for the DMG order.
+    pub(super) fn select_sprites(&self) -> Vec<usize> {
+        let height = self.sprite_height();
+        let line = self.ly as i16 + 16;
+        let mut sprites: Vec<usize> = (0..40)
+            .map(|i| i * 4)
+            .filter(|&sprite| {
+                let top = self.oam[sprite] as i16;
+                line >= top && line < top + height
+            })
+            .take(SPRITES_PER_LINE)
+            .collect();
+        if self.color_mode != ColorMode::CGB || self.opri & 1 != 0 {
+            sprites.sort_by_key(|&sprite| self.oam[sprite + 1]);
+        }
+        sprites
+    }
+
+    /// Attribute flags of a sprite, without the CGB bits outside CGB mode
+    pub(super) fn sprite_flags(&self, sprite: usize) -> u8 {
+        let flags = self.oam[sprite + 3];
+        if self.color_mode == ColorMode::CGB { flags } else { flags & 0xF0 }
+    }
+
+    pub(super) fn sprite_height(&self) -> i16 {
+        if self.lcdc & LCDC_OBJ_SIZE != 0 { 16 } else { 8 }
+    }
+
+    /// Colour indices of the 8 pixels of a sprite on line LY, left to right after flipping
+    pub(super) fn sprite_row(&self, sprite: usize) -> [u8; 8] {
+        let height = self.sprite_height();
+        let flags = self.sprite_flags(sprite);
+        let mut tile = self.oam[sprite + 2];
+        if height == 16 {
+            tile &= 0xFE;
+        }
+        let mut tile_y = (self.ly as i16 + 16 - self.oam[sprite] as i16) as u8;
+        if flags & OBJ_Y_FLIP != 0 {
+            tile_y = height as u8 - 1 - tile_y;
+        }
+        let bank = if flags & TILE_BANK != 0 { VRAM_BANK_SIZE } else { 0 };
+        let address = bank + tile as usize * 16 + (tile_y as usize / 8) * 16;
+        let mut row = [0; 8];
+        for (x, color) in row.iter_mut().enumerate() {
+            let pixel_x = if flags & OBJ_X_FLIP != 0 { 7 - x as u8 } else { x as u8 };
+            *color = self.tile_pixel(address, pixel_x, tile_y % 8);
+        }
+        row
+    }
+
+    /// Final colour of a background or window pixel
+    pub(super) fn bg_color(&self, color: u8, attributes: u8) -> u32 {
+        let shade = (self.bgp >> (color * 2)) & 0b11;
+        match self.color_mode {
+            ColorMode::DMG => DMG_PALETTE[shade as usize],
+            ColorMode::Compatibility => self.bg_palettes.color(0, shade),
+            ColorMode::CGB => self.bg_palettes.color(attributes & CGB_PALETTE, color),
+        }
+    }
+
+    /// Final colour of a sprite pixel
+    pub(super) fn obj_color(&self, color: u8, flags: u8) -> u32 {
+        let obp1 = flags & OBJ_PALETTE != 0;
+        let shade = ((if obp1 { self.obp1 } else { self.obp0 }) >> (color * 2)) & 0b11;
+        match self.color_mode {
+            ColorMode::DMG => DMG_PALETTE[shade as usize],
+            ColorMode::Compatibility => self.obj_palettes.color(obp1 as u8, shade),
+            ColorMode::CGB => self.obj_palettes.color(flags & CGB_PALETTE, color),
+        }
+    }
+
+    /// Whether an opaque sprite pixel shows over a background pixel. Colour 0 never hides a
+    /// sprite. Otherwise the background wins if the sprite is flagged behind it, or on CGB if
+    /// the tile is flagged in front, unless LCDC bit 0 is clear.
+    pub(super) fn sprite_shows(&self, bg_color: u8, bg_attributes: u8, flags: u8) -> bool {
+        if bg_color == 0 {
+            return true;
+        }
+        if self.color_mode == ColorMode::CGB && self.lcdc & LCDC_BG_ENABLE == 0 {
+            return true;
+        }
+        flags & OBJ_BEHIND_BG == 0 && bg_attributes & BG_PRIORITY == 0
+    }
+}
+
+
+// Tests
+#[test]
+fn ppu_mode_timing() {
+    let mut ppu = PPU::new();
+    assert_eq!(ppu.mode, PPUMode::OAMScan);
+    assert_eq!(ppu.tick(80), 0);
+    assert_eq!(ppu.mode, PPUMode::PixelTransfer);
+    ppu.tick(172);
+    assert_eq!(ppu.mode, PPUMode::HBlank);
+    ppu.tick(204);
+    assert_eq!((ppu.ly, ppu.mode), (1, PPUMode::OAMScan));
+
+    // LYC coincidence raises STAT once
+    ppu.write_register(LYC_ADDRESS, 3);
+    ppu.write_register(STAT_ADDRESS, STAT_LYC_INTERRUPT);
+    assert_eq!(ppu.tick(DOTS_PER_LINE), 0);
+    assert_eq!(ppu.tick(DOTS_PER_LINE), INTR_LCDC);
+    assert_eq!(ppu.read_register(STAT_ADDRESS) & STAT_LYC_EQUAL, STAT_LYC_EQUAL);
+    assert_eq!(ppu.tick(DOTS_PER_LINE), 0);
+
+    // VBlank starts at line 144 and the frame wraps after 154 lines
+    assert_eq!(ppu.tick(DOTS_PER_LINE * 140) & INTR_VBLANK, INTR_VBLANK);
+    assert!(ppu.frame_ready);
+    assert_eq!((ppu.ly, ppu.mode), (144, PPUMode::VBlank));
+    ppu.tick(DOTS_PER_LINE * 10);
+    assert_eq!((ppu.ly, ppu.mode), (0, PPUMode::OAMScan));
+
+    // Nothing moves with the LCD off
+    ppu.write_register(LCDC_ADDRESS, 0);
+    assert_eq!(ppu.tick(CYCLES_PER_FRAME), 0);
+    assert_eq!(ppu.read_register(LY_ADDRESS), 0);
+}
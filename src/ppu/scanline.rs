@@ -0,0 +1,132 @@
+use crate::ppu::ppu::*;
+
+impl PPU {
+    /// Draws the whole of line LY at once, from the registers as they are at the end of pixel transfer
+    pub(super) fn render_scanline(&mut self) {
+        let y = self.ly;
+        let row = y as usize * SCREEN_WIDTH;
+        // Raw background colour indices and tile attributes, which decide whether sprites show through
+        let mut bg_pixels = [(0u8, 0u8); SCREEN_WIDTH];
+
+        // On DMG, clearing the BG enable bit blanks the background and the window
+        if self.bg_enabled() {
+            let map = if self.lcdc & LCDC_BG_MAP != 0 { 0x1C00 } else { 0x1800 };
+            for (x, pixel) in bg_pixels.iter_mut().enumerate() {
+                *pixel = self.tile_map_pixel(map, (x as u8).wrapping_add(self.scx), y.wrapping_add(self.scy));
+            }
+
+            if self.window_visible() {
+                let map = if self.lcdc & LCDC_WINDOW_MAP != 0 { 0x1C00 } else { 0x1800 };
+                let start = self.wx as isize - 7;
+                for (x, pixel) in bg_pixels.iter_mut().enumerate().skip(start.max(0) as usize) {
+                    *pixel = self.tile_map_pixel(map, (x as isize - start) as u8, self.window_line);
+                }
+                self.window_line += 1;
+            }
+        }
+        for (x, &(color, attributes)) in bg_pixels.iter().enumerate() {
+            self.framebuffer[row + x] = self.bg_color(color, attributes);
+        }
+
+        if self.lcdc & LCDC_OBJ_ENABLE != 0 {
+            self.render_sprites(row, &bg_pixels);
+        }
+    }
+
+    fn render_sprites(&mut self, row: usize, bg_pixels: &[(u8, u8); SCREEN_WIDTH]) {
+        let mut claimed = [false; SCREEN_WIDTH];
+        for sprite in self.select_sprites() {
+            let left = self.oam[sprite + 1] as isize - 8;
+            let flags = self.sprite_flags(sprite);
+
+            for (tile_x, color) in self.sprite_row(sprite).into_iter().enumerate() {
+                let x = left + tile_x as isize;
+                if x < 0 || x >= SCREEN_WIDTH as isize || claimed[x as usize] || color == 0 {
+                    continue;
+                }
+                // An opaque pixel hides the sprites below it, even when the background then hides it
+                claimed[x as usize] = true;
+                let (bg_color, bg_attributes) = bg_pixels[x as usize];
+                if !self.sprite_shows(bg_color, bg_attributes, flags) {
+                    continue;
+                }
+                self.framebuffer[row + x as usize] = self.obj_color(color, flags);
+            }
+        }
+    }
+}
+
+// Tests
+/// Background, window and two sprites, one of them behind the background
+#[cfg(test)]
+pub(super) fn test_scene(renderer: Renderer) -> PPU {
+    let mut ppu = PPU::with_renderer(renderer);
+    // Tile 1 is solid colour 3, tile 2 has colour 1 in its left column
+    ppu.vram[0x10..0x20].fill(0xFF);
+    for row in 0..8 {
+        ppu.vram[0x20 + row * 2] = 0x80;
+    }
+    // BG map uses tile 1 at the top-left, window map tile 2
+    ppu.vram[0x1800] = 1;
+    ppu.vram[0x1C00] = 2;
+    ppu.write_register(BGP_ADDRESS, 0b11_10_01_00);
+    ppu.write_register(LCDC_ADDRESS, LCDC_ENABLE | LCDC_TILE_DATA | LCDC_BG_ENABLE | LCDC_OBJ_ENABLE
+        | LCDC_WINDOW_ENABLE | LCDC_WINDOW_MAP);
+    ppu.write_register(WY_ADDRESS, 8);
+    ppu.write_register(WX_ADDRESS, 7 + 4);
+
+    // Sprite using tile 1 at (20, 0), and one behind the background at (4, 0)
+    ppu.oam[0..4].copy_from_slice(&[16, 28, 1, 0]);
+    ppu.oam[4..8].copy_from_slice(&[16, 12, 1, OBJ_BEHIND_BG]);
+    ppu
+}
+
+/// The same scene in CGB mode, with a flipped bank 1 tile in the corner and a third sprite
+#[cfg(test)]
+pub(super) fn test_cgb_scene(renderer: Renderer) -> PPU {
+    let mut ppu = test_scene(renderer);
+    ppu.color_mode = ColorMode::CGB;
+    // Tile 1 in bank 1 has colour 3 in its right column, drawn flipped in palette 2
+    for row in 0..8 {
+        ppu.vram[VRAM_BANK_SIZE + 0x10 + row * 2..][..2].fill(0x01);
+    }
+    ppu.vram[VRAM_BANK_SIZE + 0x1800] = TILE_BANK | OBJ_X_FLIP | 2;
+    ppu.bg_palettes.set_palette(2, [0xFFFFFF, 0, 0, 0xFF0000]);
+    ppu.obj_palettes.set_palette(5, [0, 0, 0, 0x0000FF]);
+    ppu.obj_palettes.set_palette(6, [0, 0, 0, 0x00FF00]);
+    // On CGB the first sprite in OAM wins over one further left
+    ppu.oam[3] = 5;
+    ppu.oam[8..12].copy_from_slice(&[16, 24, 1, 6]);
+    ppu
+}
+
+#[test]
+fn render_background_window_and_sprites() {
+    let mut ppu = test_scene(Renderer::Scanline);
+    ppu.tick(CYCLES_PER_FRAME);
+
+    let pixel = |x: usize, y: usize| ppu.framebuffer[y * SCREEN_WIDTH + x];
+    assert_eq!(pixel(0, 0), DMG_PALETTE[3]);
+    assert_eq!(pixel(16, 0), DMG_PALETTE[0]);
+    assert_eq!(pixel(20, 0), DMG_PALETTE[ppu.obp0 as usize & 0b11]);
+    // Behind colour 3 background it hides, over colour 0 it shows
+    assert_eq!(pixel(4, 0), DMG_PALETTE[3]);
+    assert_eq!(pixel(9, 0), DMG_PALETTE[3]);
+    assert_eq!(pixel(12, 0), DMG_PALETTE[0]);
+    // Window from line 8, starting at x = 4
+    assert_eq!(pixel(3, 8), DMG_PALETTE[0]);
+    assert_eq!(pixel(4, 8), DMG_PALETTE[1]);
+    assert_eq!(pixel(5, 8), DMG_PALETTE[0]);
+}
+
+#[test]
+fn render_cgb_attributes() {
+    let mut ppu = test_cgb_scene(Renderer::Scanline);
+    ppu.tick(CYCLES_PER_FRAME);
+
+    let pixel = |x: usize, y: usize| ppu.framebuffer[y * SCREEN_WIDTH + x];
+    assert_eq!(pixel(0, 0), 0xFF0000);
+    assert_eq!(pixel(1, 0), 0xFFFFFF);
+    assert_eq!(pixel(17, 0), 0x00FF00);
+    assert_eq!(pixel(20, 0), 0x0000FF);
+}
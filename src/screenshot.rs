@@ -0,0 +1,93 @@
+use std::io;
+use std::path::Path;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+/// Largest block deflate can store without compressing
+const STORED_BLOCK_SIZE: usize = 0xFFFF;
+
+/// Writes 0xRRGGBB pixels to a PNG file
+pub fn save_png(path: &Path, pixels: &[u32], width: usize, height: usize) -> io::Result<()> {
+    std::fs::write(path, encode_png(pixels, width, height))
+}
+
+/// Encodes 0xRRGGBB pixels as an 8-bit RGB PNG. The image data is stored uncompressed, which
+/// keeps the encoder small; a Game Boy screen is only 69 KB that way.
+pub fn encode_png(pixels: &[u32], width: usize, height: usize) -> Vec<u8> {
+    // Every row starts with its filter type, 0 for none
+    let mut raw = Vec::with_capacity(height * (1 + width * 3));
+    for row in pixels.chunks(width).take(height) {
+        raw.push(0);
+        for &rgb in row {
+            raw.extend_from_slice(&rgb.to_be_bytes()[1..]);
+        }
+    }
+
+    // zlib stream of stored deflate blocks
+    let mut zlib = vec![0x78, 0x01];
+    let blocks = raw.chunks(STORED_BLOCK_SIZE).count().max(1);
+    for (i, block) in raw.chunks(STORED_BLOCK_SIZE).enumerate() {
+        zlib.push((i + 1 == blocks) as u8);
+        zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per channel, RGB, deflate, no filtering beyond the per-row byte, not interlaced
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut png = PNG_SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib);
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+
+// Tests
+#[test]
+fn png_encoding() {
+    assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+    assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+
+    let png = encode_png(&[0xFF0000, 0x00FF00, 0x0000FF, 0xFFFFFF], 2, 2);
+    assert_eq!(png[..8], PNG_SIGNATURE);
+    assert_eq!(png[12..16], *b"IHDR");
+    assert_eq!(png[png.len() - 12..], [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+    // Two rows of a filter byte and two RGB pixels, stored in one final block
+    let idat = &png[33 + 8..];
+    assert_eq!(idat[..7], [0x78, 0x01, 1, 14, 0, !14, 0xFF]);
+    assert_eq!(idat[7..21], [0, 0xFF, 0, 0, 0, 0xFF, 0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF]);
+}
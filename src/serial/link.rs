@@ -0,0 +1,280 @@
+use std::cell::RefCell;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// What is plugged into the link port. The side driving the clock exchanges a whole byte at
+/// the end of its transfer; the side waiting on an external clock polls for one.
+pub trait LinkCable {
+    /// Sends `byte` from the side driving the clock, and returns the byte shifted in from the
+    /// other end. With nobody clocking in on the other end, the line reads 0xFF.
+    fn exchange(&mut self, byte: u8) -> u8;
+
+    /// Called while waiting on an external clock, with `byte` ready to go out. Returns the byte
+    /// the other end sent if it has clocked a transfer, having sent `byte` back to it.
+    fn poll(&mut self, byte: u8) -> Option<u8>;
+}
+
+/// Nothing plugged in
+pub struct Disconnected;
+
+impl LinkCable for Disconnected {
+    fn exchange(&mut self, _byte: u8) -> u8 {
+        0xFF
+    }
+
+    fn poll(&mut self, _byte: u8) -> Option<u8> {
+        None
+    }
+}
+
+/// Writes every byte sent to stdout, which is how test ROMs such as Blargg's report results
+pub struct StdoutLink;
+
+impl LinkCable for StdoutLink {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(&[byte]);
+        let _ = stdout.flush();
+        0xFF
+    }
+
+    fn poll(&mut self, _byte: u8) -> Option<u8> {
+        None
+    }
+}
+
+#[derive(Default)]
+struct PairState {
+    // Byte each end has ready while it waits on an external clock
+    waiting: [Option<u8>; 2],
+    // Byte each end was sent by the other's transfer, not yet picked up
+    received: [Option<u8>; 2],
+}
+
+/// One end of a cable between two machines in the same process, made by `link_pair`
+#[allow(dead_code)]
+pub struct PairLink {
+    state: Rc<RefCell<PairState>>,
+    end: usize,
+}
+
+/// A cable between two motherboards stepped by the same thread. The frontend only runs one
+/// machine, so this is for embedders that run two.
+#[allow(dead_code)]
+pub fn link_pair() -> (PairLink, PairLink) {
+    let state = Rc::new(RefCell::new(PairState::default()));
+    (PairLink { state: state.clone(), end: 0 }, PairLink { state, end: 1 })
+}
+
+impl LinkCable for PairLink {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        let mut state = self.state.borrow_mut();
+        let other = 1 - self.end;
+        match state.waiting[other].take() {
+            Some(reply) => {
+                state.received[other] = Some(byte);
+                reply
+            }
+            None => 0xFF,
+        }
+    }
+
+    fn poll(&mut self, byte: u8) -> Option<u8> {
+        let mut state = self.state.borrow_mut();
+        let received = state.received[self.end].take();
+        // Offered again on every poll, so a change to SB mid-wait goes out
+        state.waiting[self.end] = if received.is_none() { Some(byte) } else { None };
+        received
+    }
+}
+
+// Messages between two processes: a transfer from the clocking side, the byte sent back, and
+// the clocking side giving up on a transfer nobody answered in time
+const MESSAGE_TRANSFER: u8 = 0;
+const MESSAGE_REPLY: u8 = 1;
+const MESSAGE_CANCEL: u8 = 2;
+
+/// How long the clocking side waits for the other process to answer a transfer
+const REPLY_TIMEOUT: Duration = Duration::from_millis(100);
+
+trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+/// A cable to another emulator process over a TCP or Unix socket. Every message is three bytes:
+/// a kind, the sequence number of the transfer it belongs to and the byte transferred. Replies
+/// that arrive after the clocking side gave up, and transfers it cancelled, are recognised by
+/// their sequence number and dropped.
+pub struct SocketLink {
+    stream: Box<dyn Stream>,
+    // Bytes read that do not yet make a whole message
+    buffer: Vec<u8>,
+    // Sequence number of the last transfer this end clocked
+    sequence: u8,
+    // Transfer from the other end that is still waiting for this end to answer
+    pending: Option<(u8, u8)>,
+}
+
+impl SocketLink {
+    /// Waits for the other process to connect to `address`, which is `host:port` for TCP or
+    /// `unix:PATH` for a Unix socket
+    pub fn listen(address: &str) -> io::Result<Self> {
+        match address.strip_prefix("unix:") {
+            Some(path) => SocketLink::listen_unix(path),
+            None => SocketLink::listen_tcp(address),
+        }
+    }
+
+    /// Connects to another process listening on `address`, in the same form as for `listen`
+    pub fn connect(address: &str) -> io::Result<Self> {
+        match address.strip_prefix("unix:") {
+            Some(path) => SocketLink::connect_unix(path),
+            None => SocketLink::connect_tcp(address),
+        }
+    }
+
+    pub fn listen_tcp<A: ToSocketAddrs>(address: A) -> io::Result<Self> {
+        let (stream, _) = TcpListener::bind(address)?.accept()?;
+        SocketLink::from_tcp(stream)
+    }
+
+    pub fn connect_tcp<A: ToSocketAddrs>(address: A) -> io::Result<Self> {
+        SocketLink::from_tcp(TcpStream::connect(address)?)
+    }
+
+    fn from_tcp(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+        Ok(SocketLink::new(Box::new(stream)))
+    }
+
+    /// The socket file is removed once the other end is connected, so the path can be reused
+    #[cfg(unix)]
+    pub fn listen_unix<P: AsRef<std::path::Path>>(path: P) -> io::Result<Self> {
+        let (stream, _) = std::os::unix::net::UnixListener::bind(&path)?.accept()?;
+        let _ = std::fs::remove_file(path);
+        SocketLink::from_unix(stream)
+    }
+
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<std::path::Path>>(path: P) -> io::Result<Self> {
+        SocketLink::from_unix(std::os::unix::net::UnixStream::connect(path)?)
+    }
+
+    #[cfg(unix)]
+    fn from_unix(stream: std::os::unix::net::UnixStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(SocketLink::new(Box::new(stream)))
+    }
+
+    #[cfg(not(unix))]
+    pub fn listen_unix<P: AsRef<std::path::Path>>(_path: P) -> io::Result<Self> {
+        Err(io::Error::new(ErrorKind::Unsupported, "Unix sockets are not available on this platform"))
+    }
+
+    #[cfg(not(unix))]
+    pub fn connect_unix<P: AsRef<std::path::Path>>(_path: P) -> io::Result<Self> {
+        Err(io::Error::new(ErrorKind::Unsupported, "Unix sockets are not available on this platform"))
+    }
+
+    fn new(stream: Box<dyn Stream>) -> Self {
+        Self {
+            stream,
+            buffer: Vec::with_capacity(3),
+            sequence: 0,
+            pending: None,
+        }
+    }
+
+    /// Next whole message as (kind, sequence, byte), if one has arrived
+    fn receive(&mut self) -> Option<(u8, u8, u8)> {
+        let mut byte = [0];
+        while self.buffer.len() < 3 {
+            match self.stream.read(&mut byte) {
+                Ok(1) => self.buffer.push(byte[0]),
+                Ok(_) => return None,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return None,
+            }
+        }
+        let message = (self.buffer[0], self.buffer[1], self.buffer[2]);
+        self.buffer.clear();
+        Some(message)
+    }
+
+    fn send(&mut self, kind: u8, sequence: u8, byte: u8) {
+        // The socket is non-blocking, but three bytes always fit in its buffer
+        let _ = self.stream.write_all(&[kind, sequence, byte]);
+        let _ = self.stream.flush();
+    }
+}
+
+impl LinkCable for SocketLink {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        self.sequence = self.sequence.wrapping_add(1);
+        self.send(MESSAGE_TRANSFER, self.sequence, byte);
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        while Instant::now() < deadline {
+            match self.receive() {
+                Some((MESSAGE_REPLY, sequence, reply)) if sequence == self.sequence => return reply,
+                // Late replies to earlier transfers, and transfers from the other end when both
+                // sides clocked at once, are lost as on hardware
+                Some(_) => {}
+                None => std::thread::sleep(Duration::from_micros(50)),
+            }
+        }
+        self.send(MESSAGE_CANCEL, self.sequence, 0);
+        0xFF
+    }
+
+    fn poll(&mut self, byte: u8) -> Option<u8> {
+        // Read everything queued first, so a transfer that has since been cancelled is not answered
+        while let Some(message) = self.receive() {
+            match message {
+                (MESSAGE_TRANSFER, sequence, received) => self.pending = Some((sequence, received)),
+                (MESSAGE_CANCEL, sequence, _) if self.pending.is_some_and(|(pending, _)| pending == sequence) => {
+                    self.pending = None;
+                }
+                _ => {}
+            }
+        }
+        let (sequence, received) = self.pending.take()?;
+        self.send(MESSAGE_REPLY, sequence, byte);
+        Some(received)
+    }
+}
+
+
+// Tests
+#[cfg(unix)]
+#[test]
+fn socket_link_loopback() {
+    use std::os::unix::net::UnixStream;
+
+    let pair = || {
+        let (a, b) = UnixStream::pair().unwrap();
+        (SocketLink::from_unix(a).unwrap(), SocketLink::from_unix(b).unwrap())
+    };
+
+    // A transfer answered while the clocking side waits
+    let (mut master, mut slave) = pair();
+    let waiting = std::thread::spawn(move || loop {
+        if let Some(received) = slave.poll(0x34) {
+            return (received, slave);
+        }
+        std::thread::sleep(Duration::from_micros(50));
+    });
+    assert_eq!(master.exchange(0x12), 0x34);
+    let (received, mut slave) = waiting.join().unwrap();
+    assert_eq!(received, 0x12);
+
+    // Nobody answered in time, so the transfer is cancelled and never seen by the other end
+    assert_eq!(master.exchange(0x56), 0xFF);
+    assert_eq!(slave.poll(0x78), None);
+
+    // A reply to an earlier transfer is not taken for the answer to the next one
+    slave.send(MESSAGE_REPLY, master.sequence, 0x9A);
+    assert_eq!(master.exchange(0xBC), 0xFF);
+    assert_eq!(slave.poll(0xDE), None);
+}
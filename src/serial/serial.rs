@@ -0,0 +1,144 @@
+use crate::cpu::INTR_SERIAL;
+use crate::serial::link::{Disconnected, LinkCable};
+use crate::util::{StateError, StateReader, StateWriter};
+
+// Registers
+pub const SB_ADDRESS: u16 = 0xFF01;
+pub const SC_ADDRESS: u16 = 0xFF02;
+
+const SC_TRANSFER: u8 = 1 << 7;
+const SC_FAST_CLOCK: u8 = 1 << 1;
+const SC_INTERNAL_CLOCK: u8 = 1 << 0;
+
+/// T-cycles per bit on the internal clock: 8192 Hz, or 262144 Hz with the CGB's fast clock
+const BIT_CYCLES: u32 = 512;
+const FAST_BIT_CYCLES: u32 = 16;
+
+/// The link port. Setting SC bit 7 starts shifting SB out and the other end's byte in. With the
+/// internal clock this side drives the transfer, which ends after 8 bits; with the external
+/// clock it waits for the other end. Either way the serial interrupt fires once SB holds the
+/// byte received.
+pub struct Serial {
+    pub cgb: bool,
+    pub sb: u8,
+    pub sc: u8,
+    // T-cycles left in a transfer on the internal clock
+    remaining: u32,
+    pub link: Box<dyn LinkCable>,
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Self {
+            cgb: false,
+            sb: 0,
+            sc: 0,
+            remaining: 0,
+            link: Box::new(Disconnected),
+        }
+    }
+
+    /// Clears the registers, keeping whatever is plugged in
+    pub fn reset(&mut self) {
+        self.cgb = false;
+        self.sb = 0;
+        self.sc = 0;
+        self.remaining = 0;
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            SB_ADDRESS => self.sb,
+            SC_ADDRESS if self.cgb => 0x7C | self.sc,
+            SC_ADDRESS => 0x7E | self.sc,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            SB_ADDRESS => self.sb = value,
+            SC_ADDRESS => {
+                let mut writable = SC_TRANSFER | SC_INTERNAL_CLOCK;
+                if self.cgb {
+                    writable |= SC_FAST_CLOCK;
+                }
+                self.sc = value & writable;
+                let bit_cycles = if self.sc & SC_FAST_CLOCK != 0 { FAST_BIT_CYCLES } else { BIT_CYCLES };
+                self.remaining = 8 * bit_cycles;
+            }
+            _ => {}
+        }
+    }
+
+    /// Runs the port for the given number of CPU cycles. Returns the serial interrupt if a
+    /// transfer finished.
+    pub fn tick(&mut self, cycles: u32) -> u8 {
+        if self.sc & SC_TRANSFER == 0 {
+            return 0;
+        }
+        let received = if self.sc & SC_INTERNAL_CLOCK != 0 {
+            self.remaining = self.remaining.saturating_sub(cycles);
+            if self.remaining > 0 {
+                return 0;
+            }
+            self.link.exchange(self.sb)
+        } else {
+            match self.link.poll(self.sb) {
+                Some(byte) => byte,
+                None => return 0,
+            }
+        };
+        self.sb = received;
+        self.sc &= !SC_TRANSFER;
+        INTR_SERIAL
+    }
+
+    /// The cable is left as it is; whatever is on the other end isn't part of this machine
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.cgb);
+        state.write_u8(self.sb);
+        state.write_u8(self.sc);
+        state.write_u32(self.remaining);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader, _state_version: u32) -> Result<(), StateError> {
+        self.cgb = state.read_bool()?;
+        self.sb = state.read_u8()?;
+        self.sc = state.read_u8()?;
+        self.remaining = state.read_u32()?;
+        Ok(())
+    }
+}
+
+
+// Tests
+#[test]
+fn serial_transfer_over_pair() {
+    use crate::serial::link::link_pair;
+
+    let (master_end, slave_end) = link_pair();
+    let mut master = Serial::new();
+    let mut slave = Serial::new();
+    master.link = Box::new(master_end);
+    slave.link = Box::new(slave_end);
+
+    master.write_register(SB_ADDRESS, 0x12);
+    slave.write_register(SB_ADDRESS, 0x34);
+    slave.write_register(SC_ADDRESS, SC_TRANSFER);
+    master.write_register(SC_ADDRESS, SC_TRANSFER | SC_INTERNAL_CLOCK);
+    assert_eq!(master.read_register(SC_ADDRESS), 0xFF);
+
+    // The slave waits on the master's clock, which takes 8 bits of 512 cycles
+    assert_eq!(slave.tick(4), 0);
+    assert_eq!(master.tick(8 * BIT_CYCLES - 4), 0);
+    assert_eq!(master.tick(4), INTR_SERIAL);
+    assert_eq!((master.sb, master.read_register(SC_ADDRESS)), (0x34, 0x7F));
+    assert_eq!(slave.tick(4), INTR_SERIAL);
+    assert_eq!(slave.sb, 0x12);
+
+    // Nobody on the other end reads as 0xFF
+    master.write_register(SC_ADDRESS, SC_TRANSFER | SC_INTERNAL_CLOCK);
+    assert_eq!(master.tick(8 * BIT_CYCLES), INTR_SERIAL);
+    assert_eq!(master.sb, 0xFF);
+}
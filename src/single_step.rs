@@ -0,0 +1,325 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use crate::bus::{Access, Bus};
+use crate::cpu::{CPU, IE_ADDRESS, IF_ADDRESS};
+use crate::json::{self, Json};
+
+/// Flat 64 KiB of memory that remembers every access made to it
+pub struct RecordingBus {
+    pub memory: Vec<u8>,
+    pub accesses: Vec<Access>,
+}
+
+impl RecordingBus {
+    pub fn new() -> Self {
+        Self {
+            memory: vec![0; 0x10000],
+            accesses: Vec::new(),
+        }
+    }
+}
+
+impl Bus for RecordingBus {
+    fn read8(&mut self, address: u16) -> u8 {
+        let value = self.memory[address as usize];
+        self.accesses.push(Access::Read { address, value });
+        value
+    }
+    fn write8(&mut self, address: u16, value: u8) {
+        self.accesses.push(Access::Write { address, value });
+        self.memory[address as usize] = value;
+    }
+    fn peek8(&mut self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+}
+
+/// The CPU and the memory the test cares about, before or after the instruction
+struct State {
+    a: u8,
+    f: u8,
+    b: u8,
+    c: u8,
+    d: u8,
+    e: u8,
+    h: u8,
+    l: u8,
+    sp: u16,
+    pc: u16,
+    ime: bool,
+    // Whether an EI is waiting to take effect, which only some vectors give
+    ei: Option<bool>,
+    ie: Option<u8>,
+    ram: Vec<(u16, u8)>,
+}
+
+impl State {
+    fn parse(json: &Json) -> Result<Self, String> {
+        let number = |key: &str, max: u64| match json.get(key) {
+            Some(value) => value.as_u64().filter(|&number| number <= max)
+                .ok_or_else(|| format!("{} is out of range", key)).map(Some),
+            None => Ok(None),
+        };
+        let register = |key: &str| number(key, 0xFF)?.map(|value| value as u8).ok_or_else(|| format!("missing {}", key));
+        let pointer = |key: &str| number(key, 0xFFFF)?.map(|value| value as u16).ok_or_else(|| format!("missing {}", key));
+        let ram = json.get("ram").and_then(Json::as_array).ok_or("missing ram")?.iter()
+            .map(|entry| match entry.as_array() {
+                Some([address, value]) => address.as_u64().filter(|&address| address <= 0xFFFF)
+                    .zip(value.as_u64().filter(|&value| value <= 0xFF))
+                    .map(|(address, value)| (address as u16, value as u8)),
+                _ => None,
+            }.ok_or_else(|| "ram entries must be [address, value]".to_string()))
+            .collect::<Result<_, _>>()?;
+        Ok(State {
+            a: register("a")?,
+            f: register("f")?,
+            b: register("b")?,
+            c: register("c")?,
+            d: register("d")?,
+            e: register("e")?,
+            h: register("h")?,
+            l: register("l")?,
+            sp: pointer("sp")?,
+            pc: pointer("pc")?,
+            ime: number("ime", 1)?.ok_or("missing ime")? != 0,
+            ei: number("ei", 1)?.map(|ei| ei != 0),
+            ie: number("ie", 0xFF)?.map(|ie| ie as u8),
+            ram,
+        })
+    }
+}
+
+/// One test vector: a single instruction run from a known state
+pub struct Vector {
+    pub name: String,
+    initial: State,
+    expected: State,
+    /// The machine cycles the instruction takes, with the access made in each, if any
+    cycles: Vec<Option<Access>>,
+}
+
+impl Vector {
+    pub fn parse(json: &Json) -> Result<Self, String> {
+        let name = json.get("name").and_then(Json::as_str).ok_or("missing name")?.to_string();
+        let state = |key: &str| json.get(key).ok_or_else(|| format!("missing {}", key)).and_then(State::parse)
+            .map_err(|error| format!("{} {}: {}", name, key, error));
+        let initial = state("initial")?;
+        let expected = state("final")?;
+        let cycles = json.get("cycles").and_then(Json::as_array).ok_or(format!("{}: missing cycles", name))?.iter()
+            .map(|cycle| parse_cycle(cycle).ok_or_else(|| format!("{}: cycles must be [address, value, activity] or null", name)))
+            .collect::<Result<_, _>>()?;
+        Ok(Vector { name, initial, expected, cycles })
+    }
+
+    /// Runs the instruction on a fresh CPU, describing the first difference from the vector
+    pub fn run(&self) -> Result<(), String> {
+        let mut cpu = CPU::new();
+        let mut bus = RecordingBus::new();
+        let initial = &self.initial;
+        (cpu.a, cpu.f, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l) =
+            (initial.a, initial.f, initial.b, initial.c, initial.d, initial.e, initial.h, initial.l);
+        (cpu.sp, cpu.pc) = (initial.sp, initial.pc);
+        cpu.interrupt_master_enable = initial.ime;
+        cpu.interrupt_queued = initial.ei.unwrap_or(false);
+        cpu.interrupts_flag_register = 0;
+        cpu.interrupts_enabled_register = initial.ie.unwrap_or(0);
+        for &(address, value) in &initial.ram {
+            match address {
+                IF_ADDRESS => cpu.interrupts_flag_register = value & 0x1F,
+                IE_ADDRESS => cpu.interrupts_enabled_register = value,
+                _ => bus.memory[address as usize] = value,
+            }
+        }
+
+        let cycles = cpu.step(&mut bus);
+
+        let expected = &self.expected;
+        let registers = [
+            ("A", cpu.a as u16, expected.a as u16), ("F", cpu.f as u16, expected.f as u16),
+            ("B", cpu.b as u16, expected.b as u16), ("C", cpu.c as u16, expected.c as u16),
+            ("D", cpu.d as u16, expected.d as u16), ("E", cpu.e as u16, expected.e as u16),
+            ("H", cpu.h as u16, expected.h as u16), ("L", cpu.l as u16, expected.l as u16),
+            ("SP", cpu.sp, expected.sp), ("PC", cpu.pc, expected.pc),
+            ("IME", cpu.interrupt_master_enable as u16, expected.ime as u16),
+        ];
+        for (name, actual, wanted) in registers {
+            if actual != wanted {
+                return Err(format!("{} is {:02X}, expected {:02X}", name, actual, wanted));
+            }
+        }
+        if let Some(ei) = expected.ei {
+            if cpu.interrupt_queued != ei {
+                return Err(format!("EI pending is {}, expected {}", cpu.interrupt_queued, ei));
+            }
+        }
+        if let Some(ie) = expected.ie {
+            if cpu.interrupts_enabled_register != ie {
+                return Err(format!("IE is {:02X}, expected {:02X}", cpu.interrupts_enabled_register, ie));
+            }
+        }
+        for &(address, wanted) in &expected.ram {
+            // Only the five interrupt bits of IF exist
+            let (actual, wanted) = match address {
+                IF_ADDRESS => (cpu.interrupts_flag_register | 0xE0, wanted | 0xE0),
+                IE_ADDRESS => (cpu.interrupts_enabled_register, wanted),
+                _ => (bus.memory[address as usize], wanted),
+            };
+            if actual != wanted {
+                return Err(format!("({:04X}) is {:02X}, expected {:02X}", address, actual, wanted));
+            }
+        }
+
+        if cycles != self.cycles.len() as u32 * 4 {
+            return Err(format!("took {} cycles, expected {}", cycles, self.cycles.len() * 4));
+        }
+        // The CPU keeps IF and IE itself, so accesses to them never reach the bus
+        let wanted: Vec<Access> = self.cycles.iter().flatten()
+            .filter(|access| !matches!(access.address(), IF_ADDRESS | IE_ADDRESS))
+            .copied().collect();
+        if let Some(i) = (0..bus.accesses.len().max(wanted.len())).find(|&i| bus.accesses.get(i) != wanted.get(i)) {
+            let describe = |access: Option<&Access>| match access {
+                Some(Access::Read { address, value }) => format!("read {:02X} from {:04X}", value, address),
+                Some(Access::Write { address, value }) => format!("write {:02X} to {:04X}", value, address),
+                None => "nothing".to_string(),
+            };
+            return Err(format!("bus access {} was {}, expected {}", i + 1, describe(bus.accesses.get(i)), describe(wanted.get(i))));
+        }
+        Ok(())
+    }
+}
+
+/// A machine cycle is `[address, value, activity]`, where activity is `r-m` for a read, `-wm`
+/// for a write and `---` when the bus is idle. Idle cycles may also be null, or have a null value.
+fn parse_cycle(cycle: &Json) -> Option<Option<Access>> {
+    if cycle.is_null() {
+        return Some(None);
+    }
+    let [address, value, activity] = cycle.as_array()? else {
+        return None;
+    };
+    let activity = activity.as_str()?;
+    if value.is_null() || !activity.contains(['r', 'w']) {
+        return Some(None);
+    }
+    let address = address.as_u64().filter(|&address| address <= 0xFFFF)? as u16;
+    let value = value.as_u64().filter(|&value| value <= 0xFF)? as u8;
+    Some(Some(if activity.contains('r') {
+        Access::Read { address, value }
+    } else {
+        Access::Write { address, value }
+    }))
+}
+
+/// How one opcode fared
+pub struct Outcome {
+    pub passed: usize,
+    pub total: usize,
+    /// The name of the first vector that failed, and what went wrong
+    pub first_failure: Option<(String, String)>,
+}
+
+/// Runs a file of vectors, which are a JSON array of them
+pub fn run_vectors(text: &str) -> Result<Outcome, String> {
+    let json = json::parse(text).map_err(|error| error.to_string())?;
+    let vectors = json.as_array().ok_or("expected an array of tests")?;
+    let mut outcome = Outcome { passed: 0, total: vectors.len(), first_failure: None };
+    for vector in vectors {
+        let vector = Vector::parse(vector)?;
+        match vector.run() {
+            Ok(()) => outcome.passed += 1,
+            Err(error) => {
+                outcome.first_failure.get_or_insert((vector.name, error));
+            }
+        }
+    }
+    Ok(outcome)
+}
+
+/// Outcomes by opcode, named after the files the vectors came from like `3c` or `cb 37`
+pub struct Report {
+    pub opcodes: BTreeMap<String, Outcome>,
+}
+
+impl Report {
+    /// Runs every .json file in a directory, like the `v1` folder of the SM83 single-step tests
+    pub fn run_directory(directory: &Path) -> io::Result<Self> {
+        let mut opcodes = BTreeMap::new();
+        for entry in std::fs::read_dir(directory)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let opcode = path.file_stem().unwrap().to_string_lossy().into_owned();
+            let outcome = run_vectors(&std::fs::read_to_string(&path)?)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), error)))?;
+            opcodes.insert(opcode, outcome);
+        }
+        Ok(Report { opcodes })
+    }
+
+    pub fn all_passed(&self) -> bool {
+        self.opcodes.values().all(|outcome| outcome.passed == outcome.total)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (opcode, outcome) in &self.opcodes {
+            let status = if outcome.passed == outcome.total { "ok" } else { "FAIL" };
+            write!(f, "{:<6} {:<4} {}/{}", opcode, status, outcome.passed, outcome.total)?;
+            if let Some((name, error)) = &outcome.first_failure {
+                write!(f, ", first {}: {}", name, error)?;
+            }
+            writeln!(f)?;
+        }
+        let passed = self.opcodes.values().filter(|outcome| outcome.passed == outcome.total).count();
+        write!(f, "{} of {} opcodes passed", passed, self.opcodes.len())
+    }
+}
+
+
+// Tests
+#[cfg(test)]
+const INC_DE: &str = r#"{"name": "13 0000",
+    "initial": {"pc": 49152, "sp": 65534, "a": 0, "b": 0, "c": 0, "d": 18, "e": 255, "f": 176, "h": 0, "l": 0, "ime": 0, "ie": 0, "ram": [[49152, 19]]},
+    "final": {"pc": 49153, "sp": 65534, "a": 0, "b": 0, "c": 0, "d": 19, "e": 0, "f": 176, "h": 0, "l": 0, "ime": 0, "ie": 0, "ram": [[49152, 19]]},
+    "cycles": [[49152, 19, "r-m"], [49153, null, "---"]]}"#;
+#[cfg(test)]
+const ADD_HL_BC: &str = r#"{"name": "09 0000",
+    "initial": {"pc": 256, "sp": 57344, "a": 1, "b": 15, "c": 255, "d": 0, "e": 0, "f": 128, "h": 16, "l": 1, "ime": 1, "ie": 0, "ram": [[256, 9]]},
+    "final": {"pc": 257, "sp": 57344, "a": 1, "b": 15, "c": 255, "d": 0, "e": 0, "f": 160, "h": 32, "l": 0, "ime": 1, "ie": 0, "ram": [[256, 9]]},
+    "cycles": [[256, 9, "r-m"], null]}"#;
+#[cfg(test)]
+const PUSH_BC: &str = r#"{"name": "c5 0000",
+    "initial": {"pc": 256, "sp": 57344, "a": 0, "b": 18, "c": 52, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ie": 0, "ram": [[256, 197]]},
+    "final": {"pc": 257, "sp": 57342, "a": 0, "b": 18, "c": 52, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ie": 0, "ram": [[256, 197], [57343, 18], [57342, 52]]},
+    "cycles": [[256, 197, "r-m"], [57344, null, "---"], [57343, 18, "-wm"], [57342, 52, "-wm"]]}"#;
+
+#[test]
+fn single_step_vectors() {
+    let outcome = run_vectors(&format!("[{}, {}, {}]", INC_DE, ADD_HL_BC, PUSH_BC)).unwrap();
+    assert_eq!((outcome.passed, outcome.total), (3, 3), "{:?}", outcome.first_failure);
+
+    // Vectors expecting INC DE to decrement, or PUSH to write the low byte first, fail
+    let decrement = INC_DE.replace(r#""d": 19, "e": 0"#, r#""d": 18, "e": 254"#);
+    let outcome = run_vectors(&format!("[{}, {}]", ADD_HL_BC, decrement)).unwrap();
+    assert_eq!((outcome.passed, outcome.total), (1, 2));
+    assert_eq!(outcome.first_failure, Some(("13 0000".to_string(), "D is 13, expected 12".to_string())));
+    let low_first = PUSH_BC.replace(r#"[57343, 18, "-wm"], [57342, 52, "-wm"]"#, r#"[57342, 52, "-wm"], [57343, 18, "-wm"]"#);
+    let outcome = run_vectors(&format!("[{}]", low_first)).unwrap();
+    assert_eq!(outcome.first_failure.unwrap().1, "bus access 2 was write 12 to DFFF, expected write 34 to DFFE");
+    let slow = ADD_HL_BC.replace("null]", "null, null]");
+    assert_eq!(run_vectors(&format!("[{}]", slow)).unwrap().first_failure.unwrap().1, "took 8 cycles, expected 12");
+
+    let report = Report { opcodes: BTreeMap::from([
+        ("09".to_string(), run_vectors(&format!("[{}]", ADD_HL_BC)).unwrap()),
+        ("13".to_string(), run_vectors(&format!("[{}]", decrement)).unwrap()),
+    ]) };
+    assert!(!report.all_passed());
+    assert_eq!(report.to_string(), "09     ok   1/1\n13     FAIL 0/1, first 13 0000: D is 13, expected 12\n1 of 2 opcodes passed");
+
+    assert_eq!(run_vectors(r#"[{"name": "00"}]"#).err().unwrap(), "00 initial: missing initial");
+    assert_eq!(run_vectors("{}").err().unwrap(), "expected an array of tests");
+}
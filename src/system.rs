@@ -0,0 +1,75 @@
+use crate::util::{StateError, StateReader, StateWriter};
+
+/// Game Boy hardware revisions. They differ in boot ROM, and in the state it leaves behind.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Model {
+    DMG,
+    MGB,
+    SGB,
+    CGB,
+}
+
+impl Model {
+    pub fn from_u8(value: u8) -> Option<Model> {
+        match value {
+            0 => Some(Model::DMG),
+            1 => Some(Model::MGB),
+            2 => Some(Model::SGB),
+            3 => Some(Model::CGB),
+            _ => None,
+        }
+    }
+}
+
+// CGB registers
+pub const KEY0_ADDRESS: u16 = 0xFF4C;
+pub const KEY1_ADDRESS: u16 = 0xFF4D;
+
+/// KEY0 value the CGB boot ROM writes for cartridges without CGB support
+pub const KEY0_DMG_COMPATIBILITY: u8 = 1 << 2;
+
+/// The CGB's speed switch, KEY1. Setting bit 0 prepares a switch, which the next STOP carries
+/// out. In double speed the CPU, the timer and OAM DMA run twice as fast as the rest.
+pub struct SpeedSwitch {
+    pub double_speed: bool,
+    pub prepared: bool,
+}
+
+impl SpeedSwitch {
+    pub fn new() -> Self {
+        Self {
+            double_speed: false,
+            prepared: false,
+        }
+    }
+
+    pub fn read_register(&self) -> u8 {
+        0x7E | ((self.double_speed as u8) << 7) | self.prepared as u8
+    }
+
+    pub fn write_register(&mut self, value: u8) {
+        self.prepared = value & 1 != 0;
+    }
+
+    /// Carries out a prepared switch. Returns whether there was one.
+    pub fn switch(&mut self) -> bool {
+        if !self.prepared {
+            return false;
+        }
+        self.prepared = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.double_speed);
+        state.write_bool(self.prepared);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader, _state_version: u32) -> Result<(), StateError> {
+        self.double_speed = state.read_bool()?;
+        self.prepared = state.read_bool()?;
+        Ok(())
+    }
+}
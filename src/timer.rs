@@ -0,0 +1,193 @@
+use crate::cpu::INTR_TIMER;
+use crate::system::Model;
+use crate::util::{StateError, StateReader, StateWriter};
+
+// Registers
+pub const DIV_ADDRESS: u16 = 0xFF04;
+pub const TIMA_ADDRESS: u16 = 0xFF05;
+pub const TMA_ADDRESS: u16 = 0xFF06;
+pub const TAC_ADDRESS: u16 = 0xFF07;
+
+const TAC_ENABLE: u8 = 1 << 2;
+
+/// Bit of the internal counter each TAC clock select watches: 4096, 262144, 65536 and 16384 Hz
+const TAC_BITS: [u16; 4] = [9, 3, 5, 7];
+
+/// DIV and TIMA, both driven by one 16-bit counter that goes up every T-cycle. DIV is its upper
+/// byte. TIMA goes up on a falling edge of the counter bit selected by TAC, ANDed with the
+/// enable bit, which is why resetting DIV or changing TAC can tick TIMA.
+pub struct Timer {
+    pub counter: u16,
+    pub tima: u8,
+    pub tma: u8,
+    pub tac: u8,
+    // TIMA overflowed during the last M-cycle and reads 0 until it is reloaded in this one
+    overflow: bool,
+    // TIMA was reloaded from TMA during the last M-cycle, and ignores writes for the rest of it
+    reloaded: bool,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self {
+            counter: 0xABCC,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflow: false,
+            reloaded: false,
+        }
+    }
+
+    /// Where each model's boot ROM leaves the counter when it hands over to the cartridge
+    pub fn set_post_boot_state(&mut self, model: Model) {
+        self.counter = match model {
+            Model::DMG | Model::MGB => 0xABCC,
+            Model::SGB => 0xD85C,
+            Model::CGB => 0x267C,
+        };
+    }
+
+    /// Input to the falling-edge detector
+    fn signal(&self) -> bool {
+        self.tac & TAC_ENABLE != 0 && self.counter & (1 << TAC_BITS[(self.tac & 0b11) as usize]) != 0
+    }
+
+    fn increment_tima(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        self.overflow = overflow;
+    }
+
+    /// Runs the timer for the given number of T-cycles, one M-cycle at a time. Returns the
+    /// interrupts raised meanwhile.
+    pub fn tick(&mut self, cycles: u32) -> u8 {
+        let mut interrupts = 0;
+        for _ in 0..cycles / 4 {
+            interrupts |= self.tick_mcycle();
+        }
+        interrupts
+    }
+
+    fn tick_mcycle(&mut self) -> u8 {
+        let mut interrupts = 0;
+        self.reloaded = false;
+        // Reloading TMA and raising the interrupt happen one M-cycle after the overflow
+        if self.overflow {
+            self.overflow = false;
+            self.reloaded = true;
+            self.tima = self.tma;
+            interrupts |= INTR_TIMER;
+        }
+        let signal = self.signal();
+        self.counter = self.counter.wrapping_add(4);
+        if signal && !self.signal() {
+            self.increment_tima();
+        }
+        interrupts
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            DIV_ADDRESS => (self.counter >> 8) as u8,
+            TIMA_ADDRESS => self.tima,
+            TMA_ADDRESS => self.tma,
+            TAC_ADDRESS => 0xF8 | self.tac,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        let signal = self.signal();
+        match address {
+            // Any write resets the whole counter
+            DIV_ADDRESS => self.counter = 0,
+            // Writing during the overflow M-cycle cancels the reload and the interrupt,
+            // writing during the reload M-cycle is lost
+            TIMA_ADDRESS if !self.reloaded => {
+                self.tima = value;
+                self.overflow = false;
+            }
+            TIMA_ADDRESS => {}
+            TMA_ADDRESS => {
+                self.tma = value;
+                if self.reloaded {
+                    self.tima = value;
+                }
+            }
+            TAC_ADDRESS => self.tac = value & 0b111,
+            _ => {}
+        }
+        if signal && !self.signal() {
+            self.increment_tima();
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.counter);
+        state.write_u8(self.tima);
+        state.write_u8(self.tma);
+        state.write_u8(self.tac);
+        state.write_bool(self.overflow);
+        state.write_bool(self.reloaded);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader, _state_version: u32) -> Result<(), StateError> {
+        self.counter = state.read_u16()?;
+        self.tima = state.read_u8()?;
+        self.tma = state.read_u8()?;
+        self.tac = state.read_u8()?;
+        self.overflow = state.read_bool()?;
+        self.reloaded = state.read_bool()?;
+        Ok(())
+    }
+}
+
+
+// Tests
+#[test]
+fn timer_overflow_and_glitches() {
+    let mut timer = Timer::new();
+    timer.write_register(DIV_ADDRESS, 0);
+    assert_eq!(timer.read_register(DIV_ADDRESS), 0);
+    timer.tick(256);
+    assert_eq!(timer.read_register(DIV_ADDRESS), 1);
+
+    // 262144 Hz: TIMA goes up every 16 cycles
+    timer.write_register(DIV_ADDRESS, 0);
+    timer.write_register(TMA_ADDRESS, 0x80);
+    timer.write_register(TIMA_ADDRESS, 0xFE);
+    timer.write_register(TAC_ADDRESS, TAC_ENABLE | 0b01);
+    assert_eq!(timer.tick(16), 0);
+    assert_eq!(timer.tima, 0xFF);
+
+    // Overflow reads 0 for one M-cycle before TMA is loaded and the interrupt raised
+    assert_eq!(timer.tick(16), 0);
+    assert_eq!(timer.tima, 0x00);
+    assert_eq!(timer.tick(4), INTR_TIMER);
+    assert_eq!(timer.tima, 0x80);
+
+    // Writes in the reload M-cycle are lost
+    timer.write_register(TIMA_ADDRESS, 0x10);
+    assert_eq!(timer.tima, 0x80);
+
+    // A TIMA write in the overflow M-cycle cancels the reload
+    timer.tick(4);
+    timer.write_register(TIMA_ADDRESS, 0xFF);
+    timer.tick(8);
+    assert_eq!(timer.tima, 0x00);
+    timer.write_register(TIMA_ADDRESS, 0x42);
+    assert_eq!(timer.tick(4), 0);
+    assert_eq!(timer.tima, 0x42);
+
+    // Resetting DIV while the selected bit is set ticks TIMA
+    timer.write_register(DIV_ADDRESS, 0);
+    timer.tick(8);
+    timer.write_register(DIV_ADDRESS, 0);
+    assert_eq!(timer.tima, 0x43);
+
+    // So does disabling the timer while it is set
+    timer.tick(8);
+    timer.write_register(TAC_ADDRESS, 0b01);
+    assert_eq!(timer.tima, 0x44);
+}
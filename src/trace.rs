@@ -0,0 +1,124 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use crate::cpu::CPU;
+
+/// Registers as trace logs show them, like `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100`
+pub fn registers(cpu: &CPU) -> String {
+    format!("A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X}",
+        cpu.a, cpu.f, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l, cpu.sp, cpu.pc)
+}
+
+/// Logs the instructions the CPU runs, a line each before it runs, in the format of Gameboy
+/// Doctor and the logs of other emulators it compares against: the registers, then the four
+/// bytes from PC. Interrupt dispatch and cycles spent halted aren't logged.
+pub struct Tracer {
+    output: Box<dyn Write>,
+    /// Only instructions in this inclusive range of addresses are logged
+    pub pc_range: Option<(u16, u16)>,
+    /// Only instructions in this inclusive range of frames are logged
+    pub frames: Option<(u64, u64)>,
+    /// Frames completed since tracing started, which the motherboard counts at each VBlank
+    pub frame: u64,
+}
+
+impl Tracer {
+    pub fn new(output: Box<dyn Write>) -> Self {
+        Self {
+            output,
+            pc_range: None,
+            frames: None,
+            frame: 0,
+        }
+    }
+
+    /// Logs to a file, or to standard output for `-`
+    pub fn create(path: &Path) -> io::Result<Self> {
+        if path == Path::new("-") {
+            return Ok(Tracer::new(Box::new(io::stdout())));
+        }
+        Ok(Tracer::new(Box::new(BufWriter::new(File::create(path)?))))
+    }
+
+    /// Whether the instruction at `pc` should be logged
+    pub fn wants(&self, pc: u16) -> bool {
+        self.pc_range.is_none_or(|(start, end)| start <= pc && pc <= end)
+            && self.frames.is_none_or(|(first, last)| first <= self.frame && self.frame <= last)
+    }
+
+    /// Logs the instruction about to run. Logging stops at the first error writing it.
+    pub fn trace(&mut self, cpu: &CPU, pcmem: [u8; 4]) {
+        let result = writeln!(self.output, "{} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            registers(cpu), pcmem[0], pcmem[1], pcmem[2], pcmem[3]);
+        if let Err(error) = result {
+            eprintln!("Failed to write trace: {}", error);
+            self.output = Box::new(io::sink());
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+
+// Tests
+/// Output the test can still read after handing it to a tracer
+#[cfg(test)]
+#[derive(Clone)]
+struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+#[cfg(test)]
+impl Write for SharedBuffer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(data);
+        Ok(data.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn gameboy_doctor_format() {
+    use crate::bus::Access;
+    use crate::motherboard::{Bus, Motherboard};
+
+    let mut motherboard = Motherboard::new();
+    // NOP; INC A; JR -3 in work RAM
+    for (i, byte) in [0x00, 0x3C, 0x18, 0xFD].into_iter().enumerate() {
+        motherboard.write8(0xC000 + i as u16, byte);
+    }
+    motherboard.cpu.pc = 0xC000;
+    let buffer = SharedBuffer(Default::default());
+    motherboard.cpu.tracer = Some(Tracer::new(Box::new(buffer.clone())));
+    for _ in 0..3 {
+        motherboard.step();
+    }
+    assert_eq!(String::from_utf8(buffer.0.take()).unwrap(), "\
+A:01 F:80 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:C000 PCMEM:00,3C,18,FD
+A:01 F:80 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:C001 PCMEM:3C,18,FD,00
+A:02 F:00 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:C002 PCMEM:18,FD,00,00
+");
+
+    // Only INC A in the second frame
+    let tracer = motherboard.cpu.tracer.as_mut().unwrap();
+    tracer.pc_range = Some((0xC001, 0xC001));
+    tracer.frames = Some((1, 1));
+    motherboard.run_frame();
+    assert!(buffer.0.borrow().is_empty());
+    motherboard.run_frame();
+    motherboard.run_frame();
+    let log = String::from_utf8(buffer.0.take()).unwrap();
+    assert!(log.lines().count() > 1000 && log.lines().all(|line| line.contains("PC:C001 PCMEM:3C,18")));
+    assert_eq!(motherboard.cpu.tracer.as_ref().unwrap().frame, 3);
+
+    // Reading PCMEM doesn't show up as accesses to a watching debugger
+    let tracer = motherboard.cpu.tracer.as_mut().unwrap();
+    (tracer.pc_range, tracer.frames) = (None, None);
+    motherboard.cpu.pc = 0xC000;
+    let mut accesses = Vec::new();
+    motherboard.step_observed(|access| accesses.push(access));
+    assert_eq!(accesses, [Access::Read { address: 0xC000, value: 0x00 }]);
+    assert!(buffer.0.take().ends_with(b"PC:C000 PCMEM:00,3C,18,FD\n"));
+}
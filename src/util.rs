@@ -0,0 +1,132 @@
+use std::fmt;
+
+/// Save states start with this, then the version of the layout that follows
+pub const STATE_MAGIC: [u8; 4] = *b"RBSS";
+/// Version of the layout `Motherboard::save_state` writes. Bump it when the layout changes, and
+/// teach the `load_state` that changed to read the previous versions.
+pub const STATE_VERSION: u32 = 1;
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    NotAState,
+    NewerVersion(u32),
+    Truncated,
+    Corrupt,
+    WrongCartridge,
+    MissingBootROM,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "not a save state"),
+            StateError::NewerVersion(version) => write!(f, "save state version {} is newer than this emulator's {}", version, STATE_VERSION),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::Corrupt => write!(f, "save state is corrupt"),
+            StateError::WrongCartridge => write!(f, "save state is for a different cartridge"),
+            StateError::MissingBootROM => write!(f, "save state was taken while the boot ROM ran, and none is loaded"),
+        }
+    }
+}
+
+/// Appends values to a save state, little-endian
+pub struct StateWriter {
+    pub data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self { data: Vec::new() }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_f64(&mut self, value: f64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+/// Reads back what a `StateWriter` wrote, in the same order
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let mut bytes = [0; N];
+        self.read_into(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, StateError> {
+        Ok(f32::from_le_bytes(self.take()?))
+    }
+
+    pub fn read_f64(&mut self) -> Result<f64, StateError> {
+        Ok(f64::from_le_bytes(self.take()?))
+    }
+
+    /// Fills `bytes` from the state
+    pub fn read_into(&mut self, bytes: &mut [u8]) -> Result<(), StateError> {
+        let end = self.position + bytes.len();
+        let source = self.data.get(self.position..end).ok_or(StateError::Truncated)?;
+        bytes.copy_from_slice(source);
+        self.position = end;
+        Ok(())
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.position == self.data.len()
+    }
+}